@@ -1,82 +1,335 @@
-use std::error::Error;
+use std::time::Instant;
 
 use tui::Terminal;
 
+use crate::util::error::Error;
 use crate::util::event::{Event, Events};
 use crate::util::inputs;
-use crate::util::json_utils::{read_config, read_passwords};
+use crate::util::json_utils::{read_config, read_passwords, UnlockedVault};
+use crate::util::lock::{self, LockScreen};
+use crate::util::paths;
 use crate::util::stateful_table::{CurrentMode, StatefulPasswordTable};
 use crate::util::ui::{self, Backend};
 use crate::util::utils::build_table_rows;
-use aes_gcm::Aes128Gcm;
 
-pub fn run(terminal: &mut Terminal<Backend>, key: Aes128Gcm) -> Result<(), Box<dyn Error>> {
+pub fn run(terminal: &mut Terminal<Backend>, vault: UnlockedVault) -> Result<(), Error> {
     let events = Events::new();
-    let mut table = StatefulPasswordTable::new(key);
-    table.items = build_table_rows(read_passwords()?);
+    let mut vault = vault;
+
+    loop {
+        let previous = paths::current_vault();
+        let (mode, old_vault) = run_table(terminal, &events, vault)?;
+        vault = match next_key(mode, old_vault, &previous, |switched_to| {
+            run_lock_screen(terminal, &events, switched_to)
+        })? {
+            Some(vault) => vault,
+            None => break,
+        };
+    }
+
+    Ok(())
+}
+
+/// The key to reopen the table with after it ended in `mode`, or `None` to
+/// quit. `unlock` shows the lock screen, for the vault just switched to if
+/// it's given one.
+///
+/// The table, and the key with it, is dropped whenever the TUI locks and only
+/// rebuilt once the key has been typed in again. Switching vaults keeps the old
+/// key until the new vault is open, so backing out of the switch returns to
+/// `previous`, the vault it came from.
+fn next_key<K>(
+    mode: CurrentMode,
+    old_key: K,
+    previous: &str,
+    unlock: impl FnOnce(Option<String>) -> Result<Option<K>, Error>,
+) -> Result<Option<K>, Error> {
+    match mode {
+        CurrentMode::Locked => {
+            drop(old_key);
+            unlock(None)
+        }
+        CurrentMode::VaultSwitched => match unlock(Some(paths::current_vault()))? {
+            Some(key) => Ok(Some(key)),
+            None => {
+                paths::set_vault(previous)?;
+                Ok(Some(old_key))
+            }
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Runs the password table until the user quits, it locks itself or another
+/// vault is picked. Returns the mode it ended in, and the key handed back.
+fn run_table(
+    terminal: &mut Terminal<Backend>,
+    events: &Events,
+    vault: UnlockedVault,
+) -> Result<(CurrentMode, UnlockedVault), Error> {
+    let unreadable = vault.unreadable_error();
+    let mut table = StatefulPasswordTable::new(vault.key);
+    table.items = build_table_rows(read_passwords(&table.key)?);
+    table.mark_breaches();
+    if let Some(e) = unreadable {
+        table.show_error(e);
+    }
+    let mut last_input = Instant::now();
+    let mut cfg = read_config()?;
+    let mut config_broken = false;
 
     loop {
         // Reading the config in the loop allows for live editing of colours/style/etc.
-        let cfg = match read_config() {
-            Ok(c) => c,
-            Err(e) => {
-                terminal.show_cursor()?;
-                panic!("Unable to read config file: {}", e);
+        // A broken edit keeps the last good config, and is only reported once.
+        match read_config() {
+            Ok(c) => {
+                cfg = c;
+                config_broken = false;
             }
-        };
+            Err(e) if !config_broken => {
+                config_broken = true;
+                table.show_error(e);
+            }
+            Err(_) => {}
+        }
 
-        terminal.draw(|mut f| {
+        terminal.draw(|f| {
             match table.current_mode {
-                CurrentMode::Normal => {
-                    ui::draw_table(table.ui_details(), &cfg, &mut f, None);
+                CurrentMode::Normal | CurrentMode::Search => {
+                    ui::draw_table(table.ui_details(), &cfg, f, None);
                 }
                 CurrentMode::WithHelp => {
-                    ui::draw_help_window(&mut f);
+                    ui::draw_help_window(f, &cfg.keymap);
+                }
+                CurrentMode::Audit => {
+                    if let Some(view) = table.audit.as_mut() {
+                        ui::draw_audit(f, view, &cfg);
+                    }
                 }
                 CurrentMode::NewPassword
                 | CurrentMode::NewUserName
+                | CurrentMode::NewLogin
+                | CurrentMode::NewUrl
+                | CurrentMode::NewTags
+                | CurrentMode::NewNotes
+                | CurrentMode::NewTotp
+                | CurrentMode::InvalidTotp
                 | CurrentMode::PasswordCreated
                 | CurrentMode::DeletePassword
                 | CurrentMode::PasswordDeleted
                 | CurrentMode::NoSuchPassword
-                | CurrentMode::PasswordExists => {
-                    ui::draw_table(table.ui_details(), &cfg, &mut f, None);
-                    ui::draw_add_delete_password(&mut f, &table.current_mode, &table.input);
+                | CurrentMode::PasswordExists
+                | CurrentMode::EditUserName
+                | CurrentMode::EditPassword
+                | CurrentMode::PasswordEdited
+                | CurrentMode::RekeyOldKey
+                | CurrentMode::RekeyNewKey
+                | CurrentMode::RekeyConfirmKey
+                | CurrentMode::Rekeyed
+                | CurrentMode::WrongKey
+                | CurrentMode::KeyMismatch
+                | CurrentMode::CopyFailed
+                | CurrentMode::DecryptFailed
+                | CurrentMode::NoTotp
+                | CurrentMode::NoHistory
+                | CurrentMode::PasswordRestored
+                | CurrentMode::SwitchVault
+                | CurrentMode::NoSuchVault => {
+                    ui::draw_table(table.ui_details(), &cfg, f, None);
+                    ui::draw_add_delete_password(
+                        f,
+                        &table.current_mode,
+                        table.input.expose(),
+                        &cfg.keymap,
+                    );
+                }
+                CurrentMode::Error => {
+                    let message = table.error.as_ref().map(Error::to_string);
+                    ui::draw_table(table.ui_details(), &cfg, f, None);
+                    ui::draw_add_delete_password(
+                        f,
+                        &table.current_mode,
+                        message.as_deref().unwrap_or_default(),
+                        &cfg.keymap,
+                    );
+                }
+                CurrentMode::History => {
+                    ui::draw_table(table.ui_details(), &cfg, f, None);
+                    if let Some(details) = table.history_details() {
+                        ui::draw_history(f, details, &cfg);
+                    }
                 }
-                CurrentMode::Exit => {}
+                CurrentMode::ConfirmEdit => {
+                    let summary = table.edit_summary();
+                    ui::draw_table(table.ui_details(), &cfg, f, None);
+                    ui::draw_add_delete_password(f, &table.current_mode, &summary, &cfg.keymap);
+                }
+                CurrentMode::Exit | CurrentMode::Locked | CurrentMode::VaultSwitched => {}
             };
         })?;
 
-        match table.current_mode {
-            CurrentMode::Normal => {
-                if let Event::Input(key) = events.next()? {
-                    inputs::password_table_input_handler(&mut table, key);
-                }
+        if let CurrentMode::Exit | CurrentMode::Locked | CurrentMode::VaultSwitched =
+            table.current_mode
+        {
+            break;
+        }
+        let key = match events.next()? {
+            Event::Input(key) => {
+                last_input = Instant::now();
+                key
             }
-            CurrentMode::WithHelp => {
-                if let Event::Input(key) = events.next()? {
-                    inputs::with_help_input_handler(&mut table, key);
+            Event::Tick => {
+                let now = Instant::now();
+                table.clear_expired_clipboard(now);
+                if lock::is_idle(last_input, now, cfg.lock_timeout) {
+                    table.lock();
                 }
+                continue;
             }
-            #[rustfmt::skip]
+        };
+
+        match table.current_mode {
+            CurrentMode::Normal => {
+                inputs::password_table_input_handler(&mut table, key, &cfg.keymap)
+            }
+            CurrentMode::WithHelp => inputs::with_help_input_handler(&mut table, key, &cfg.keymap),
+            CurrentMode::Search => inputs::search_input_handler(&mut table, key),
             CurrentMode::NewUserName
+            | CurrentMode::NewLogin
             | CurrentMode::NewPassword
+            | CurrentMode::NewUrl
+            | CurrentMode::NewTags
+            | CurrentMode::NewNotes
+            | CurrentMode::NewTotp
+            | CurrentMode::InvalidTotp
             | CurrentMode::PasswordCreated
             | CurrentMode::PasswordExists => {
-                if let Event::Input(key) = events.next()? {
-                    inputs::add_password_input_handler(&mut table, key)?;
-                }
-            },
+                inputs::add_password_input_handler(&mut table, key, &cfg.keymap)?
+            }
             CurrentMode::DeletePassword
             | CurrentMode::PasswordDeleted
             | CurrentMode::NoSuchPassword => {
-                if let Event::Input(key) = events.next()? {
-                    inputs::delete_password_input_handler(&mut table, key);
-                }
+                inputs::delete_password_input_handler(&mut table, key, &cfg.keymap)
+            }
+            CurrentMode::EditUserName
+            | CurrentMode::EditPassword
+            | CurrentMode::ConfirmEdit
+            | CurrentMode::PasswordEdited => {
+                inputs::edit_password_input_handler(&mut table, key, &cfg.keymap)
             }
-            CurrentMode::Exit => break,
+            CurrentMode::RekeyOldKey
+            | CurrentMode::RekeyNewKey
+            | CurrentMode::RekeyConfirmKey
+            | CurrentMode::Rekeyed
+            | CurrentMode::WrongKey
+            | CurrentMode::KeyMismatch => inputs::rekey_input_handler(&mut table, key, &cfg.keymap),
+            CurrentMode::History => inputs::history_input_handler(&mut table, key, &cfg.keymap),
+            CurrentMode::Audit => inputs::audit_input_handler(&mut table, key, &cfg.keymap),
+            CurrentMode::CopyFailed
+            | CurrentMode::DecryptFailed
+            | CurrentMode::NoTotp
+            | CurrentMode::NoHistory
+            | CurrentMode::PasswordRestored => inputs::notice_input_handler(&mut table, key),
+            CurrentMode::Error => inputs::error_input_handler(&mut table, key),
+            CurrentMode::SwitchVault | CurrentMode::NoSuchVault => {
+                inputs::switch_vault_input_handler(&mut table, key, &cfg.keymap)
+            }
+            CurrentMode::Exit | CurrentMode::Locked | CurrentMode::VaultSwitched => {}
         }
     }
 
-    Ok(())
+    // Don't leave a copied password behind once we're gone.
+    table.clear_clipboard();
+
+    let vault = UnlockedVault {
+        key: table.key,
+        unreadable: Vec::new(),
+    };
+    Ok((table.current_mode, vault))
+}
+
+/// Shows the lock screen until the right master key is typed in, and opens the
+/// vault with it. Returns the key, or `None` if the user backed out instead.
+/// `vault` is set when it's asking for the key of a vault just switched to.
+fn run_lock_screen(
+    terminal: &mut Terminal<Backend>,
+    events: &Events,
+    vault: Option<String>,
+) -> Result<Option<UnlockedVault>, Error> {
+    let mut screen = LockScreen::new();
+    screen.vault = vault;
+
+    // As in the table, a broken config edit keeps the last good config.
+    let mut cfg = read_config().unwrap_or_default();
+
+    while screen.unlocked.is_none() && !screen.exit {
+        if let Ok(c) = read_config() {
+            cfg = c;
+        }
+        terminal.draw(|f| ui::draw_lock_screen(f, &screen, &cfg))?;
+
+        if let Event::Input(key) = events.next()? {
+            inputs::lock_screen_input_handler(&mut screen, key, cfg.login_attempts)?;
+        }
+    }
+
+    Ok(screen.unlocked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::paths::TestDataDir;
+    use std::cell::Cell;
+
+    /// Stands in for the cipher, counting how many have been dropped.
+    struct Key<'a>(&'a Cell<u32>);
+
+    impl Drop for Key<'_> {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_locking_drops_the_key_before_the_lock_screen() {
+        let dropped = Cell::new(0);
+        let next = next_key(CurrentMode::Locked, Key(&dropped), "default", |vault| {
+            assert_eq!(vault, None);
+            assert_eq!(dropped.get(), 1);
+            Ok(Some(Key(&dropped)))
+        });
+        assert!(next.unwrap().is_some());
+    }
+
+    #[test]
+    fn test_backing_out_of_a_switch_keeps_the_old_key() {
+        let _dir = TestDataDir::new("next-key");
+        let dropped = Cell::new(0);
+        paths::set_vault("team").unwrap();
+
+        let next = next_key(
+            CurrentMode::VaultSwitched,
+            Key(&dropped),
+            "default",
+            |vault| {
+                assert_eq!(vault.as_deref(), Some("team"));
+                Ok(None)
+            },
+        )
+        .unwrap();
+        assert!(next.is_some());
+        assert_eq!(dropped.get(), 0);
+        assert_eq!(paths::current_vault(), "default");
+    }
+
+    #[test]
+    fn test_quitting_ends_the_loop() {
+        let dropped = Cell::new(0);
+        let next = next_key(CurrentMode::Exit, Key(&dropped), "default", |_| {
+            panic!("no lock screen after quitting")
+        });
+        assert!(next.unwrap().is_none());
+        assert_eq!(dropped.get(), 1);
+    }
 }
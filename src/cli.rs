@@ -0,0 +1,1028 @@
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use aes_gcm::Aes128Gcm;
+use termion::input::TermRead;
+use zeroize::Zeroize;
+
+use crate::util::audit::{self, AuditRow, AuditSort};
+use crate::util::breach::{self, BreachIndex};
+use crate::util::clipboard;
+use crate::util::error::Error;
+use crate::util::export::{self, ExportFormat};
+use crate::util::generator;
+use crate::util::import::{self, ImportFormat};
+use crate::util::json_utils::{
+    delete_password, read_config, read_passwords, rekey, restore_password, write_new_password,
+    EntryDetails,
+};
+use crate::util::paths;
+use crate::util::secret::SecretString;
+use crate::util::stateful_table::EntryState;
+use crate::util::totp::Totp;
+use crate::util::utils::{
+    decrypt_optional, format_date, parse_tags, try_decrypt, unix_now, EncryptionData,
+};
+
+pub const USAGE: &str = "\
+Usage: psc [--dir <path>] [--vault <name>] [COMMAND]
+
+Opens the passwords table when no command is given.
+
+Options:
+  --dir <path>              Keep the files in <path>, instead of $PASSCURSES_DIR,
+                            ~/.passcurses or $XDG_DATA_HOME/passcurses
+  --vault <name>            Open the named vault instead of $PASSCURSES_VAULT or
+                            the default one, creating it if it's new
+
+Commands:
+  get <service> [--copy]    Print a password, or copy it to the clipboard
+  add <service> [--username <login>] [--url <url>] [--tags <a,b>]
+      [--notes] [--totp] [--generate]
+                            Add a password, read from stdin unless generated,
+                            then the notes and the 2FA secret or otpauth://
+                            URI if asked for, so none of them are in argv
+  totp <service> [--copy]   Print the current 2FA code, or copy it
+  history <service> [--show] [--restore <n>]
+                            List a password's previous passwords, newest
+                            first, or make the nth the current one again
+  rm <service>              Delete a password
+  ls [--tag <tag>]          List services, optionally only those with a tag
+  audit [--sort <order>]    Report breached, weak, reused and old passwords,
+                            sorted by risk (the default), service, strength
+                            or age
+  breach-index <hibp.txt> <index>
+                            Build a compact index of a Have I Been Pwned SHA-1
+                            file, ordered by hash, for breach_file in config
+  generate [options]        Print a generated password or passphrase
+  import <format> <path> [--overwrite] [--dry-run]
+                            Import an export from keepass (XML), bitwarden
+                            (JSON), 1password, chrome or firefox (CSV), or a
+                            pass store directory, or a backup
+  export <format> <path> [--yes]
+                            Export an encrypted backup, or plaintext csv or
+                            bitwarden (JSON) after confirming, to a new file
+  rekey                     Change the master key
+  vaults                    List the vaults
+  help                      Show this message";
+
+pub const EXIT_OK: i32 = 0;
+/// Anything that went wrong at runtime: a wrong key, unreadable files, etc.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_NOT_FOUND: i32 = 3;
+pub const EXIT_EXISTS: i32 = 4;
+
+/// What `psc` was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Tui,
+    Help,
+    Get {
+        service: String,
+        copy: bool,
+    },
+    Add {
+        service: String,
+        details: EntryDetails,
+        /// Read the notes, then the 2FA secret, the same way as the password.
+        notes: bool,
+        totp: bool,
+        generate: bool,
+    },
+    Remove {
+        service: String,
+    },
+    Totp {
+        service: String,
+        copy: bool,
+    },
+    History {
+        service: String,
+        show: bool,
+        /// Which previous password to restore, counting from 1 as listed.
+        restore: Option<usize>,
+    },
+    List {
+        tag: Option<String>,
+    },
+    Audit {
+        sort: AuditSort,
+    },
+    BreachIndex {
+        source: PathBuf,
+        dest: PathBuf,
+    },
+    Generate(Vec<String>),
+    Import {
+        format: Option<ImportFormat>,
+        path: PathBuf,
+        overwrite: bool,
+        dry_run: bool,
+    },
+    Export {
+        format: Option<ExportFormat>,
+        path: PathBuf,
+        confirmed: bool,
+    },
+    Rekey,
+    Vaults,
+}
+
+/// Options given before the command, which pick where the files are.
+#[derive(Debug, Default, PartialEq)]
+pub struct GlobalOptions {
+    pub dir: Option<PathBuf>,
+    pub vault: Option<String>,
+}
+
+impl GlobalOptions {
+    /// Takes the options off the front of `args`, returning the rest.
+    pub fn parse(args: &[String]) -> Result<(GlobalOptions, &[String]), String> {
+        let mut options = GlobalOptions::default();
+        let mut rest = args;
+
+        while let Some((option, after)) = rest.split_first() {
+            let value = || {
+                after
+                    .first()
+                    .cloned()
+                    .ok_or_else(|| format!("{} needs a value", option))
+            };
+            match option.as_str() {
+                "--dir" => options.dir = Some(PathBuf::from(value()?)),
+                "--vault" => options.vault = Some(value()?),
+                _ => break,
+            }
+            rest = &after[1..];
+        }
+
+        Ok((options, rest))
+    }
+
+    /// Points the file paths at the chosen directory and vault.
+    pub fn apply(self) -> Result<(), Error> {
+        if let Some(dir) = self.dir {
+            paths::set_data_dir(dir);
+        }
+        if let Some(vault) = self.vault {
+            paths::set_vault(&vault)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Command {
+    pub fn parse(args: &[String]) -> Result<Command, String> {
+        let (command, rest) = match args.split_first() {
+            Some((command, rest)) => (command.as_str(), rest),
+            None => return Ok(Command::Tui),
+        };
+
+        let command = match command {
+            "help" | "--help" | "-h" => Command::Help,
+            // The generator parses its own flags.
+            "generate" => return Ok(Command::Generate(rest.to_vec())),
+            "get" => Command::Get {
+                service: String::new(),
+                copy: false,
+            },
+            "add" => Command::Add {
+                service: String::new(),
+                details: EntryDetails::default(),
+                notes: false,
+                totp: false,
+                generate: false,
+            },
+            "rm" => Command::Remove {
+                service: String::new(),
+            },
+            "totp" => Command::Totp {
+                service: String::new(),
+                copy: false,
+            },
+            "history" => Command::History {
+                service: String::new(),
+                show: false,
+                restore: None,
+            },
+            "ls" => Command::List { tag: None },
+            "audit" => Command::Audit {
+                sort: AuditSort::Risk,
+            },
+            "breach-index" => Command::BreachIndex {
+                source: PathBuf::new(),
+                dest: PathBuf::new(),
+            },
+            "import" => Command::Import {
+                format: None,
+                path: PathBuf::new(),
+                overwrite: false,
+                dry_run: false,
+            },
+            "export" => Command::Export {
+                format: None,
+                path: PathBuf::new(),
+                confirmed: false,
+            },
+            "rekey" => Command::Rekey,
+            "vaults" => Command::Vaults,
+            other => return Err(format!("Unknown command: {}", other)),
+        };
+
+        parse_options(command, rest)
+    }
+}
+
+/// Fills in a command's positional service name and flags.
+fn parse_options(mut command: Command, args: &[String]) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{} needs a value", name))
+        };
+        match (&mut command, arg.as_str()) {
+            (Command::Get { copy, .. } | Command::Totp { copy, .. }, "--copy" | "-c") => {
+                *copy = true
+            }
+            (Command::Add { details, .. }, "--username" | "-u") => details.username = value(arg)?,
+            (Command::Add { details, .. }, "--url") => details.url = value(arg)?,
+            (Command::Add { details, .. }, "--tags" | "-t") => {
+                details.tags = parse_tags(&value(arg)?)
+            }
+            (Command::Add { notes, .. }, "--notes") => *notes = true,
+            (Command::Add { totp, .. }, "--totp") => *totp = true,
+            (Command::Add { generate, .. }, "--generate" | "-g") => *generate = true,
+            (Command::History { show, .. }, "--show" | "-s") => *show = true,
+            (Command::History { restore, .. }, "--restore") => {
+                *restore = Some(
+                    value(arg)?
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| format!("{} needs a number from 1", arg))?,
+                )
+            }
+            (Command::List { tag }, "--tag" | "-t") => *tag = Some(value(arg)?),
+            (Command::Audit { sort }, "--sort" | "-s") => {
+                let name = value(arg)?;
+                *sort = AuditSort::from_name(&name)
+                    .ok_or_else(|| format!("Unknown sort order: {}", name))?
+            }
+            (Command::Import { overwrite, .. }, "--overwrite") => *overwrite = true,
+            (Command::Import { dry_run, .. }, "--dry-run" | "-n") => *dry_run = true,
+            (Command::Export { confirmed, .. }, "--yes" | "-y") => *confirmed = true,
+            (_, option) if option.starts_with('-') => {
+                return Err(format!("Unknown option: {}", option))
+            }
+            (_, other) => positional.push(other.to_string()),
+        }
+    }
+
+    match &mut command {
+        Command::Get { service, .. }
+        | Command::Add { service, .. }
+        | Command::Remove { service }
+        | Command::Totp { service, .. }
+        | Command::History { service, .. } => {
+            if positional.len() != 1 {
+                return Err(String::from("Expected exactly one service name"));
+            }
+            *service = positional.remove(0);
+        }
+        Command::Import { format, path, .. } => {
+            if positional.len() != 2 {
+                return Err(String::from("Expected a format and a path"));
+            }
+            *format = Some(
+                ImportFormat::from_name(&positional[0])
+                    .ok_or_else(|| format!("Unknown import format: {}", positional[0]))?,
+            );
+            *path = PathBuf::from(&positional[1]);
+        }
+        Command::Export { format, path, .. } => {
+            if positional.len() != 2 {
+                return Err(String::from("Expected a format and a path"));
+            }
+            *format = Some(
+                ExportFormat::from_name(&positional[0])
+                    .ok_or_else(|| format!("Unknown export format: {}", positional[0]))?,
+            );
+            *path = PathBuf::from(&positional[1]);
+        }
+        Command::BreachIndex { source, dest } => {
+            if positional.len() != 2 {
+                return Err(String::from(
+                    "Expected the breach file and the index to write",
+                ));
+            }
+            *source = PathBuf::from(&positional[0]);
+            *dest = PathBuf::from(&positional[1]);
+        }
+        _ if !positional.is_empty() => {
+            return Err(format!("Unexpected argument: {}", positional[0]));
+        }
+        _ => {}
+    }
+
+    Ok(command)
+}
+
+/// Runs one of the commands that work on the vault without opening the TUI.
+/// Returns the exit code.
+pub fn run(command: Command, key: &Aes128Gcm) -> Result<i32, Error> {
+    match command {
+        Command::Get { service, copy } => get(&service, copy, key),
+        Command::Add {
+            service,
+            details,
+            notes,
+            totp,
+            generate,
+        } => add(service, details, notes, totp, generate, key),
+        Command::Remove { service } => remove(&service, key),
+        Command::Totp { service, copy } => totp(&service, copy, key),
+        Command::History {
+            service,
+            show,
+            restore,
+        } => history(&service, show, restore, key),
+        Command::List { tag } => list(tag.as_deref(), key),
+        Command::Audit { sort } => audit_command(sort, key),
+        Command::Import {
+            format: Some(format),
+            path,
+            overwrite,
+            dry_run,
+        } => import_command(format, &path, overwrite, dry_run, key),
+        Command::Export {
+            format: Some(format),
+            path,
+            confirmed,
+        } => export_command(format, &path, confirmed, key),
+        Command::Rekey => rekey_command(key),
+        Command::Tui
+        | Command::Help
+        | Command::Generate(_)
+        | Command::Vaults
+        | Command::BreachIndex { .. }
+        | Command::Import { format: None, .. }
+        | Command::Export { format: None, .. } => {
+            Err(Error::Input(String::from("Not a passwords command")))
+        }
+    }
+}
+
+/// Prompts on stderr, keeping stdout clean for output meant for scripts.
+fn prompt_for_key(prompt: &str) -> Result<SecretString, Error> {
+    // Big enough that reading a line won't have to reallocate and leave a copy
+    // of the key behind.
+    let mut line = String::with_capacity(1024);
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let read = io::stdin().read_line(&mut line);
+    let key = SecretString::from(line.trim_end());
+    line.zeroize();
+    read?;
+
+    Ok(key)
+}
+
+/// Like `prompt_for_key`, but doesn't echo the input when run interactively.
+pub fn prompt_for_secret(prompt: &str) -> Result<SecretString, Error> {
+    if !termion::is_tty(&io::stdin()) || !termion::is_tty(&io::stdout()) {
+        return prompt_for_key(prompt);
+    }
+
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+    let secret = io::stdin()
+        .read_passwd(&mut io::stdout())?
+        .ok_or_else(|| Error::Input(String::from("No input given")))?;
+    eprintln!();
+
+    Ok(SecretString::from(secret))
+}
+
+fn get(service: &str, copy: bool, key: &Aes128Gcm) -> Result<i32, Error> {
+    let map = read_passwords(key)?;
+    let entry = match map.get(service) {
+        Some(entry) => entry,
+        None => {
+            eprintln!("No password for {}", service);
+            return Ok(EXIT_NOT_FOUND);
+        }
+    };
+
+    let password = SecretString::from(try_decrypt(EncryptionData {
+        password: &entry.password,
+        nonce: &entry.nonce,
+        key,
+    })?);
+    if copy {
+        clipboard::from_settings(&read_config()?.clipboard).copy(password.expose())?;
+        eprintln!("Copied the password for {}", service);
+    } else {
+        println!("{}", password.expose());
+    }
+
+    Ok(EXIT_OK)
+}
+
+fn totp(service: &str, copy: bool, key: &Aes128Gcm) -> Result<i32, Error> {
+    let map = read_passwords(key)?;
+    let entry = match map.get(service) {
+        Some(entry) if !entry.totp.is_empty() => entry,
+        Some(_) => {
+            eprintln!("No 2FA secret for {}", service);
+            return Ok(EXIT_NOT_FOUND);
+        }
+        None => {
+            eprintln!("No password for {}", service);
+            return Ok(EXIT_NOT_FOUND);
+        }
+    };
+
+    let secret = SecretString::from(decrypt_optional(&entry.totp, &entry.totp_nonce, key)?);
+    let totp = Totp::parse(secret.expose())?;
+    let now = unix_now();
+    let code = SecretString::from(totp.code_at(now));
+    if copy {
+        clipboard::from_settings(&read_config()?.clipboard).copy(code.expose())?;
+        eprintln!(
+            "Copied the code for {}, valid for {}s",
+            service,
+            totp.seconds_left(now)
+        );
+    } else {
+        println!("{}", code.expose());
+    }
+
+    Ok(EXIT_OK)
+}
+
+fn history(
+    service: &str,
+    show: bool,
+    restore: Option<usize>,
+    key: &Aes128Gcm,
+) -> Result<i32, Error> {
+    if let Some(n) = restore {
+        return match restore_password(service, n - 1, key)? {
+            EntryState::NoSuchPassword => {
+                eprintln!("No password for {}", service);
+                Ok(EXIT_NOT_FOUND)
+            }
+            _ => {
+                eprintln!("Restored password {} for {}", n, service);
+                Ok(EXIT_OK)
+            }
+        };
+    }
+
+    let map = read_passwords(key)?;
+    let entry = match map.get(service) {
+        Some(entry) => entry,
+        None => {
+            eprintln!("No password for {}", service);
+            return Ok(EXIT_NOT_FOUND);
+        }
+    };
+    if entry.history.is_empty() {
+        eprintln!("No previous passwords for {}", service);
+    }
+
+    for (i, previous) in entry.history.iter().enumerate() {
+        let password = if show {
+            SecretString::from(try_decrypt(EncryptionData {
+                password: &previous.password,
+                nonce: &previous.nonce,
+                key,
+            })?)
+        } else {
+            SecretString::from("********")
+        };
+        println!(
+            "{}\treplaced {}\t{}",
+            i + 1,
+            format_date(previous.replaced),
+            password.expose()
+        );
+    }
+
+    Ok(EXIT_OK)
+}
+
+/// Adds `service`, reading its password, then its notes and 2FA secret if
+/// asked for, from the terminal or stdin rather than argv.
+fn add(
+    service: String,
+    mut details: EntryDetails,
+    notes: bool,
+    totp: bool,
+    generate: bool,
+    key: &Aes128Gcm,
+) -> Result<i32, Error> {
+    if read_passwords(key)?.contains_key(&service) {
+        eprintln!("A password for {} already exists", service);
+        return Ok(EXIT_EXISTS);
+    }
+
+    let password = if generate {
+        let generated =
+            SecretString::from(generator::generate_password(&read_config()?.generator)?);
+        println!("{}", generated.expose());
+        generated
+    } else {
+        prompt_for_secret(&format!("Password for {}: ", service))?
+    };
+    if password.is_empty() {
+        return Err(Error::Input(String::from(
+            "Refusing to add an empty password",
+        )));
+    }
+    if notes {
+        details.notes = prompt_for_secret("Notes: ")?;
+    }
+    if totp {
+        details.totp = prompt_for_secret("2FA secret or otpauth:// URI: ")?;
+        Totp::parse(details.totp.expose())?;
+    }
+
+    write_new_password(service, password.expose(), details, key)?;
+
+    Ok(EXIT_OK)
+}
+
+fn remove(service: &str, key: &Aes128Gcm) -> Result<i32, Error> {
+    match delete_password(service, key)? {
+        EntryState::NoSuchPassword => {
+            eprintln!("No password for {}", service);
+            Ok(EXIT_NOT_FOUND)
+        }
+        _ => Ok(EXIT_OK),
+    }
+}
+
+fn list(tag: Option<&str>, key: &Aes128Gcm) -> Result<i32, Error> {
+    let mut services: Vec<String> = read_passwords(key)?
+        .into_iter()
+        .filter(|(_, entry)| tag.map_or(true, |tag| entry.tags.iter().any(|t| t == tag)))
+        .map(|(service, _)| service)
+        .collect();
+    services.sort();
+
+    services.iter().for_each(|service| println!("{}", service));
+
+    Ok(EXIT_OK)
+}
+
+fn audit_command(sort: AuditSort, key: &Aes128Gcm) -> Result<i32, Error> {
+    let max_age = read_config()?.max_password_age;
+    let mut rows = audit::audit_vault(
+        &read_passwords(key)?,
+        key,
+        max_age,
+        unix_now(),
+        BreachIndex::configured()?.as_mut(),
+    )?;
+    audit::sort(&mut rows, sort);
+
+    for row in &rows {
+        println!(
+            "{}\t{:.0} bits\t{}\t{}",
+            row.service,
+            row.bits,
+            row.age(),
+            row.issues()
+        );
+    }
+    let count = |issue: fn(&AuditRow) -> bool| rows.iter().filter(|r| issue(r)).count();
+    eprintln!(
+        "{} breached, {} weak, {} reused, {} old",
+        count(AuditRow::is_breached),
+        count(AuditRow::is_weak),
+        count(AuditRow::is_reused),
+        count(|r| r.old)
+    );
+
+    Ok(EXIT_OK)
+}
+
+fn import_command(
+    format: ImportFormat,
+    path: &Path,
+    overwrite: bool,
+    dry_run: bool,
+    key: &Aes128Gcm,
+) -> Result<i32, Error> {
+    let passphrase = match format {
+        ImportFormat::Backup => Some(prompt_for_secret("Backup passphrase: ")?),
+        _ => None,
+    };
+    let entries = import::read_entries(format, path, passphrase.as_ref().map(|p| p.expose()))?;
+    let report = import::import(entries, overwrite, dry_run, key)?;
+
+    let sections = [
+        (&report.added, "Added"),
+        (&report.replaced, "Replaced"),
+        (&report.duplicates, "Already there"),
+        (
+            &report.conflicts,
+            "Conflicts, not imported (use --overwrite to replace)",
+        ),
+        (&report.skipped, "Skipped, no name or password"),
+        (&report.invalid_totp, "Skipped, invalid 2FA secret"),
+    ];
+    for (services, heading) in sections.iter().filter(|(s, _)| !s.is_empty()) {
+        println!("{} ({}):", heading, services.len());
+        services
+            .iter()
+            .for_each(|service| println!("  {}", service));
+    }
+    if dry_run {
+        eprintln!("Dry run, nothing was written.");
+    }
+
+    Ok(EXIT_OK)
+}
+
+fn export_command(
+    format: ExportFormat,
+    path: &Path,
+    confirmed: bool,
+    key: &Aes128Gcm,
+) -> Result<i32, Error> {
+    let passphrase = if format.is_plaintext() {
+        if !confirmed {
+            let answer = prompt_for_key(&format!(
+                "This writes every password, unencrypted, to {}. Type yes to continue: ",
+                path.display()
+            ))?;
+            if answer != "yes" {
+                eprintln!("Nothing exported.");
+                return Ok(EXIT_FAILURE);
+            }
+        }
+        None
+    } else {
+        let passphrase = prompt_for_secret("Backup passphrase: ")?;
+        if passphrase.is_empty() || passphrase != prompt_for_secret("Backup passphrase again: ")? {
+            return Err(Error::Input(String::from(
+                "Passphrases don't match, nothing exported",
+            )));
+        }
+        Some(passphrase)
+    };
+
+    let entries = export::decrypt_entries(read_passwords(key)?, key)?;
+    let mut contents = export::export(format, &entries, passphrase.as_ref().map(|p| p.expose()))?;
+    let written = export::write_export(path, &contents);
+    contents.zeroize();
+    written?;
+    eprintln!("Exported {} passwords to {}", entries.len(), path.display());
+
+    Ok(EXIT_OK)
+}
+
+fn rekey_command(key: &Aes128Gcm) -> Result<i32, Error> {
+    let new_key = prompt_for_secret("Enter a new key: ")?;
+    if new_key.is_empty() || new_key != prompt_for_secret("Enter the new key again: ")? {
+        return Err(Error::Input(String::from(
+            "Keys don't match, master key unchanged",
+        )));
+    }
+
+    rekey(key, new_key.expose())?;
+    eprintln!("Master key changed.");
+
+    Ok(EXIT_OK)
+}
+
+/// Builds a breach index. It works on public data only, so needs no key.
+pub fn breach_index(source: &Path, dest: &Path) -> Result<i32, Error> {
+    let records = breach::build_index(source, dest)?;
+    eprintln!("Indexed {} hashes into {}", records, dest.display());
+
+    Ok(EXIT_OK)
+}
+
+/// Lists the vaults in the data directory, marking the one in use.
+pub fn vaults() -> Result<i32, Error> {
+    let current = paths::current_vault();
+    for vault in paths::list_vaults()? {
+        let marker = if vault == current { "*" } else { " " };
+        println!("{} {}", marker, vault);
+    }
+
+    Ok(EXIT_OK)
+}
+
+pub fn generate(args: &[String]) -> Result<i32, Error> {
+    let settings = read_config().unwrap_or_default().generator;
+    let (settings, passphrase) = match generator::parse_args(args, settings) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("psc: {}\n\n{}", message, USAGE);
+            return Ok(EXIT_USAGE);
+        }
+    };
+    let generated = if passphrase {
+        generator::generate_passphrase(&settings)?
+    } else {
+        generator::generate_password(&settings)?
+    };
+    println!("{}", generated);
+
+    Ok(EXIT_OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::json_utils::{check_files, unlock_vault};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_no_command_opens_tui() {
+        assert_eq!(Command::parse(&[]), Ok(Command::Tui));
+    }
+
+    #[test]
+    fn test_parse_get() {
+        assert_eq!(
+            Command::parse(&args(&["get", "github", "--copy"])),
+            Ok(Command::Get {
+                service: String::from("github"),
+                copy: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_add() {
+        let command = Command::parse(&args(&[
+            "add",
+            "github",
+            "--username",
+            "ernie",
+            "--tags",
+            "work, code",
+            "--totp",
+            "-g",
+        ]));
+        assert_eq!(
+            command,
+            Ok(Command::Add {
+                service: String::from("github"),
+                details: EntryDetails {
+                    username: String::from("ernie"),
+                    tags: vec![String::from("work"), String::from("code")],
+                    ..Default::default()
+                },
+                notes: false,
+                totp: true,
+                generate: true,
+            })
+        );
+        // Secrets are never taken from the command line.
+        assert!(Command::parse(&args(&["add", "github", "--notes", "pin 1234"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_totp() {
+        assert_eq!(
+            Command::parse(&args(&["totp", "github", "-c"])),
+            Ok(Command::Totp {
+                service: String::from("github"),
+                copy: true,
+            })
+        );
+        assert!(Command::parse(&args(&["totp"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_history() {
+        assert_eq!(
+            Command::parse(&args(&["history", "github", "--restore", "2"])),
+            Ok(Command::History {
+                service: String::from("github"),
+                show: false,
+                restore: Some(2),
+            })
+        );
+        assert!(Command::parse(&args(&["history", "github", "--restore", "0"])).is_err());
+        assert!(Command::parse(&args(&["history", "github", "--restore", "x"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_ls() {
+        assert_eq!(
+            Command::parse(&args(&["ls", "--tag", "work"])),
+            Ok(Command::List {
+                tag: Some(String::from("work")),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_audit() {
+        assert_eq!(
+            Command::parse(&args(&["audit"])),
+            Ok(Command::Audit {
+                sort: AuditSort::Risk,
+            })
+        );
+        assert_eq!(
+            Command::parse(&args(&["audit", "--sort", "age"])),
+            Ok(Command::Audit {
+                sort: AuditSort::Age,
+            })
+        );
+        assert!(Command::parse(&args(&["audit", "--sort", "colour"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_breach_index() {
+        assert_eq!(
+            Command::parse(&args(&["breach-index", "hibp.txt", "hibp.idx"])),
+            Ok(Command::BreachIndex {
+                source: PathBuf::from("hibp.txt"),
+                dest: PathBuf::from("hibp.idx"),
+            })
+        );
+        assert!(Command::parse(&args(&["breach-index", "hibp.txt"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_generate_keeps_its_flags() {
+        assert_eq!(
+            Command::parse(&args(&["generate", "--length", "12"])),
+            Ok(Command::Generate(args(&["--length", "12"])))
+        );
+    }
+
+    #[test]
+    fn test_parse_import() {
+        assert_eq!(
+            Command::parse(&args(&["import", "bitwarden", "export.json", "--dry-run"])),
+            Ok(Command::Import {
+                format: Some(ImportFormat::Bitwarden),
+                path: PathBuf::from("export.json"),
+                overwrite: false,
+                dry_run: true,
+            })
+        );
+        assert!(Command::parse(&args(&["import", "lastpass", "x.csv"])).is_err());
+        assert!(Command::parse(&args(&["import", "chrome"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_export() {
+        assert_eq!(
+            Command::parse(&args(&["export", "csv", "out.csv", "--yes"])),
+            Ok(Command::Export {
+                format: Some(ExportFormat::Csv),
+                path: PathBuf::from("out.csv"),
+                confirmed: true,
+            })
+        );
+        assert!(Command::parse(&args(&["export", "backup"])).is_err());
+        assert!(Command::parse(&args(&["export", "backup", "b.json", "--overwrite"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_global_options() {
+        let args = args(&["--dir", "/tmp/psc", "--vault", "team", "get", "github"]);
+        let (options, rest) = GlobalOptions::parse(&args).unwrap();
+        assert_eq!(
+            options,
+            GlobalOptions {
+                dir: Some(PathBuf::from("/tmp/psc")),
+                vault: Some(String::from("team")),
+            }
+        );
+        assert_eq!(
+            Command::parse(rest),
+            Ok(Command::Get {
+                service: String::from("github"),
+                copy: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_global_options_needs_values() {
+        assert!(GlobalOptions::parse(&args(&["--vault"])).is_err());
+        let args = args(&["ls", "--vault", "team"]);
+        let (options, rest) = GlobalOptions::parse(&args).unwrap();
+        assert_eq!(options, GlobalOptions::default());
+        assert_eq!(rest.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Command::parse(&args(&["bogus"])).is_err());
+        assert!(Command::parse(&args(&["get"])).is_err());
+        assert!(Command::parse(&args(&["rm", "a", "b"])).is_err());
+        assert!(Command::parse(&args(&["get", "github", "--url", "x"])).is_err());
+        assert!(Command::parse(&args(&["add", "github", "--username"])).is_err());
+        assert!(Command::parse(&args(&["ls", "github"])).is_err());
+    }
+
+    /// A vault holding a password for `mail`, opened the way `main` opens one.
+    fn test_vault(name: &str) -> (paths::TestDataDir, Aes128Gcm) {
+        let dir = paths::TestDataDir::new(name);
+        check_files("testing987654321").unwrap();
+        let key = unlock_vault("testing987654321").unwrap().unwrap().key;
+        write_new_password(
+            String::from("mail"),
+            "hunter2",
+            EntryDetails::default(),
+            &key,
+        )
+        .unwrap();
+        (dir, key)
+    }
+
+    fn password_of(service: &str, key: &Aes128Gcm) -> Option<String> {
+        read_passwords(key).unwrap().get(service).map(|entry| {
+            try_decrypt(EncryptionData {
+                password: &entry.password,
+                nonce: &entry.nonce,
+                key,
+            })
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_run_get() {
+        let (_dir, key) = test_vault("cli-get");
+        let get = |service: &str| Command::Get {
+            service: String::from(service),
+            copy: false,
+        };
+        assert_eq!(run(get("mail"), &key).unwrap(), EXIT_OK);
+        assert_eq!(run(get("bank"), &key).unwrap(), EXIT_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_run_add() {
+        let (_dir, key) = test_vault("cli-add");
+        let add = |service: &str| Command::Add {
+            service: String::from(service),
+            details: EntryDetails {
+                tags: vec![String::from("work")],
+                ..Default::default()
+            },
+            notes: false,
+            totp: false,
+            generate: true,
+        };
+
+        assert_eq!(run(add("mail"), &key).unwrap(), EXIT_EXISTS);
+        assert_eq!(password_of("mail", &key).as_deref(), Some("hunter2"));
+        assert!(read_passwords(&key).unwrap()["mail"].tags.is_empty());
+
+        assert_eq!(run(add("bank"), &key).unwrap(), EXIT_OK);
+        assert!(!password_of("bank", &key).unwrap().is_empty());
+        assert_eq!(read_passwords(&key).unwrap()["bank"].tags, vec!["work"]);
+    }
+
+    #[test]
+    fn test_run_rm() {
+        let (_dir, key) = test_vault("cli-rm");
+        let rm = |service: &str| Command::Remove {
+            service: String::from(service),
+        };
+        assert_eq!(run(rm("bank"), &key).unwrap(), EXIT_NOT_FOUND);
+        assert_eq!(password_of("mail", &key).as_deref(), Some("hunter2"));
+
+        assert_eq!(run(rm("mail"), &key).unwrap(), EXIT_OK);
+        assert!(read_passwords(&key).unwrap().is_empty());
+        assert_eq!(run(rm("mail"), &key).unwrap(), EXIT_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_run_ls() {
+        let (_dir, key) = test_vault("cli-ls");
+        let ls = |tag: Option<&str>| Command::List {
+            tag: tag.map(String::from),
+        };
+        assert_eq!(run(ls(None), &key).unwrap(), EXIT_OK);
+        assert_eq!(run(ls(Some("work")), &key).unwrap(), EXIT_OK);
+        // Listing doesn't change the vault.
+        assert_eq!(read_passwords(&key).unwrap().len(), 1);
+        assert_eq!(password_of("mail", &key).as_deref(), Some("hunter2"));
+    }
+}
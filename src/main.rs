@@ -1,6 +1,4 @@
-use std::error::Error;
 use std::io;
-use std::io::prelude::*;
 
 use termion::input::MouseTerminal;
 use termion::raw::IntoRawMode;
@@ -8,37 +6,97 @@ use termion::screen::AlternateScreen;
 use tui::backend::TermionBackend;
 use tui::Terminal;
 
-use crate::util::utils::keygen;
+use crate::cli::{prompt_for_secret, Command, GlobalOptions};
+use crate::util::error::Error;
+use crate::util::json_utils::{read_config, unlock_vault, UnlockedVault};
+use crate::util::secret::SecretString;
 
 mod app;
+mod cli;
 mod util;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let mut key = String::new();
-
-    key = if util::utils::verify_dev() {
-        std::env::var_os("PASSCURSES_DEV_PASSWORD_KEY")
-            .ok_or("Dev key not set")
-            .unwrap()
-            .into_string()
-            .unwrap()
-    } else {
-        let stdin = io::stdin();
-        print!("Enter your key: ");
-        io::stdout().flush()?;
-        stdin.read_line(&mut key)?;
-        key.trim_end().parse::<String>()?
+fn main() {
+    util::secret::harden_process();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let parsed = GlobalOptions::parse(&args)
+        .and_then(|(options, rest)| Ok((options, Command::parse(rest)?)));
+    let code = match parsed {
+        Ok((options, command)) => {
+            options
+                .apply()
+                .and_then(|()| run(command))
+                .unwrap_or_else(|error| {
+                    eprintln!("psc: {}", error);
+                    cli::EXIT_FAILURE
+                })
+        }
+        Err(message) => {
+            eprintln!("psc: {}\n\n{}", message, cli::USAGE);
+            cli::EXIT_USAGE
+        }
     };
 
-    let aead = if let Ok(k) = keygen(key.as_bytes().to_vec()) {
-        k.aead
+    std::process::exit(code);
+}
+
+fn run(command: Command) -> Result<i32, Error> {
+    match command {
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(cli::EXIT_OK)
+        }
+        // Generating a password doesn't touch the vault, so it needs no key.
+        Command::Generate(args) => cli::generate(&args),
+        Command::Vaults => cli::vaults(),
+        Command::BreachIndex { source, dest } => cli::breach_index(&source, &dest),
+        Command::Tui => {
+            run_tui(unlocked_vault()?)?;
+            Ok(cli::EXIT_OK)
+        }
+        command => {
+            let vault = unlocked_vault()?;
+            if let Some(unreadable) = vault.unreadable_error() {
+                eprintln!("psc: {}", unreadable);
+            }
+            cli::run(command, &vault.key)
+        }
+    }
+}
+
+/// Asks for the master key, checks it and brings the vault up to date.
+fn unlocked_vault() -> Result<UnlockedVault, Error> {
+    let dev = util::utils::verify_dev()?;
+    let mut key = if dev {
+        SecretString::from(
+            std::env::var_os("PASSCURSES_DEV_PASSWORD_KEY")
+                .ok_or_else(|| Error::Input(String::from("Dev key not set")))?
+                .into_string()
+                .map_err(|_| Error::Input(String::from("Dev key is not valid unicode")))?,
+        )
     } else {
-        panic!("Error generating key!");
+        prompt_for_secret("Enter your key: ")?
     };
 
     util::json_utils::check_directory_exists()?;
-    util::json_utils::check_files(&key)?;
+    util::json_utils::check_files(key.expose())?;
 
+    let mut attempts = 1;
+    loop {
+        if let Some(vault) = unlock_vault(key.expose())? {
+            return Ok(vault);
+        }
+        if dev || attempts >= read_config()?.login_attempts {
+            return Err(Error::WrongKey);
+        }
+        eprintln!("Incorrect key, please try again.");
+        key = prompt_for_secret("Enter your key: ")?;
+        attempts += 1;
+    }
+}
+
+/// Runs the TUI, handing back whatever stopped it only once the terminal is
+/// out of raw mode and back on the main screen, so it can be printed.
+fn run_tui(vault: UnlockedVault) -> Result<(), Error> {
     let stdout = io::stdout().into_raw_mode()?;
     let stdout = MouseTerminal::from(stdout);
     let stdout = AlternateScreen::from(stdout);
@@ -46,11 +104,11 @@ fn main() -> Result<(), Box<dyn Error>> {
     let mut terminal = Terminal::new(backend)?;
     terminal.hide_cursor()?;
 
-    if let Err(error) = app::run(&mut terminal, aead) {
-        std::fs::write("debugging.txt", format!("Error rendering table: {}", error)).unwrap();
-    }
+    let result = app::run(&mut terminal, vault);
 
     terminal.show_cursor()?;
+    // Dropping the terminal restores the screen and the terminal mode.
+    drop(terminal);
 
-    Ok(())
+    result
 }
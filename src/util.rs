@@ -1,8 +1,22 @@
+pub mod audit;
 pub mod banner;
+pub mod breach;
+pub mod clipboard;
 pub mod configs;
+pub mod error;
 pub mod event;
+pub mod export;
+pub mod generator;
+pub mod import;
 pub mod inputs;
 pub mod json_utils;
+pub mod keymap;
+pub mod lock;
+pub mod paths;
+pub mod search;
+pub mod secret;
 pub mod stateful_table;
+pub mod totp;
 pub mod ui;
 pub mod utils;
+pub mod vault;
@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use aes_gcm::Aes128Gcm;
+use sha2::{Digest, Sha256};
+
+use crate::util::breach::BreachIndex;
+use crate::util::error::Error;
+use crate::util::json_utils::PasswordEntry;
+use crate::util::secret::SecretString;
+use crate::util::utils::{try_decrypt, EncryptionData};
+
+/// Passwords estimated below this many bits of entropy are reported as weak.
+pub const WEAK_BITS: f64 = 60.0;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// What the audit found for one entry. Holds no secrets, so it can outlive the
+/// decrypted passwords it was worked out from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRow {
+    pub(crate) service: String,
+    /// Estimated entropy of the password, in bits.
+    pub(crate) bits: f64,
+    /// The other services using the same password.
+    pub(crate) reused_with: Vec<String>,
+    /// Days since the password was last changed, if that's known.
+    pub(crate) age_days: Option<u64>,
+    pub(crate) old: bool,
+    /// How many times the password turned up in known breaches, if it did.
+    pub(crate) breached: Option<u64>,
+}
+
+impl AuditRow {
+    pub fn is_weak(&self) -> bool {
+        self.bits < WEAK_BITS
+    }
+
+    pub fn is_reused(&self) -> bool {
+        !self.reused_with.is_empty()
+    }
+
+    pub fn is_breached(&self) -> bool {
+        self.breached.is_some()
+    }
+
+    /// How many problems the entry has.
+    pub fn risk(&self) -> usize {
+        [
+            self.is_weak(),
+            self.is_reused(),
+            self.old,
+            self.is_breached(),
+        ]
+        .iter()
+        .filter(|issue| **issue)
+        .count()
+    }
+
+    /// The problems, for display, e.g. "weak, reused".
+    pub fn issues(&self) -> String {
+        let mut issues = Vec::new();
+        if let Some(times) = self.breached {
+            issues.push(format!("breached ({} times)", times));
+        }
+        if self.is_weak() {
+            issues.push(String::from("weak"));
+        }
+        if self.is_reused() {
+            issues.push(format!("reused ({})", self.reused_with.join(", ")));
+        }
+        if self.old {
+            issues.push(String::from("old"));
+        }
+
+        issues.join(", ")
+    }
+
+    pub fn age(&self) -> String {
+        self.age_days
+            .map_or_else(|| String::from("unknown"), |days| format!("{}d", days))
+    }
+}
+
+/// Orders in which the audit report can be listed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AuditSort {
+    /// Entries with the most problems, then the weakest, first.
+    Risk,
+    Service,
+    /// Weakest first.
+    Strength,
+    /// Oldest first.
+    Age,
+}
+
+impl AuditSort {
+    pub fn from_name(name: &str) -> Option<AuditSort> {
+        match name {
+            "risk" => Some(AuditSort::Risk),
+            "service" => Some(AuditSort::Service),
+            "strength" => Some(AuditSort::Strength),
+            "age" => Some(AuditSort::Age),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditSort::Risk => "risk",
+            AuditSort::Service => "service",
+            AuditSort::Strength => "strength",
+            AuditSort::Age => "age",
+        }
+    }
+
+    /// The order after this one, for cycling through them in the TUI.
+    pub fn next(self) -> AuditSort {
+        match self {
+            AuditSort::Risk => AuditSort::Service,
+            AuditSort::Service => AuditSort::Strength,
+            AuditSort::Strength => AuditSort::Age,
+            AuditSort::Age => AuditSort::Risk,
+        }
+    }
+}
+
+/// A rough estimate of a password's entropy from its length and the kinds of
+/// characters in it. It can't tell a dictionary word from random letters, so
+/// it errs on the generous side.
+pub fn entropy_bits(password: &str) -> f64 {
+    let (mut lower, mut upper, mut digits, mut symbols, mut other) =
+        (false, false, false, false, false);
+    for c in password.chars() {
+        match c {
+            'a'..='z' => lower = true,
+            'A'..='Z' => upper = true,
+            '0'..='9' => digits = true,
+            c if c.is_ascii() => symbols = true,
+            _ => other = true,
+        }
+    }
+    let pool: u32 = [
+        (lower, 26),
+        (upper, 26),
+        (digits, 10),
+        (symbols, 33),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(present, _)| *present)
+    .map(|(_, size)| size)
+    .sum();
+
+    if pool == 0 {
+        return 0.0;
+    }
+
+    password.chars().count() as f64 * f64::from(pool).log2()
+}
+
+/// Audits passwords that are already decrypted. `entries` are the service,
+/// its password and when it was last modified (0 when unknown). Passwords are
+/// only checked for breaches if there's an index to check them against.
+pub fn audit(
+    entries: &[(String, SecretString, u64)],
+    max_age: Duration,
+    now: u64,
+    mut breaches: Option<&mut BreachIndex>,
+) -> Result<Vec<AuditRow>, Error> {
+    // Passwords are grouped by their hash, so the plaintext isn't copied into
+    // a map that won't be zeroized.
+    let mut by_hash: HashMap<Vec<u8>, Vec<&str>> = HashMap::new();
+    for (service, password, _) in entries {
+        by_hash
+            .entry(Sha256::digest(password.expose().as_bytes()).to_vec())
+            .or_default()
+            .push(service);
+    }
+
+    entries
+        .iter()
+        .map(|(service, password, modified)| {
+            let hash = Sha256::digest(password.expose().as_bytes()).to_vec();
+            let mut reused_with: Vec<String> = by_hash[&hash]
+                .iter()
+                .filter(|other| *other != service)
+                .map(|other| other.to_string())
+                .collect();
+            reused_with.sort();
+            let age_days = if *modified == 0 {
+                None
+            } else {
+                Some(now.saturating_sub(*modified) / SECONDS_PER_DAY)
+            };
+
+            let breached = match breaches.as_deref_mut() {
+                Some(index) => index.times_seen(password.expose())?,
+                None => None,
+            };
+
+            Ok(AuditRow {
+                service: service.clone(),
+                bits: entropy_bits(password.expose()),
+                reused_with,
+                age_days,
+                old: max_age.as_secs() > 0
+                    && *modified != 0
+                    && now.saturating_sub(*modified) > max_age.as_secs(),
+                breached,
+            })
+        })
+        .collect()
+}
+
+/// Decrypts every entry in memory and audits them.
+pub fn audit_vault(
+    entries: &HashMap<String, PasswordEntry>,
+    key: &Aes128Gcm,
+    max_age: Duration,
+    now: u64,
+    breaches: Option<&mut BreachIndex>,
+) -> Result<Vec<AuditRow>, Error> {
+    let decrypted = entries
+        .iter()
+        .map(|(service, entry)| {
+            let password = try_decrypt(EncryptionData {
+                password: &entry.password,
+                nonce: &entry.nonce,
+                key,
+            })?;
+            Ok((
+                service.clone(),
+                SecretString::from(password),
+                entry.modified,
+            ))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    audit(&decrypted, max_age, now, breaches)
+}
+
+pub fn sort(rows: &mut [AuditRow], by: AuditSort) {
+    match by {
+        AuditSort::Risk => rows.sort_by(|a, b| {
+            b.risk()
+                .cmp(&a.risk())
+                .then(a.bits.total_cmp(&b.bits))
+                .then_with(|| a.service.cmp(&b.service))
+        }),
+        AuditSort::Service => rows.sort_by(|a, b| a.service.cmp(&b.service)),
+        AuditSort::Strength => rows.sort_by(|a, b| {
+            a.bits
+                .total_cmp(&b.bits)
+                .then_with(|| a.service.cmp(&b.service))
+        }),
+        // Unknown ages go last.
+        AuditSort::Age => rows.sort_by(|a, b| {
+            b.age_days
+                .cmp(&a.age_days)
+                .then_with(|| a.service.cmp(&b.service))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::Sha1;
+
+    const DAY: u64 = SECONDS_PER_DAY;
+    const NOW: u64 = 1_700_000_000;
+
+    fn entry(service: &str, password: &str, modified: u64) -> (String, SecretString, u64) {
+        (
+            String::from(service),
+            SecretString::from(password),
+            modified,
+        )
+    }
+
+    fn sample_entries() -> Vec<(String, SecretString, u64)> {
+        vec![
+            entry("bank", "hunter2", NOW - 400 * DAY),
+            entry("email", "hunter2", NOW - DAY),
+            entry("github", "v9#Lq2!xZr8@Kp4$Wm7&", NOW - 10 * DAY),
+            entry("forum", "Tr0ub4dor&3xYzzy!!Long", 0),
+        ]
+    }
+
+    fn sample() -> Vec<AuditRow> {
+        audit(&sample_entries(), Duration::from_secs(365 * DAY), NOW, None).unwrap()
+    }
+
+    #[test]
+    fn test_entropy_estimate() {
+        assert_eq!(entropy_bits(""), 0.0);
+        assert!((entropy_bits("aaaa") - 4.0 * 26_f64.log2()).abs() < 1e-9);
+        assert!((entropy_bits("aA1!") - 4.0 * 95_f64.log2()).abs() < 1e-9);
+        assert!(entropy_bits("password") < WEAK_BITS);
+        assert!(entropy_bits("v9#Lq2!xZr8@Kp4$Wm7&") > WEAK_BITS);
+    }
+
+    #[test]
+    fn test_audit_finds_reused_weak_and_old() {
+        let rows = sample();
+        let bank = &rows[0];
+        assert_eq!(bank.reused_with, vec![String::from("email")]);
+        assert!(bank.is_weak());
+        assert!(bank.old);
+        assert_eq!(bank.age_days, Some(400));
+        assert_eq!(bank.risk(), 3);
+        assert_eq!(bank.issues(), "weak, reused (email), old");
+
+        let github = &rows[2];
+        assert_eq!(github.risk(), 0);
+        assert_eq!(github.issues(), "");
+
+        // An unknown modification date is never reported as old.
+        assert!(!rows[3].old);
+        assert_eq!(rows[3].age(), "unknown");
+    }
+
+    #[test]
+    fn test_zero_max_age_never_flags_old() {
+        let rows = audit(
+            &[entry("bank", "hunter2", 1)],
+            Duration::from_secs(0),
+            NOW,
+            None,
+        )
+        .unwrap();
+        assert!(!rows[0].old);
+    }
+
+    #[test]
+    fn test_breached_passwords_are_flagged() {
+        let hunter2: String = Sha1::digest(b"hunter2")
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("passcurses-audit-hibp-{}", std::process::id()));
+        std::fs::write(&path, format!("{}:17043\n", hunter2)).unwrap();
+        let mut index = BreachIndex::open(&path).unwrap();
+
+        let rows = audit(
+            &sample_entries(),
+            Duration::from_secs(365 * DAY),
+            NOW,
+            Some(&mut index),
+        )
+        .unwrap();
+        assert_eq!(rows[0].breached, Some(17_043));
+        assert_eq!(
+            rows[0].issues(),
+            "breached (17043 times), weak, reused (email), old"
+        );
+        assert_eq!(rows[0].risk(), 4);
+        assert!(rows[1].is_breached());
+        assert!(!rows[2].is_breached());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sort_orders() {
+        let mut rows = sample();
+        let services = |rows: &[AuditRow]| -> Vec<String> {
+            rows.iter().map(|row| row.service.clone()).collect()
+        };
+
+        sort(&mut rows, AuditSort::Risk);
+        assert_eq!(services(&rows), ["bank", "email", "github", "forum"]);
+        sort(&mut rows, AuditSort::Service);
+        assert_eq!(services(&rows), ["bank", "email", "forum", "github"]);
+        sort(&mut rows, AuditSort::Age);
+        assert_eq!(services(&rows), ["bank", "github", "email", "forum"]);
+        sort(&mut rows, AuditSort::Strength);
+        assert_eq!(rows[0].service, "bank");
+    }
+
+    #[test]
+    fn test_sort_names_cycle() {
+        let mut sort = AuditSort::Risk;
+        for _ in 0..4 {
+            assert_eq!(AuditSort::from_name(sort.name()), Some(sort));
+            sort = sort.next();
+        }
+        assert_eq!(sort, AuditSort::Risk);
+    }
+}
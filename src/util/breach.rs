@@ -0,0 +1,327 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use crate::util::error::Error;
+use crate::util::json_utils::read_config;
+
+/// Starts a binary index, so it can be told apart from the text it's built from.
+const INDEX_MAGIC: &[u8; 8] = b"PSCHIBP1";
+/// The magic, then the number of records as a little endian u64.
+const HEADER_LEN: u64 = 16;
+/// A SHA-1 hash, then how many times it was seen as a little endian u32.
+const RECORD_LEN: u64 = 24;
+
+/// A local copy of the Have I Been Pwned passwords, searched without ever going
+/// to the network. Either the SHA-1 download ordered by hash, a `HASH:COUNT`
+/// line each, or the binary index `build_index` makes from it. Both are binary
+/// searched on disk, so they're never read into memory.
+pub struct BreachIndex {
+    file: BufReader<File>,
+    kind: IndexKind,
+}
+
+enum IndexKind {
+    Text { len: u64 },
+    Binary { records: u64 },
+}
+
+impl BreachIndex {
+    pub fn open(path: &Path) -> Result<BreachIndex, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let len = file.get_ref().metadata()?.len();
+
+        let mut magic = [0; 8];
+        let kind =
+            if len >= HEADER_LEN && file.read_exact(&mut magic).is_ok() && &magic == INDEX_MAGIC {
+                let mut records = [0; 8];
+                file.read_exact(&mut records)?;
+                let records = u64::from_le_bytes(records);
+                // A corrupt count could overflow rather than just not match.
+                let expected = records
+                    .checked_mul(RECORD_LEN)
+                    .and_then(|body| body.checked_add(HEADER_LEN));
+                if expected != Some(len) {
+                    return Err(Error::Malformed(format!(
+                        "{} is a truncated breach index",
+                        path.display()
+                    )));
+                }
+                IndexKind::Binary { records }
+            } else {
+                IndexKind::Text { len }
+            };
+
+        Ok(BreachIndex { file, kind })
+    }
+
+    /// The index set in `config.json`, if there is one. An unreadable config is
+    /// reported wherever it's read for everything else, so it's not repeated here.
+    pub fn configured() -> Result<Option<BreachIndex>, Error> {
+        read_config()
+            .unwrap_or_default()
+            .breach_file
+            .map(|path| BreachIndex::open(&path))
+            .transpose()
+    }
+
+    /// How many times `password` turned up in breaches, if it ever did.
+    pub fn times_seen(&mut self, password: &str) -> Result<Option<u64>, Error> {
+        let hash: [u8; 20] = Sha1::digest(password.as_bytes()).into();
+        match self.kind {
+            IndexKind::Text { len } => self.find_line(&hex(&hash), len),
+            IndexKind::Binary { records } => self.find_record(&hash, records),
+        }
+    }
+
+    /// Binary searches the text file by byte offset. `lo` is always the start of
+    /// a line, and the line being looked for, if it's there, starts before `hi`.
+    fn find_line(&mut self, target: &[u8], len: u64) -> Result<Option<u64>, Error> {
+        let (mut lo, mut hi) = (0, len);
+        let mut line = Vec::new();
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let start = self.next_line_start(mid)?;
+            if start >= hi {
+                hi = mid;
+                continue;
+            }
+
+            line.clear();
+            let read = self.file.read_until(b'\n', &mut line)? as u64;
+            let (hash, count) = split_line(&line);
+            match hash.to_ascii_uppercase().as_slice().cmp(target) {
+                Ordering::Less => lo = start + read,
+                Ordering::Greater => hi = start,
+                Ordering::Equal => return parse_count(count).map(Some),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Moves to, and returns, the start of the first line at or after `pos`.
+    fn next_line_start(&mut self, pos: u64) -> io::Result<u64> {
+        if pos == 0 {
+            return self.file.seek(SeekFrom::Start(0));
+        }
+        self.file.seek(SeekFrom::Start(pos - 1))?;
+        let skipped = self.file.read_until(b'\n', &mut Vec::new())?;
+
+        Ok(pos - 1 + skipped as u64)
+    }
+
+    fn find_record(&mut self, target: &[u8; 20], records: u64) -> Result<Option<u64>, Error> {
+        let (mut lo, mut hi) = (0, records);
+        let mut record = [0; RECORD_LEN as usize];
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.file
+                .seek(SeekFrom::Start(HEADER_LEN + mid * RECORD_LEN))?;
+            self.file.read_exact(&mut record)?;
+            match record[..20].cmp(&target[..]) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => {
+                    let count =
+                        u32::from_le_bytes([record[20], record[21], record[22], record[23]]);
+                    return Ok(Some(u64::from(count)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Packs the text download into a binary index, about half its size, with
+/// fixed size records. Returns how many hashes went in.
+pub fn build_index(source: &Path, dest: &Path) -> Result<u64, Error> {
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut writer = BufWriter::new(File::create(dest)?);
+    writer.write_all(INDEX_MAGIC)?;
+    writer.write_all(&0_u64.to_le_bytes())?;
+
+    let mut records = 0_u64;
+    let mut last: Option<[u8; 20]> = None;
+    let mut line = Vec::new();
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        let (hash, count) = split_line(&line);
+        if hash.is_empty() {
+            continue;
+        }
+        let malformed = |reason: &str| {
+            Error::Malformed(format!(
+                "{} line {} {}",
+                source.display(),
+                line_number,
+                reason
+            ))
+        };
+        let hash = decode_hash(hash).ok_or_else(|| malformed("isn't a SHA-1 hash"))?;
+        let count = parse_count(count).map_err(|_| malformed("has an invalid count"))?;
+        // Lookups binary search the index, so it has to stay sorted.
+        match last.map(|last| last.cmp(&hash)) {
+            Some(Ordering::Greater) => return Err(malformed("is out of order, sort by hash")),
+            Some(Ordering::Equal) => continue,
+            _ => {}
+        }
+
+        writer.write_all(&hash)?;
+        writer.write_all(&(count.min(u64::from(u32::MAX)) as u32).to_le_bytes())?;
+        last = Some(hash);
+        records += 1;
+    }
+
+    writer.seek(SeekFrom::Start(INDEX_MAGIC.len() as u64))?;
+    writer.write_all(&records.to_le_bytes())?;
+    writer.flush()?;
+
+    Ok(records)
+}
+
+/// Splits `HASH:COUNT`, trimming the line ending. Some lists have no counts.
+fn split_line(line: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |last| last + 1);
+    let line = &line[..end];
+    match line.iter().position(|b| *b == b':') {
+        Some(colon) => (&line[..colon], Some(&line[colon + 1..])),
+        None => (line, None),
+    }
+}
+
+fn parse_count(count: Option<&[u8]>) -> Result<u64, Error> {
+    match count {
+        Some(count) => std::str::from_utf8(count)
+            .ok()
+            .and_then(|count| count.trim().parse().ok())
+            .ok_or_else(|| Error::Malformed(String::from("Invalid count in the breach file"))),
+        None => Ok(1),
+    }
+}
+
+fn hex(bytes: &[u8]) -> Vec<u8> {
+    bytes
+        .iter()
+        .flat_map(|b| format!("{:02X}", b).into_bytes())
+        .collect()
+}
+
+fn decode_hash(hex: &[u8]) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut hash = [0; 20];
+    for (byte, pair) in hash.iter_mut().zip(hex.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn sha1_hex(password: &str) -> String {
+        String::from_utf8(hex(&Sha1::digest(password.as_bytes()))).unwrap()
+    }
+
+    /// A sorted HIBP style file with `breached` in it, plus filler hashes
+    /// either side of it.
+    fn write_breaches(name: &str, breached: &[(&str, u64)]) -> PathBuf {
+        let mut lines: Vec<String> = breached
+            .iter()
+            .map(|(password, count)| format!("{}:{}", sha1_hex(password), count))
+            .collect();
+        lines.extend((0..200).map(|i| format!("{}:{}", sha1_hex(&format!("filler{}", i)), i + 1)));
+        lines.sort();
+
+        let path = std::env::temp_dir().join(format!("passcurses-{}-{}", name, std::process::id()));
+        fs::write(&path, lines.join("\r\n") + "\r\n").unwrap();
+        path
+    }
+
+    #[test]
+    fn test_search_sorted_text() {
+        let path = write_breaches("hibp-text", &[("password", 9_545_824), ("hunter2", 17_043)]);
+        let mut index = BreachIndex::open(&path).unwrap();
+        assert_eq!(index.times_seen("password").unwrap(), Some(9_545_824));
+        assert_eq!(index.times_seen("hunter2").unwrap(), Some(17_043));
+        assert_eq!(index.times_seen("filler0").unwrap(), Some(1));
+        assert_eq!(index.times_seen("filler199").unwrap(), Some(200));
+        assert_eq!(index.times_seen("v9#Lq2!xZr8@Kp4$Wm7&").unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_build_and_search_binary_index() {
+        let source = write_breaches("hibp-source", &[("password", 9_545_824)]);
+        let dest = source.with_extension("idx");
+        assert_eq!(build_index(&source, &dest).unwrap(), 201);
+        assert_eq!(
+            fs::metadata(&dest).unwrap().len(),
+            HEADER_LEN + 201 * RECORD_LEN
+        );
+
+        let mut index = BreachIndex::open(&dest).unwrap();
+        assert_eq!(index.times_seen("password").unwrap(), Some(9_545_824));
+        assert_eq!(index.times_seen("filler42").unwrap(), Some(43));
+        assert_eq!(index.times_seen("not breached").unwrap(), None);
+        fs::remove_file(source).unwrap();
+        fs::remove_file(dest).unwrap();
+    }
+
+    #[test]
+    fn test_build_index_refuses_unsorted_input() {
+        let source =
+            std::env::temp_dir().join(format!("passcurses-hibp-unsorted-{}", std::process::id()));
+        fs::write(
+            &source,
+            format!("{}:1\n{}:1\n", "F".repeat(40), "0".repeat(40)),
+        )
+        .unwrap();
+        let dest = source.with_extension("idx");
+        assert!(matches!(
+            build_index(&source, &dest),
+            Err(Error::Malformed(_))
+        ));
+        fs::remove_file(source).unwrap();
+        fs::remove_file(dest).unwrap();
+    }
+
+    #[test]
+    fn test_empty_file_finds_nothing() {
+        let path =
+            std::env::temp_dir().join(format!("passcurses-hibp-empty-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let mut index = BreachIndex::open(&path).unwrap();
+        assert_eq!(index.times_seen("password").unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_overflowing_record_count_is_malformed() {
+        let path =
+            std::env::temp_dir().join(format!("passcurses-hibp-overflow-{}", std::process::id()));
+        let mut contents = INDEX_MAGIC.to_vec();
+        contents.extend_from_slice(&u64::MAX.to_le_bytes());
+        fs::write(&path, contents).unwrap();
+        assert!(matches!(BreachIndex::open(&path), Err(Error::Malformed(_))));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,497 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::util::error::Error;
+use crate::util::secret::SecretString;
+use base64::encode;
+use serde::{Deserialize, Serialize};
+
+pub trait Clipboard {
+    fn copy(&mut self, text: &str) -> Result<(), Error>;
+    fn paste(&mut self) -> Result<String, Error>;
+
+    fn clear(&mut self) -> Result<(), Error> {
+        self.copy("")
+    }
+}
+
+/// Clipboard settings, stored under `clipboard` in `config.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ClipboardSettings {
+    /// One of `auto`, `pbcopy`, `xclip`, `xsel`, `wl-copy`, `tmux`, `osc52`,
+    /// `file` or `command`. Anything else is treated as `auto`.
+    pub(crate) backend: String,
+    /// Program and arguments for the `command` backend. The text is written to
+    /// its stdin.
+    pub(crate) copy_command: Vec<String>,
+    /// Program and arguments that print the clipboard, for the `command`
+    /// backend. Optional.
+    pub(crate) paste_command: Vec<String>,
+    /// Where the `file` backend writes to.
+    pub(crate) file: String,
+}
+
+impl Default for ClipboardSettings {
+    fn default() -> ClipboardSettings {
+        ClipboardSettings {
+            backend: String::from("auto"),
+            copy_command: Vec::new(),
+            paste_command: Vec::new(),
+            file: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Pbcopy,
+    Xclip,
+    Xsel,
+    WlCopy,
+    Tmux,
+    Osc52,
+    File,
+    Command,
+}
+
+impl Backend {
+    fn from_name(name: &str) -> Option<Backend> {
+        match name.to_ascii_lowercase().as_ref() {
+            "pbcopy" => Some(Backend::Pbcopy),
+            "xclip" => Some(Backend::Xclip),
+            "xsel" => Some(Backend::Xsel),
+            "wl-copy" | "wayland" => Some(Backend::WlCopy),
+            "tmux" => Some(Backend::Tmux),
+            "osc52" => Some(Backend::Osc52),
+            "file" => Some(Backend::File),
+            "command" => Some(Backend::Command),
+            _ => None,
+        }
+    }
+
+    /// Picks the best clipboard for the current session. `is_set` tells whether
+    /// an environment variable is set and `is_installed` whether a program is on
+    /// the `PATH`.
+    ///
+    /// Falls back to the OSC 52 escape, which works over SSH and without a
+    /// display server as long as the terminal supports it.
+    pub fn detect<V, I>(macos: bool, is_set: V, is_installed: I) -> Backend
+    where
+        V: Fn(&str) -> bool,
+        I: Fn(&str) -> bool,
+    {
+        if macos {
+            Backend::Pbcopy
+        } else if is_set("WAYLAND_DISPLAY") && is_installed("wl-copy") {
+            Backend::WlCopy
+        } else if is_set("DISPLAY") && is_installed("xclip") {
+            Backend::Xclip
+        } else if is_set("DISPLAY") && is_installed("xsel") {
+            Backend::Xsel
+        } else if is_set("TMUX") && is_installed("tmux") {
+            Backend::Tmux
+        } else {
+            Backend::Osc52
+        }
+    }
+}
+
+/// Builds the clipboard chosen in the config, detecting one if it's set to
+/// `auto` or the chosen backend is missing its settings.
+pub fn from_settings(settings: &ClipboardSettings) -> Box<dyn Clipboard> {
+    let backend = Backend::from_name(&settings.backend)
+        .unwrap_or_else(|| Backend::detect(cfg!(target_os = "macos"), env_is_set, is_installed));
+
+    match backend {
+        Backend::Pbcopy => CommandClipboard::boxed(&["pbcopy"], &["pbpaste"]),
+        Backend::Xclip => CommandClipboard::boxed(
+            &["xclip", "-selection", "clipboard"],
+            &["xclip", "-selection", "clipboard", "-o"],
+        ),
+        Backend::Xsel => CommandClipboard::boxed(
+            &["xsel", "--clipboard", "--input"],
+            &["xsel", "--clipboard", "--output"],
+        ),
+        Backend::WlCopy => CommandClipboard::boxed(&["wl-copy"], &["wl-paste", "--no-newline"]),
+        Backend::Tmux => {
+            CommandClipboard::boxed(&["tmux", "load-buffer", "-"], &["tmux", "save-buffer", "-"])
+        }
+        Backend::Osc52 => Box::new(Osc52Clipboard {
+            tmux: env_is_set("TMUX"),
+        }),
+        Backend::File if !settings.file.is_empty() => Box::new(FileClipboard {
+            path: PathBuf::from(&settings.file),
+        }),
+        Backend::Command if !settings.copy_command.is_empty() => Box::new(CommandClipboard {
+            copy: settings.copy_command.clone(),
+            paste: settings.paste_command.clone(),
+        }),
+        Backend::File | Backend::Command => from_settings(&ClipboardSettings::default()),
+    }
+}
+
+fn env_is_set(name: &str) -> bool {
+    std::env::var_os(name).is_some_and(|value| !value.is_empty())
+}
+
+fn is_installed(program: &str) -> bool {
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
+
+/// A clipboard driven by external programs: one that reads the text to copy on
+/// stdin and, optionally, one that prints the clipboard.
+pub struct CommandClipboard {
+    copy: Vec<String>,
+    paste: Vec<String>,
+}
+
+impl CommandClipboard {
+    fn boxed(copy: &[&str], paste: &[&str]) -> Box<dyn Clipboard> {
+        Box::new(CommandClipboard {
+            copy: copy.iter().map(|a| a.to_string()).collect(),
+            paste: paste.iter().map(|a| a.to_string()).collect(),
+        })
+    }
+}
+
+impl Clipboard for CommandClipboard {
+    fn copy(&mut self, text: &str) -> Result<(), Error> {
+        let (program, args) = self
+            .copy
+            .split_first()
+            .ok_or_else(|| Error::Clipboard(String::from("No copy command set")))?;
+        // Some of these fork to keep serving the clipboard, so don't let them
+        // hold on to the terminal.
+        let mut process = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| Error::Clipboard(format!("Couldn't run {}: {}", program, e)))?;
+
+        process
+            .stdin
+            .take()
+            .ok_or_else(|| Error::Clipboard(format!("Couldn't write to {}", program)))?
+            .write_all(text.as_bytes())?;
+        if !process.wait()?.success() {
+            return Err(Error::Clipboard(format!(
+                "{} couldn't copy to the clipboard",
+                program
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn paste(&mut self) -> Result<String, Error> {
+        let (program, args) = self
+            .paste
+            .split_first()
+            .ok_or_else(|| Error::Clipboard(String::from("No paste command set")))?;
+        let output = Command::new(program)
+            .args(args)
+            .stderr(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::Clipboard(format!(
+                "{} couldn't read the clipboard",
+                program
+            )));
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+}
+
+/// Sets the terminal's own clipboard with the OSC 52 escape sequence, which
+/// terminals pass on to the local clipboard even over SSH. Write only.
+pub struct Osc52Clipboard {
+    /// tmux swallows the sequence unless it's wrapped in a passthrough.
+    tmux: bool,
+}
+
+impl Osc52Clipboard {
+    fn sequence(&self, text: &str) -> String {
+        let sequence = format!("\x1b]52;c;{}\x07", encode(text));
+        if self.tmux {
+            format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+        } else {
+            sequence
+        }
+    }
+}
+
+impl Clipboard for Osc52Clipboard {
+    fn copy(&mut self, text: &str) -> Result<(), Error> {
+        let sequence = self.sequence(text);
+        match OpenOptions::new().write(true).open("/dev/tty") {
+            Ok(mut tty) => tty.write_all(sequence.as_bytes())?,
+            Err(_) => {
+                let mut stdout = io::stdout();
+                stdout.write_all(sequence.as_bytes())?;
+                stdout.flush()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn paste(&mut self) -> Result<String, Error> {
+        Err(Error::Clipboard(String::from(
+            "The terminal clipboard can't be read back",
+        )))
+    }
+}
+
+/// Writes the copied text to a file, readable only by the user, for sessions
+/// with no clipboard at all.
+pub struct FileClipboard {
+    path: PathBuf,
+}
+
+impl Clipboard for FileClipboard {
+    fn copy(&mut self, text: &str) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        file.write_all(text.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn paste(&mut self) -> Result<String, Error> {
+        Ok(fs::read_to_string(&self.path)?)
+    }
+}
+
+/// A password copied to the clipboard, to be cleared again once `clear_at` has
+/// passed.
+pub struct ClipboardTimer {
+    contents: SecretString,
+    clear_at: Instant,
+}
+
+impl ClipboardTimer {
+    pub fn new(contents: SecretString, timeout: Duration, now: Instant) -> Self {
+        ClipboardTimer {
+            contents,
+            clear_at: now + timeout,
+        }
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.clear_at
+    }
+
+    /// Whole seconds left before clearing, rounded up so the countdown doesn't
+    /// show 0 while the password is still there.
+    pub fn seconds_left(&self, now: Instant) -> u64 {
+        let left = self.clear_at.saturating_duration_since(now);
+        left.as_secs() + u64::from(left.subsec_nanos() > 0)
+    }
+
+    /// Clears the clipboard, unless something else has been copied over our
+    /// password since. Clipboards that can't be read back are always cleared.
+    pub fn clear(self, clipboard: &mut dyn Clipboard) -> Result<(), Error> {
+        match clipboard.paste().map(SecretString::from) {
+            Ok(contents) if contents != self.contents => Ok(()),
+            _ => clipboard.clear(),
+        }
+    }
+}
+
+/// An in-memory clipboard for tests. Clones share the same contents, so a test
+/// can hand one to the table and inspect it through another.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct FakeClipboard {
+    contents: std::rc::Rc<std::cell::RefCell<String>>,
+}
+
+#[cfg(test)]
+impl FakeClipboard {
+    pub fn contents(&self) -> String {
+        self.contents.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Clipboard for FakeClipboard {
+    fn copy(&mut self, text: &str) -> Result<(), Error> {
+        *self.contents.borrow_mut() = text.to_string();
+        Ok(())
+    }
+
+    fn paste(&mut self) -> Result<String, Error> {
+        Ok(self.contents())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &'static [&'static str]) -> impl Fn(&str) -> bool {
+        move |name| vars.contains(&name)
+    }
+
+    #[test]
+    fn test_detect_backend() {
+        let everything = env(&["wl-copy", "xclip", "xsel", "tmux"]);
+        assert_eq!(Backend::detect(true, env(&[]), env(&[])), Backend::Pbcopy);
+        assert_eq!(
+            Backend::detect(false, env(&["WAYLAND_DISPLAY", "DISPLAY"]), &everything),
+            Backend::WlCopy
+        );
+        assert_eq!(
+            Backend::detect(false, env(&["DISPLAY"]), &everything),
+            Backend::Xclip
+        );
+        assert_eq!(
+            Backend::detect(false, env(&["DISPLAY"]), env(&["xsel"])),
+            Backend::Xsel
+        );
+        assert_eq!(
+            Backend::detect(false, env(&["TMUX"]), &everything),
+            Backend::Tmux
+        );
+        assert_eq!(
+            Backend::detect(false, env(&["DISPLAY"]), env(&[])),
+            Backend::Osc52
+        );
+    }
+
+    #[test]
+    fn test_backend_from_name() {
+        assert_eq!(Backend::from_name("wl-copy"), Some(Backend::WlCopy));
+        assert_eq!(Backend::from_name("OSC52"), Some(Backend::Osc52));
+        assert_eq!(Backend::from_name("auto"), None);
+        assert_eq!(Backend::from_name("bogus"), None);
+    }
+
+    #[test]
+    fn test_osc52_sequence() {
+        let plain = Osc52Clipboard { tmux: false };
+        assert_eq!(plain.sequence("secret"), "\x1b]52;c;c2VjcmV0\x07");
+
+        let tmux = Osc52Clipboard { tmux: true };
+        assert_eq!(
+            tmux.sequence("secret"),
+            "\x1bPtmux;\x1b\x1b]52;c;c2VjcmV0\x07\x1b\\"
+        );
+    }
+
+    #[test]
+    fn test_file_clipboard() {
+        let path = std::env::temp_dir().join(format!("psc-clipboard-{}", std::process::id()));
+        let mut clipboard = FileClipboard { path: path.clone() };
+
+        clipboard.copy("secret").unwrap();
+        assert_eq!(clipboard.paste().unwrap(), "secret");
+        clipboard.clear().unwrap();
+        assert_eq!(clipboard.paste().unwrap(), "");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_command_clipboard() {
+        let path = std::env::temp_dir().join(format!("psc-command-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut clipboard = CommandClipboard {
+            copy: vec![
+                String::from("sh"),
+                String::from("-c"),
+                format!("cat > {}", path),
+            ],
+            paste: vec![String::from("cat"), String::from(path)],
+        };
+
+        clipboard.copy("secret").unwrap();
+        assert_eq!(clipboard.paste().unwrap(), "secret");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_missing_command_is_err() {
+        let mut clipboard = CommandClipboard {
+            copy: vec![String::from("psc-no-such-program")],
+            paste: Vec::new(),
+        };
+        assert!(clipboard.copy("secret").is_err());
+        assert!(clipboard.paste().is_err());
+    }
+
+    #[test]
+    fn test_timer_expiry() {
+        let now = Instant::now();
+        let timer = ClipboardTimer::new(SecretString::from("secret"), Duration::from_secs(30), now);
+        assert!(!timer.is_expired(now + Duration::from_secs(29)));
+        assert!(timer.is_expired(now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_seconds_left_rounds_up() {
+        let now = Instant::now();
+        let timer = ClipboardTimer::new(SecretString::from("secret"), Duration::from_secs(30), now);
+        assert_eq!(timer.seconds_left(now), 30);
+        assert_eq!(timer.seconds_left(now + Duration::from_millis(100)), 30);
+        assert_eq!(timer.seconds_left(now + Duration::from_millis(29_900)), 1);
+        assert_eq!(timer.seconds_left(now + Duration::from_secs(31)), 0);
+    }
+
+    #[test]
+    fn test_clear_write_only_clipboard() {
+        let path = std::env::temp_dir().join(format!("psc-osc-{}", std::process::id()));
+        let mut clipboard = CommandClipboard {
+            copy: vec![
+                String::from("sh"),
+                String::from("-c"),
+                format!("cat > {}", path.display()),
+            ],
+            paste: Vec::new(),
+        };
+
+        clipboard.copy("secret").unwrap();
+        ClipboardTimer::new(
+            SecretString::from("secret"),
+            Duration::from_secs(30),
+            Instant::now(),
+        )
+        .clear(&mut clipboard)
+        .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_clear_only_clears_our_password() {
+        let mut clipboard = FakeClipboard::default();
+        let now = Instant::now();
+
+        clipboard.copy("secret").unwrap();
+        ClipboardTimer::new(SecretString::from("secret"), Duration::from_secs(30), now)
+            .clear(&mut clipboard)
+            .unwrap();
+        assert_eq!(clipboard.contents(), "");
+
+        clipboard.copy("something else").unwrap();
+        ClipboardTimer::new(SecretString::from("secret"), Duration::from_secs(30), now)
+            .clear(&mut clipboard)
+            .unwrap();
+        assert_eq!(clipboard.contents(), "something else");
+    }
+}
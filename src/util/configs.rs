@@ -1,29 +1,105 @@
+use crate::util::clipboard::ClipboardSettings;
+use crate::util::error::Error;
+use crate::util::generator::GeneratorSettings;
+use crate::util::keymap::{Keymap, KeymapSettings};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 use tui::style::Modifier;
 use tui::widgets::BorderType;
 
+pub const CONFIG_VERSION: u32 = 9;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RawConfigs {
+    #[serde(default)]
+    pub(crate) version: u32,
     pub(crate) border_type: String,
     pub(crate) border_style: String,
     pub(crate) title_style: String,
+    #[serde(default = "default_login_attempts")]
+    pub(crate) login_attempts: u8,
+    #[serde(default)]
+    pub(crate) generator: GeneratorSettings,
+    /// Seconds before a copied password is cleared from the clipboard, 0 to
+    /// leave it there.
+    #[serde(default = "default_clipboard_timeout")]
+    pub(crate) clipboard_timeout: u64,
+    #[serde(default)]
+    pub(crate) clipboard: ClipboardSettings,
+    /// Seconds without a key press before the TUI locks, 0 to never lock.
+    #[serde(default = "default_lock_timeout")]
+    pub(crate) lock_timeout: u64,
+    /// How many previous passwords each entry keeps.
+    #[serde(default = "default_history_size")]
+    pub(crate) history_size: usize,
+    /// Days after which the audit reports a password as old, 0 to never.
+    #[serde(default = "default_max_password_age")]
+    pub(crate) max_password_age: u64,
+    /// A downloaded Have I Been Pwned SHA-1 file, or an index built from it with
+    /// `psc breach-index`, to check passwords against. Empty to not check.
+    #[serde(default)]
+    pub(crate) breach_file: String,
+    #[serde(default)]
+    pub(crate) keymap: KeymapSettings,
 }
 
 impl Default for RawConfigs {
     fn default() -> RawConfigs {
         RawConfigs {
+            version: CONFIG_VERSION,
             border_type: "rounded".to_string(),
             border_style: "bold".to_string(),
             title_style: "italic".to_string(),
+            login_attempts: default_login_attempts(),
+            generator: GeneratorSettings::default(),
+            clipboard_timeout: default_clipboard_timeout(),
+            clipboard: ClipboardSettings::default(),
+            lock_timeout: default_lock_timeout(),
+            history_size: default_history_size(),
+            max_password_age: default_max_password_age(),
+            breach_file: String::new(),
+            keymap: KeymapSettings::default(),
         }
     }
 }
 
+fn default_login_attempts() -> u8 {
+    3
+}
+
+pub(crate) fn default_clipboard_timeout() -> u64 {
+    30
+}
+
+pub(crate) fn default_lock_timeout() -> u64 {
+    300
+}
+
+pub(crate) fn default_history_size() -> usize {
+    10
+}
+
+pub(crate) fn default_max_password_age() -> u64 {
+    365
+}
+
 #[derive(Debug)]
 pub struct CursesConfigs {
+    /// Parsed from the config but not applied to any borders yet.
+    #[allow(dead_code)]
     pub border_type: BorderType,
     pub border_style: Modifier,
     pub title_style: Modifier,
+    pub login_attempts: u8,
+    pub generator: GeneratorSettings,
+    pub clipboard_timeout: Duration,
+    pub clipboard: ClipboardSettings,
+    pub lock_timeout: Duration,
+    pub history_size: usize,
+    pub max_password_age: Duration,
+    pub breach_file: Option<PathBuf>,
+    pub keymap: Keymap,
 }
 
 impl Default for CursesConfigs {
@@ -32,17 +108,39 @@ impl Default for CursesConfigs {
             border_type: BorderType::Rounded,
             border_style: Modifier::BOLD,
             title_style: Modifier::ITALIC,
+            login_attempts: default_login_attempts(),
+            generator: GeneratorSettings::default(),
+            clipboard_timeout: Duration::from_secs(default_clipboard_timeout()),
+            clipboard: ClipboardSettings::default(),
+            lock_timeout: Duration::from_secs(default_lock_timeout()),
+            history_size: default_history_size(),
+            max_password_age: days(default_max_password_age()),
+            breach_file: None,
+            keymap: Keymap::default(),
         }
     }
 }
 
 impl CursesConfigs {
-    pub fn new(border_type: String, border_style: String, title_style: String) -> Self {
-        CursesConfigs {
-            border_type: match_border_type(border_type),
-            border_style: match_modifier(border_style),
-            title_style: match_modifier(title_style),
-        }
+    /// Fails if the keymap names keys or actions that don't exist.
+    pub fn new(raw_config: RawConfigs) -> Result<Self, Error> {
+        Ok(CursesConfigs {
+            border_type: match_border_type(raw_config.border_type),
+            border_style: match_modifier(raw_config.border_style),
+            title_style: match_modifier(raw_config.title_style),
+            // Zero attempts would lock the vault for good.
+            login_attempts: raw_config.login_attempts.max(1),
+            generator: raw_config.generator,
+            clipboard_timeout: Duration::from_secs(raw_config.clipboard_timeout),
+            clipboard: raw_config.clipboard,
+            lock_timeout: Duration::from_secs(raw_config.lock_timeout),
+            history_size: raw_config.history_size,
+            max_password_age: days(raw_config.max_password_age),
+            breach_file: Some(raw_config.breach_file)
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            keymap: Keymap::from_settings(&raw_config.keymap)?,
+        })
     }
 }
 
@@ -74,3 +172,8 @@ fn match_modifier(modifier: String) -> Modifier {
 
     result
 }
+
+#[inline]
+fn days(days: u64) -> Duration {
+    Duration::from_secs(days.saturating_mul(24 * 60 * 60))
+}
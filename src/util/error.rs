@@ -0,0 +1,177 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+use std::sync::mpsc::RecvError;
+
+/// Everything that can go wrong in passcurses, from reading files to talking to
+/// the clipboard.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// A stored value that isn't valid base64.
+    Base64(base64::DecodeError),
+    /// Decrypted bytes that aren't valid UTF-8.
+    Utf8(FromUtf8Error),
+    KeyDerivation(argon2::Error),
+    Csv(csv::Error),
+    Xml(roxmltree::Error),
+    /// An export from another password manager that can't be imported.
+    Import(String),
+    Encrypt,
+    /// A password, or the whole passwords file, won't decrypt with this key.
+    Decrypt,
+    WrongKey,
+    /// A file that parsed, but isn't laid out the way it should be.
+    Malformed(String),
+    /// A file written by a newer version of passcurses.
+    UnsupportedVersion {
+        file: String,
+        version: u32,
+        supported: u32,
+    },
+    NoHomeDirectory,
+    Clipboard(String),
+    Generator(&'static str),
+    /// Bad input on the command line or at a prompt.
+    Input(String),
+    /// Entries that wouldn't decrypt with the vault's key, so were moved aside
+    /// when it was opened.
+    Unreadable(Vec<String>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "Invalid JSON: {}", e),
+            Error::Base64(e) => write!(f, "Corrupt encrypted data: {}", e),
+            Error::Utf8(_) => write!(f, "Decrypted data isn't valid text"),
+            Error::KeyDerivation(e) => write!(f, "Unable to derive the key: {}", e),
+            Error::Csv(e) => write!(f, "Invalid CSV: {}", e),
+            Error::Xml(e) => write!(f, "Invalid XML: {}", e),
+            Error::Import(message) => write!(f, "{}", message),
+            Error::Encrypt => write!(f, "Unable to encrypt"),
+            Error::Decrypt => write!(f, "Unable to decrypt with this key"),
+            Error::WrongKey => write!(f, "Incorrect key, refusing to open passwords"),
+            Error::Malformed(message) => write!(f, "{}", message),
+            Error::UnsupportedVersion {
+                file,
+                version,
+                supported,
+            } => write!(
+                f,
+                "{} is version {}, newer than this build supports ({})",
+                file, version, supported
+            ),
+            Error::NoHomeDirectory => write!(f, "Unable to find the home directory"),
+            Error::Clipboard(message) => write!(f, "{}", message),
+            Error::Generator(message) => write!(f, "{}", message),
+            Error::Input(message) => write!(f, "{}", message),
+            Error::Unreadable(services) => write!(
+                f,
+                "{} entries won't decrypt with this key and were moved to unreadable.json: {}",
+                services.len(),
+                services.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Base64(e) => Some(e),
+            Error::Utf8(e) => Some(e),
+            Error::KeyDerivation(e) => Some(e),
+            Error::Csv(e) => Some(e),
+            Error::Xml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Error {
+        Error::Base64(e)
+    }
+}
+
+impl From<FromUtf8Error> for Error {
+    fn from(e: FromUtf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
+
+impl From<argon2::Error> for Error {
+    fn from(e: argon2::Error) -> Error {
+        Error::KeyDerivation(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Error {
+        Error::Csv(e)
+    }
+}
+
+impl From<roxmltree::Error> for Error {
+    fn from(e: roxmltree::Error) -> Error {
+        Error::Xml(e)
+    }
+}
+
+impl From<rand::Error> for Error {
+    fn from(e: rand::Error) -> Error {
+        Error::Io(e.into())
+    }
+}
+
+/// The terminal's input thread has gone away.
+impl From<RecvError> for Error {
+    fn from(_: RecvError) -> Error {
+        Error::Io(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "Lost input from the terminal",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error_keeps_source() {
+        let error = Error::from(io::Error::new(io::ErrorKind::NotFound, "gone"));
+        assert_eq!(error.to_string(), "gone");
+        assert!(std::error::Error::source(&error).is_some());
+    }
+
+    #[test]
+    fn test_unsupported_version_message() {
+        let error = Error::UnsupportedVersion {
+            file: String::from("config.json"),
+            version: 9,
+            supported: 5,
+        };
+        assert_eq!(
+            error.to_string(),
+            "config.json is version 9, newer than this build supports (5)"
+        );
+    }
+}
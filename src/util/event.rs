@@ -42,11 +42,9 @@ impl Events {
             let tx = tx.clone();
             thread::spawn(move || {
                 let stdin = io::stdin();
-                for evt in stdin.keys() {
-                    if let Ok(key) = evt {
-                        if tx.send(Event::Input(key)).is_err() {
-                            return;
-                        }
+                for key in stdin.keys().flatten() {
+                    if tx.send(Event::Input(key)).is_err() {
+                        return;
                     }
                 }
             })
@@ -56,7 +54,9 @@ impl Events {
             thread::spawn(move || {
                 let tx = tx.clone();
                 loop {
-                    tx.send(Event::Tick).unwrap();
+                    if tx.send(Event::Tick).is_err() {
+                        return;
+                    }
                     thread::sleep(config.tick_rate);
                 }
             })
@@ -0,0 +1,542 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use aes_gcm::aead::{generic_array::GenericArray, Aead, Payload};
+use aes_gcm::Aes128Gcm;
+use argon2::{Config, Variant};
+use base64::{decode, encode};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use zeroize::Zeroize;
+
+use crate::util::error::Error;
+use crate::util::import::PlainEntry;
+use crate::util::json_utils::{EntryDetails, PasswordEntry};
+use crate::util::secret::SecretString;
+use crate::util::utils::{decrypt_optional, try_decrypt, AesWrapper, EncryptionData};
+
+pub const BACKUP_FORMAT: &str = "passcurses-backup";
+pub const BACKUP_VERSION: u32 = 1;
+
+/// Argon2id settings for new backups. They're stored in the backup, so they
+/// can be raised without breaking older ones.
+const BACKUP_KDF: KdfParams = KdfParams {
+    mem_cost: 19_456,
+    time_cost: 2,
+    lanes: 1,
+};
+/// The most memory, in KiB, passes and threads a backup may ask for to derive
+/// its key. Keeps a hostile file from exhausting memory, or hanging the import,
+/// before the passphrase is checked.
+const MAX_MEM_COST: u32 = 1 << 21;
+const MAX_TIME_COST: u32 = 64;
+const MAX_LANES: u32 = 64;
+
+/// What `psc export` can write.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExportFormat {
+    /// Everything, encrypted under its own passphrase.
+    Backup,
+    /// Plaintext, in the CSV layout browsers and most managers import.
+    Csv,
+    /// Plaintext, as an unencrypted Bitwarden JSON export.
+    Bitwarden,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name {
+            "backup" => Some(ExportFormat::Backup),
+            "csv" => Some(ExportFormat::Csv),
+            "bitwarden" => Some(ExportFormat::Bitwarden),
+            _ => None,
+        }
+    }
+
+    /// Whether the export leaves the passwords readable by anyone with the file.
+    pub fn is_plaintext(self) -> bool {
+        self != ExportFormat::Backup
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct KdfParams {
+    mem_cost: u32,
+    time_cost: u32,
+    lanes: u32,
+}
+
+/// A portable backup of a vault. Unlike `passwords.json` it doesn't need
+/// `passrc.json` or the master key: it says how its key is derived from the
+/// passphrase, and everything but the ciphertext is authenticated as
+/// associated data.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    format: String,
+    version: u32,
+    kdf: String,
+    kdf_params: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// One entry inside a backup, in the clear.
+#[derive(Serialize, Deserialize)]
+struct BackupEntry {
+    service: String,
+    password: String,
+    username: String,
+    url: String,
+    notes: String,
+    tags: Vec<String>,
+    /// Not in backups made before 2FA secrets could be stored.
+    #[serde(default)]
+    totp: String,
+}
+
+impl Drop for BackupEntry {
+    fn drop(&mut self) {
+        self.password.zeroize();
+        self.notes.zeroize();
+        self.totp.zeroize();
+    }
+}
+
+impl Backup {
+    pub fn seal(entries: &[PlainEntry], passphrase: &str) -> Result<Backup, Error> {
+        Backup::seal_with(entries, passphrase, BACKUP_KDF)
+    }
+
+    fn seal_with(
+        entries: &[PlainEntry],
+        passphrase: &str,
+        params: KdfParams,
+    ) -> Result<Backup, Error> {
+        let mut salt = [0_u8; 16];
+        let mut nonce = [0_u8; 12];
+        thread_rng().try_fill(&mut salt[..])?;
+        thread_rng().try_fill(&mut nonce[..])?;
+
+        let mut backup = Backup {
+            format: BACKUP_FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            kdf: String::from("argon2id"),
+            kdf_params: params,
+            salt: encode(salt),
+            nonce: encode(nonce),
+            ciphertext: String::new(),
+        };
+
+        let contents: Vec<BackupEntry> = entries
+            .iter()
+            .map(|entry| BackupEntry {
+                service: entry.service.clone(),
+                password: entry.password.expose().to_string(),
+                username: entry.details.username.clone(),
+                url: entry.details.url.clone(),
+                notes: entry.details.notes.expose().to_string(),
+                tags: entry.details.tags.clone(),
+                totp: entry.details.totp.expose().to_string(),
+            })
+            .collect();
+        let mut plaintext = serde_json::to_vec(&contents)?;
+        let sealed = backup.key(passphrase)?.encrypt(
+            GenericArray::from_slice(&nonce),
+            Payload {
+                msg: &plaintext,
+                aad: &backup.aad(),
+            },
+        );
+        plaintext.zeroize();
+        backup.ciphertext = encode(sealed.map_err(|_| Error::Encrypt)?);
+
+        Ok(backup)
+    }
+
+    pub fn read(path: &Path) -> Result<Backup, Error> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Decrypts the entries. A wrong passphrase and a tampered backup look the
+    /// same.
+    pub fn open(&self, passphrase: &str) -> Result<Vec<PlainEntry>, Error> {
+        if self.format != BACKUP_FORMAT {
+            return Err(Error::Malformed(format!("Not a backup: {}", self.format)));
+        }
+        if self.version > BACKUP_VERSION {
+            return Err(Error::UnsupportedVersion {
+                file: String::from("The backup"),
+                version: self.version,
+                supported: BACKUP_VERSION,
+            });
+        }
+        let params = &self.kdf_params;
+        if self.kdf != "argon2id"
+            || params.mem_cost > MAX_MEM_COST
+            || params.time_cost > MAX_TIME_COST
+            || params.lanes > MAX_LANES
+        {
+            return Err(Error::Malformed(format!(
+                "Unsupported backup key derivation: {}",
+                self.kdf
+            )));
+        }
+
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(Error::Malformed(String::from("Corrupt backup nonce")));
+        }
+        let mut plaintext = self
+            .key(passphrase)?
+            .decrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &decode(&self.ciphertext)?,
+                    aad: &self.aad(),
+                },
+            )
+            .map_err(|_| Error::Decrypt)?;
+        let contents: Result<Vec<BackupEntry>, _> = serde_json::from_slice(&plaintext);
+        plaintext.zeroize();
+
+        Ok(contents?
+            .into_iter()
+            .map(|mut entry| PlainEntry {
+                service: std::mem::take(&mut entry.service),
+                password: SecretString::from(entry.password.as_str()),
+                details: EntryDetails {
+                    username: std::mem::take(&mut entry.username),
+                    url: std::mem::take(&mut entry.url),
+                    notes: SecretString::from(entry.notes.as_str()),
+                    tags: std::mem::take(&mut entry.tags),
+                    totp: SecretString::from(entry.totp.as_str()),
+                },
+            })
+            .collect())
+    }
+
+    fn key(&self, passphrase: &str) -> Result<Aes128Gcm, Error> {
+        let config = Config {
+            variant: Variant::Argon2id,
+            mem_cost: self.kdf_params.mem_cost,
+            time_cost: self.kdf_params.time_cost,
+            lanes: self.kdf_params.lanes,
+            hash_length: 16,
+            ..Config::default()
+        };
+        let mut raw = argon2::hash_raw(passphrase.as_bytes(), &decode(&self.salt)?, &config)?;
+        let key = AesWrapper::new(&raw).aead;
+        raw.zeroize();
+
+        Ok(key)
+    }
+
+    fn aad(&self) -> Vec<u8> {
+        format!(
+            "{}:{}:{}:{}:{}:{}:{}:{}",
+            self.format,
+            self.version,
+            self.kdf,
+            self.kdf_params.mem_cost,
+            self.kdf_params.time_cost,
+            self.kdf_params.lanes,
+            self.salt,
+            self.nonce
+        )
+        .into_bytes()
+    }
+}
+
+/// Decrypts every entry in the vault, sorted by service.
+pub fn decrypt_entries(
+    map: HashMap<String, PasswordEntry>,
+    key: &Aes128Gcm,
+) -> Result<Vec<PlainEntry>, Error> {
+    let mut entries = map
+        .into_iter()
+        .map(|(service, entry)| {
+            let password = SecretString::from(try_decrypt(EncryptionData {
+                password: &entry.password,
+                nonce: &entry.nonce,
+                key,
+            })?);
+            let notes =
+                SecretString::from(decrypt_optional(&entry.notes, &entry.notes_nonce, key)?);
+            let totp = SecretString::from(decrypt_optional(&entry.totp, &entry.totp_nonce, key)?);
+
+            Ok(PlainEntry {
+                service,
+                password,
+                details: EntryDetails {
+                    username: entry.username,
+                    url: entry.url,
+                    notes,
+                    tags: entry.tags,
+                    totp,
+                },
+            })
+        })
+        .collect::<Result<Vec<PlainEntry>, Error>>()?;
+    entries.sort_by(|a, b| a.service.cmp(&b.service));
+
+    Ok(entries)
+}
+
+/// Writes `entries` out in `format`. Only backups need a passphrase.
+pub fn export(
+    format: ExportFormat,
+    entries: &[PlainEntry],
+    passphrase: Option<&str>,
+) -> Result<Vec<u8>, Error> {
+    match format {
+        ExportFormat::Backup => {
+            let passphrase = passphrase
+                .ok_or_else(|| Error::Input(String::from("A backup needs a passphrase")))?;
+            Ok(serde_json::to_vec_pretty(&Backup::seal(
+                entries, passphrase,
+            )?)?)
+        }
+        ExportFormat::Csv => to_csv(entries),
+        ExportFormat::Bitwarden => to_bitwarden(entries),
+    }
+}
+
+/// Creates `path`, readable only by its owner, and writes `contents` to it. An
+/// existing file is never overwritten.
+pub fn write_export(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+
+    Ok(file.sync_all()?)
+}
+
+fn to_csv(entries: &[PlainEntry]) -> Result<Vec<u8>, Error> {
+    let mut csv = csv::Writer::from_writer(Vec::new());
+    csv.write_record([
+        "name", "url", "username", "password", "notes", "tags", "totp",
+    ])?;
+    for entry in entries {
+        csv.write_record([
+            entry.service.as_str(),
+            &entry.details.url,
+            &entry.details.username,
+            entry.password.expose(),
+            entry.details.notes.expose(),
+            &entry.details.tags.join(";"),
+            entry.details.totp.expose(),
+        ])?;
+    }
+
+    csv.into_inner().map_err(|e| Error::Io(e.into_error()))
+}
+
+/// Bitwarden has folders rather than tags, so each entry goes in the folder
+/// named after its first tag.
+fn to_bitwarden(entries: &[PlainEntry]) -> Result<Vec<u8>, Error> {
+    let mut folders: Vec<&str> = entries
+        .iter()
+        .filter_map(|entry| entry.details.tags.first().map(String::as_str))
+        .collect();
+    folders.sort_unstable();
+    folders.dedup();
+    let folder_id = |tag: &str| {
+        folders
+            .iter()
+            .position(|f| *f == tag)
+            .map(|i| i.to_string())
+    };
+
+    let items: Vec<serde_json::Value> = entries
+        .iter()
+        .map(|entry| {
+            let uris = if entry.details.url.is_empty() {
+                json!([])
+            } else {
+                json!([{ "match": null, "uri": entry.details.url }])
+            };
+            json!({
+                "type": 1,
+                "name": entry.service,
+                "notes": if entry.details.notes.is_empty() { None } else { Some(entry.details.notes.expose()) },
+                "favorite": false,
+                "folderId": entry.details.tags.first().and_then(|tag| folder_id(tag)),
+                "login": {
+                    "username": entry.details.username,
+                    "password": entry.password.expose(),
+                    "uris": uris,
+                    "totp": if entry.details.totp.is_empty() { None } else { Some(entry.details.totp.expose()) },
+                },
+            })
+        })
+        .collect();
+    let folders: Vec<serde_json::Value> = folders
+        .iter()
+        .enumerate()
+        .map(|(id, name)| json!({ "id": id.to_string(), "name": name }))
+        .collect();
+
+    Ok(serde_json::to_vec_pretty(&json!({
+        "encrypted": false,
+        "folders": folders,
+        "items": items,
+    }))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::import::{parse_bitwarden, parse_csv};
+    use crate::util::json_utils::new_entry;
+    use aes_gcm::NewAead;
+
+    /// Cheap enough to keep the tests quick.
+    const TEST_KDF: KdfParams = KdfParams {
+        mem_cost: 64,
+        time_cost: 1,
+        lanes: 1,
+    };
+
+    fn entries() -> Vec<PlainEntry> {
+        vec![
+            PlainEntry {
+                service: String::from("github"),
+                password: SecretString::from("hun,ter\"2"),
+                details: EntryDetails {
+                    username: String::from("ernie"),
+                    url: String::from("https://github.com"),
+                    notes: SecretString::from("two\nlines"),
+                    tags: vec![String::from("code"), String::from("work")],
+                    totp: SecretString::from("JBSWY3DPEHPK3PXP"),
+                },
+            },
+            PlainEntry {
+                service: String::from("wifi"),
+                password: SecretString::from("pw"),
+                details: EntryDetails::default(),
+            },
+        ]
+    }
+
+    fn assert_same(actual: &[PlainEntry], expected: &[PlainEntry]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert_eq!(a.service, e.service);
+            assert_eq!(a.password, e.password);
+            assert_eq!(a.details, e.details);
+        }
+    }
+
+    #[test]
+    fn test_backup_roundtrip() {
+        let backup = Backup::seal_with(&entries(), "correct horse", TEST_KDF).unwrap();
+        let json = serde_json::to_string(&backup).unwrap();
+        assert!(!json.contains("github"));
+
+        let backup: Backup = serde_json::from_str(&json).unwrap();
+        assert_same(&backup.open("correct horse").unwrap(), &entries());
+    }
+
+    #[test]
+    fn test_backup_wrong_passphrase_is_err() {
+        let backup = Backup::seal_with(&entries(), "correct horse", TEST_KDF).unwrap();
+        assert!(backup.open("battery staple").is_err());
+    }
+
+    #[test]
+    fn test_backup_tampered_header_is_err() {
+        let mut backup = Backup::seal_with(&entries(), "correct horse", TEST_KDF).unwrap();
+        backup.kdf_params.time_cost = 2;
+        assert!(backup.open("correct horse").is_err());
+
+        let mut backup = Backup::seal_with(&entries(), "correct horse", TEST_KDF).unwrap();
+        backup.version = BACKUP_VERSION + 1;
+        assert!(matches!(
+            backup.open("correct horse"),
+            Err(Error::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_backup_excessive_kdf_params_are_malformed() {
+        let excessive = vec![
+            KdfParams {
+                mem_cost: u32::MAX,
+                ..TEST_KDF
+            },
+            KdfParams {
+                time_cost: u32::MAX,
+                ..TEST_KDF
+            },
+            KdfParams {
+                lanes: u32::MAX,
+                ..TEST_KDF
+            },
+        ];
+        for params in excessive {
+            let mut backup = Backup::seal_with(&entries(), "correct horse", TEST_KDF).unwrap();
+            backup.kdf_params = params;
+            assert!(matches!(
+                backup.open("correct horse"),
+                Err(Error::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_csv_export_reimports() {
+        let csv = to_csv(&entries()).unwrap();
+        assert_same(&parse_csv(csv.as_slice()).unwrap(), &entries());
+    }
+
+    #[test]
+    fn test_bitwarden_export_reimports() {
+        let json = to_bitwarden(&entries()).unwrap();
+        let imported = parse_bitwarden(std::str::from_utf8(&json).unwrap()).unwrap();
+        assert_eq!(imported[0].details.tags, vec![String::from("code")]);
+        assert_eq!(imported[0].details.notes, "two\nlines");
+        assert_eq!(imported[0].details.totp, "JBSWY3DPEHPK3PXP");
+        assert_eq!(imported[1].password, "pw");
+    }
+
+    #[test]
+    fn test_decrypt_entries_sorted_with_notes() {
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        let mut map = HashMap::new();
+        for entry in entries().into_iter().rev() {
+            let stored = new_entry(entry.password.expose(), entry.details, &key).unwrap();
+            map.insert(entry.service, stored);
+        }
+
+        assert_same(&decrypt_entries(map, &key).unwrap(), &entries());
+    }
+
+    #[test]
+    fn test_write_export_is_private_and_never_overwrites() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("passcurses-export-{}", std::process::id()));
+        fs::remove_file(&path).ok();
+        write_export(&path, b"secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(write_export(&path, b"again").is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"secret");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_plaintext_formats() {
+        assert!(!ExportFormat::Backup.is_plaintext());
+        assert!(ExportFormat::from_name("csv").unwrap().is_plaintext());
+        assert_eq!(ExportFormat::from_name("xml"), None);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::util::error::Error;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use serde::{Deserialize, Serialize};
+
+use lazy_static::lazy_static;
+
+static LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+static UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+static DIGITS: &str = "0123456789";
+static SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~";
+/// Characters easily mistaken for one another when read or typed by hand.
+static AMBIGUOUS: &str = "0O1lI|";
+
+lazy_static! {
+    /// The BIP-39 English word list: 2048 short, common words, each unique in
+    /// its first four letters, giving 11 bits of entropy per word.
+    static ref WORDS: Vec<&'static str> = include_str!("wordlist.txt").lines().collect();
+}
+
+/// Settings for generated passwords and passphrases, stored under `generator`
+/// in `config.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct GeneratorSettings {
+    pub(crate) length: usize,
+    pub(crate) lowercase: bool,
+    pub(crate) uppercase: bool,
+    pub(crate) digits: bool,
+    pub(crate) symbols: bool,
+    pub(crate) exclude_ambiguous: bool,
+    pub(crate) words: usize,
+    pub(crate) separator: String,
+}
+
+impl Default for GeneratorSettings {
+    fn default() -> GeneratorSettings {
+        GeneratorSettings {
+            length: 20,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+            exclude_ambiguous: true,
+            words: 6,
+            separator: "-".to_string(),
+        }
+    }
+}
+
+/// Generates a random password with at least one character from every enabled
+/// class.
+pub fn generate_password(settings: &GeneratorSettings) -> Result<String, Error> {
+    let classes: Vec<Vec<char>> = [
+        (settings.lowercase, LOWERCASE),
+        (settings.uppercase, UPPERCASE),
+        (settings.digits, DIGITS),
+        (settings.symbols, SYMBOLS),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, chars)| {
+        chars
+            .chars()
+            .filter(|c| !settings.exclude_ambiguous || !AMBIGUOUS.contains(*c))
+            .collect()
+    })
+    .collect();
+
+    if classes.is_empty() {
+        return Err(Error::Generator("No character classes enabled"));
+    }
+    if settings.length < classes.len() {
+        return Err(Error::Generator(
+            "Length is too short to include every character class",
+        ));
+    }
+
+    let mut rng = thread_rng();
+    let all: Vec<char> = classes.iter().flatten().copied().collect();
+    let mut password: Vec<char> = classes
+        .iter()
+        .map(|class| *class.choose(&mut rng).unwrap())
+        .collect();
+    password.extend((password.len()..settings.length).map(|_| *all.choose(&mut rng).unwrap()));
+    password.shuffle(&mut rng);
+
+    Ok(password.into_iter().collect())
+}
+
+/// Generates a diceware-style passphrase from the bundled word list.
+pub fn generate_passphrase(settings: &GeneratorSettings) -> Result<String, Error> {
+    if settings.words == 0 {
+        return Err(Error::Generator("A passphrase needs at least one word"));
+    }
+
+    let mut rng = thread_rng();
+    Ok((0..settings.words)
+        .map(|_| *WORDS.choose(&mut rng).unwrap())
+        .collect::<Vec<&str>>()
+        .join(&settings.separator))
+}
+
+/// Parses `psc generate` flags on top of `settings`. Returns the settings and
+/// whether a passphrase was asked for.
+pub fn parse_args(
+    args: &[String],
+    mut settings: GeneratorSettings,
+) -> Result<(GeneratorSettings, bool), String> {
+    let mut passphrase = false;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| format!("{} needs a value", name))
+                .map(String::as_str)
+        };
+        match arg.as_str() {
+            "--length" | "-l" => {
+                settings.length = value(arg)?
+                    .parse()
+                    .map_err(|_| String::from("--length must be a number"))?
+            }
+            "--words" | "-w" => {
+                passphrase = true;
+                settings.words = value(arg)?
+                    .parse()
+                    .map_err(|_| String::from("--words must be a number"))?
+            }
+            "--separator" => {
+                passphrase = true;
+                settings.separator = value(arg)?.to_string();
+            }
+            "--passphrase" | "-p" => passphrase = true,
+            "--no-lowercase" => settings.lowercase = false,
+            "--no-uppercase" => settings.uppercase = false,
+            "--no-digits" => settings.digits = false,
+            "--no-symbols" => settings.symbols = false,
+            "--exclude-ambiguous" => settings.exclude_ambiguous = true,
+            "--include-ambiguous" => settings.exclude_ambiguous = false,
+            other => return Err(format!("Unknown option: {}", other)),
+        }
+    }
+
+    Ok((settings, passphrase))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_password_has_requested_length() {
+        let settings = GeneratorSettings {
+            length: 32,
+            ..Default::default()
+        };
+        assert_eq!(generate_password(&settings).unwrap().chars().count(), 32);
+    }
+
+    #[test]
+    fn test_password_contains_every_class() {
+        let settings = GeneratorSettings {
+            length: 4,
+            ..Default::default()
+        };
+        for _ in 0..50 {
+            let password = generate_password(&settings).unwrap();
+            assert!(password.chars().any(|c| c.is_ascii_lowercase()));
+            assert!(password.chars().any(|c| c.is_ascii_uppercase()));
+            assert!(password.chars().any(|c| c.is_ascii_digit()));
+            assert!(password.chars().any(|c| SYMBOLS.contains(c)));
+        }
+    }
+
+    #[test]
+    fn test_password_respects_disabled_classes() {
+        let settings = GeneratorSettings {
+            length: 64,
+            uppercase: false,
+            symbols: false,
+            ..Default::default()
+        };
+        let password = generate_password(&settings).unwrap();
+        assert!(password
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_password_excludes_ambiguous_characters() {
+        let settings = GeneratorSettings {
+            length: 500,
+            exclude_ambiguous: true,
+            ..Default::default()
+        };
+        let password = generate_password(&settings).unwrap();
+        assert!(!password.chars().any(|c| AMBIGUOUS.contains(c)));
+    }
+
+    #[test]
+    fn test_password_without_classes_is_err() {
+        let settings = GeneratorSettings {
+            lowercase: false,
+            uppercase: false,
+            digits: false,
+            symbols: false,
+            ..Default::default()
+        };
+        assert!(generate_password(&settings).is_err());
+    }
+
+    #[test]
+    fn test_password_shorter_than_classes_is_err() {
+        let settings = GeneratorSettings {
+            length: 3,
+            ..Default::default()
+        };
+        assert!(generate_password(&settings).is_err());
+    }
+
+    #[test]
+    fn test_passphrase_uses_word_list() {
+        let settings = GeneratorSettings {
+            words: 8,
+            separator: String::from(" "),
+            ..Default::default()
+        };
+        let passphrase = generate_passphrase(&settings).unwrap();
+        let words: Vec<&str> = passphrase.split(' ').collect();
+        assert_eq!(words.len(), 8);
+        assert!(words.iter().all(|w| WORDS.contains(w)));
+    }
+
+    #[test]
+    fn test_word_list_is_complete() {
+        let mut words = WORDS.clone();
+        words.dedup();
+        assert_eq!(words.len(), 2048);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let (settings, passphrase) = parse_args(
+            &args(&["--length", "12", "--no-symbols"]),
+            GeneratorSettings::default(),
+        )
+        .unwrap();
+        assert!(!passphrase);
+        assert_eq!(settings.length, 12);
+        assert!(!settings.symbols);
+        assert!(settings.digits);
+    }
+
+    #[test]
+    fn test_parse_args_passphrase() {
+        let (settings, passphrase) =
+            parse_args(&args(&["--words", "4"]), GeneratorSettings::default()).unwrap();
+        assert!(passphrase);
+        assert_eq!(settings.words, 4);
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(parse_args(&args(&["--length"]), GeneratorSettings::default()).is_err());
+        assert!(parse_args(&args(&["--length", "x"]), GeneratorSettings::default()).is_err());
+        assert!(parse_args(&args(&["--bogus"]), GeneratorSettings::default()).is_err());
+    }
+}
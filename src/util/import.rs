@@ -0,0 +1,802 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use aes_gcm::Aes128Gcm;
+use roxmltree::Node;
+use serde::Deserialize;
+
+use crate::util::error::Error;
+use crate::util::export::Backup;
+use crate::util::json_utils::{
+    new_entry, read_passwords, write_new_passwords, EntryDetails, PasswordEntry,
+};
+use crate::util::secret::SecretString;
+use crate::util::totp::Totp;
+use crate::util::utils::{parse_tags, try_decrypt, EncryptionData};
+
+/// The exports that can be imported.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImportFormat {
+    KeePassXml,
+    Bitwarden,
+    OnePassword,
+    /// Chrome and Firefox both export saved logins as CSV.
+    BrowserCsv,
+    /// A `pass` password store directory, decrypted with `gpg`.
+    Pass,
+    /// A backup made by `psc export backup`.
+    Backup,
+}
+
+impl ImportFormat {
+    pub fn from_name(name: &str) -> Option<ImportFormat> {
+        match name {
+            "keepass" => Some(ImportFormat::KeePassXml),
+            "bitwarden" => Some(ImportFormat::Bitwarden),
+            "1password" => Some(ImportFormat::OnePassword),
+            "chrome" | "firefox" => Some(ImportFormat::BrowserCsv),
+            "pass" => Some(ImportFormat::Pass),
+            "backup" => Some(ImportFormat::Backup),
+            _ => None,
+        }
+    }
+}
+
+/// An entry with its password in the clear, as read from another password
+/// manager's export or written to one.
+#[derive(Debug)]
+pub struct PlainEntry {
+    pub(crate) service: String,
+    pub(crate) password: SecretString,
+    pub(crate) details: EntryDetails,
+}
+
+/// What an import did, or would do, to the vault.
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub added: Vec<String>,
+    /// Already in the vault and replaced, with `--overwrite`.
+    pub replaced: Vec<String>,
+    /// Already in the vault, or earlier in the export, exactly as imported.
+    pub duplicates: Vec<String>,
+    /// Already in the vault with a different password or login, so left alone.
+    pub conflicts: Vec<String>,
+    /// Nothing to import: no name or no password, e.g. secure notes.
+    pub skipped: Vec<String>,
+    /// Has a 2FA secret that isn't base32 or an `otpauth://` URI, so left out
+    /// rather than stored as a code that can never be shown.
+    pub invalid_totp: Vec<String>,
+}
+
+/// Reads every entry from an export at `path`. Only backups need a passphrase.
+pub fn read_entries(
+    format: ImportFormat,
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<Vec<PlainEntry>, Error> {
+    match format {
+        ImportFormat::KeePassXml => parse_keepass(&fs::read_to_string(path)?),
+        ImportFormat::Bitwarden => parse_bitwarden(&fs::read_to_string(path)?),
+        ImportFormat::OnePassword | ImportFormat::BrowserCsv => parse_csv(fs::File::open(path)?),
+        ImportFormat::Pass => read_pass_store(path),
+        ImportFormat::Backup => Backup::read(path)?.open(
+            passphrase
+                .ok_or_else(|| Error::Input(String::from("A backup needs its passphrase")))?,
+        ),
+    }
+}
+
+/// Imports `entries` into the vault in one write. With `dry_run` nothing is
+/// written, but the report still says what would have been.
+pub fn import(
+    entries: Vec<PlainEntry>,
+    overwrite: bool,
+    dry_run: bool,
+    key: &Aes128Gcm,
+) -> Result<ImportReport, Error> {
+    let existing = read_passwords(key)?;
+    let (to_write, report) = plan(entries, &existing, overwrite, key);
+
+    if !dry_run {
+        let encrypted = to_write
+            .into_iter()
+            .map(|e| Ok((e.service, new_entry(e.password.expose(), e.details, key)?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        write_new_passwords(encrypted, key)?;
+    }
+
+    Ok(report)
+}
+
+/// Works out which entries to write, checking them against the vault and
+/// against each other. Two logins for the same site are told apart by their
+/// usernames.
+fn plan(
+    entries: Vec<PlainEntry>,
+    existing: &HashMap<String, PasswordEntry>,
+    overwrite: bool,
+    key: &Aes128Gcm,
+) -> (Vec<PlainEntry>, ImportReport) {
+    let mut report = ImportReport::default();
+    let mut to_write = Vec::new();
+    // Every login already taken from the export, by the name it was given,
+    // whether or not it's being written.
+    let mut seen: HashMap<String, (String, SecretString)> = HashMap::new();
+
+    for mut entry in entries {
+        if entry.service.is_empty() || entry.password.is_empty() {
+            report.skipped.push(entry.service);
+            continue;
+        }
+        if !entry.details.totp.is_empty() && Totp::parse(entry.details.totp.expose()).is_err() {
+            report.invalid_totp.push(entry.service);
+            continue;
+        }
+
+        if let Some((username, password)) = seen.get(&entry.service) {
+            if *username == entry.details.username && *password == entry.password {
+                report.duplicates.push(entry.service);
+                continue;
+            }
+            let renamed = format!("{} ({})", entry.service, entry.details.username);
+            if entry.details.username.is_empty() || seen.contains_key(&renamed) {
+                report.conflicts.push(entry.service);
+                continue;
+            }
+            entry.service = renamed;
+        }
+        seen.insert(
+            entry.service.clone(),
+            (entry.details.username.clone(), entry.password.clone()),
+        );
+
+        match existing.get(&entry.service) {
+            None => report.added.push(entry.service.clone()),
+            Some(stored) if matches_stored(stored, &entry, key) => {
+                report.duplicates.push(entry.service);
+                continue;
+            }
+            Some(_) if overwrite => report.replaced.push(entry.service.clone()),
+            Some(_) => {
+                report.conflicts.push(entry.service);
+                continue;
+            }
+        }
+        to_write.push(entry);
+    }
+
+    (to_write, report)
+}
+
+fn matches_stored(stored: &PasswordEntry, entry: &PlainEntry, key: &Aes128Gcm) -> bool {
+    let password = try_decrypt(EncryptionData {
+        password: &stored.password,
+        nonce: &stored.nonce,
+        key,
+    })
+    .map(SecretString::from);
+
+    stored.username == entry.details.username
+        && password.is_ok_and(|password| password == entry.password)
+}
+
+/// Reads a KeePass 2 XML export. Each entry is tagged with the group it's in,
+/// and anything in the recycle bin is left out.
+fn parse_keepass(xml: &str) -> Result<Vec<PlainEntry>, Error> {
+    let document = roxmltree::Document::parse(xml)?;
+    let root = document.root_element();
+    if !root.has_tag_name("KeePassFile") {
+        return Err(Error::Import(String::from("Not a KeePass XML export")));
+    }
+
+    let recycle_bin = child(root, "Meta").and_then(|meta| child_text(meta, "RecycleBinUUID"));
+    let mut entries = Vec::new();
+    if let Some(group) = child(root, "Root").and_then(|r| child(r, "Group")) {
+        read_keepass_group(group, None, recycle_bin, &mut entries);
+    }
+
+    Ok(entries)
+}
+
+fn read_keepass_group(
+    group: Node,
+    name: Option<&str>,
+    recycle_bin: Option<&str>,
+    entries: &mut Vec<PlainEntry>,
+) {
+    for node in group.children().filter(Node::is_element) {
+        match node.tag_name().name() {
+            "Entry" => entries.push(keepass_entry(node, name)),
+            "Group" if child_text(node, "UUID") != recycle_bin || recycle_bin.is_none() => {
+                read_keepass_group(node, child_text(node, "Name"), recycle_bin, entries)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn keepass_entry(entry: Node, group: Option<&str>) -> PlainEntry {
+    let fields: HashMap<&str, &str> = entry
+        .children()
+        .filter(|n| n.has_tag_name("String"))
+        .filter_map(|n| Some((child_text(n, "Key")?, child_text(n, "Value").unwrap_or(""))))
+        .collect();
+    let field = |name: &str| fields.get(name).copied().unwrap_or("").to_string();
+
+    let mut tags = parse_tags(&child_text(entry, "Tags").unwrap_or("").replace(';', ","));
+    tags.extend(group.map(String::from));
+
+    PlainEntry {
+        service: field("Title"),
+        password: SecretString::from(field("Password")),
+        details: EntryDetails {
+            username: field("UserName"),
+            url: field("URL"),
+            notes: field("Notes").into(),
+            tags,
+            // Where KeePassXC keeps its 2FA secrets.
+            totp: field("otp").into(),
+        },
+    }
+}
+
+#[inline]
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+#[inline]
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name).and_then(|n| n.text())
+}
+
+#[derive(Deserialize)]
+struct BitwardenExport {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    folders: Vec<BitwardenFolder>,
+    #[serde(default)]
+    items: Vec<BitwardenItem>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenFolder {
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct BitwardenItem {
+    name: String,
+    notes: Option<String>,
+    #[serde(rename = "folderId")]
+    folder_id: Option<String>,
+    login: Option<BitwardenLogin>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenLogin {
+    username: Option<String>,
+    password: Option<String>,
+    uris: Option<Vec<BitwardenUri>>,
+    totp: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BitwardenUri {
+    uri: Option<String>,
+}
+
+/// Reads an unencrypted Bitwarden JSON export. Folders become tags; items that
+/// aren't logins have no password and are skipped.
+pub(crate) fn parse_bitwarden(json: &str) -> Result<Vec<PlainEntry>, Error> {
+    let export: BitwardenExport = serde_json::from_str(json)?;
+    if export.encrypted {
+        return Err(Error::Import(String::from(
+            "Encrypted Bitwarden exports can't be imported, export as unencrypted JSON",
+        )));
+    }
+
+    let folders: HashMap<String, String> = export
+        .folders
+        .into_iter()
+        .map(|folder| (folder.id, folder.name))
+        .collect();
+
+    Ok(export
+        .items
+        .into_iter()
+        .map(|item| {
+            let login = item.login.unwrap_or(BitwardenLogin {
+                username: None,
+                password: None,
+                uris: None,
+                totp: None,
+            });
+            let url = login
+                .uris
+                .unwrap_or_default()
+                .into_iter()
+                .find_map(|uri| uri.uri)
+                .unwrap_or_default();
+
+            PlainEntry {
+                service: item.name,
+                password: SecretString::from(login.password.unwrap_or_default()),
+                details: EntryDetails {
+                    username: login.username.unwrap_or_default(),
+                    url,
+                    notes: item.notes.unwrap_or_default().into(),
+                    tags: item
+                        .folder_id
+                        .and_then(|id| folders.get(&id).cloned())
+                        .into_iter()
+                        .collect(),
+                    totp: login.totp.unwrap_or_default().into(),
+                },
+            }
+        })
+        .collect())
+}
+
+/// Reads a CSV export from 1Password, Chrome or Firefox. The columns are found
+/// by their headers, which differ between them and between versions. Entries
+/// without a name, as in Firefox's, are named after the site.
+pub(crate) fn parse_csv<R: Read>(reader: R) -> Result<Vec<PlainEntry>, Error> {
+    let mut csv = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let headers: Vec<String> = csv
+        .headers()?
+        .iter()
+        .map(|h| h.trim().to_lowercase())
+        .collect();
+    let column = |names: &[&str]| headers.iter().position(|h| names.contains(&h.as_str()));
+
+    let title = column(&["title", "name"]);
+    let url = column(&["url", "website", "login_uri"]);
+    let username = column(&["username", "login_username"]);
+    let password = column(&["password", "login_password"])
+        .ok_or_else(|| Error::Import(String::from("The CSV has no password column")))?;
+    let notes = column(&["notes", "note", "notesplain"]);
+    let tags = column(&["tags"]);
+    let totp = column(&["otpauth", "totp", "otp"]);
+
+    let mut entries = Vec::new();
+    for record in csv.records() {
+        let record = record?;
+        let field = |i: Option<usize>| {
+            i.and_then(|i| record.get(i))
+                .unwrap_or("")
+                .trim()
+                .to_string()
+        };
+        let url = field(url);
+        let service = match field(title) {
+            title if title.is_empty() => host(&url).to_string(),
+            title => title,
+        };
+
+        entries.push(PlainEntry {
+            service,
+            password: SecretString::from(field(Some(password))),
+            details: EntryDetails {
+                username: field(username),
+                url,
+                notes: field(notes).into(),
+                tags: parse_tags(&field(tags).replace(';', ",")),
+                totp: field(totp).into(),
+            },
+        });
+    }
+
+    Ok(entries)
+}
+
+/// The host part of a URL, or the whole thing if it doesn't look like one.
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = rest.find(['/', ':', '?', '#']).unwrap_or(rest.len());
+    &rest[..end]
+}
+
+/// Reads a `pass` password store. Each `.gpg` file is an entry named after its
+/// path in the store, decrypted by `gpg`, which asks for the passphrase itself.
+fn read_pass_store(dir: &Path) -> Result<Vec<PlainEntry>, Error> {
+    let mut files = Vec::new();
+    find_gpg_files(dir, &mut files)?;
+    files.sort();
+
+    files
+        .iter()
+        .map(|file| {
+            let service = file
+                .strip_prefix(dir)
+                .unwrap_or(file)
+                .with_extension("")
+                .to_string_lossy()
+                .into_owned();
+            Ok(parse_pass_entry(service, decrypt_gpg(file)?.expose()))
+        })
+        .collect()
+}
+
+fn find_gpg_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            find_gpg_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "gpg") {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn decrypt_gpg(path: &Path) -> Result<SecretString, Error> {
+    let output = Command::new("gpg")
+        .args(["--quiet", "--batch", "--decrypt"])
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::Import(format!("Couldn't run gpg: {}", e)))?;
+    if !output.status.success() {
+        return Err(Error::Import(format!(
+            "gpg couldn't decrypt {}",
+            path.display()
+        )));
+    }
+
+    Ok(SecretString::from(String::from_utf8(output.stdout)?))
+}
+
+/// Splits a decrypted `pass` entry: the password on the first line, then
+/// `login:` and `url:` lines and a `pass-otp` URI, with anything else kept as
+/// notes.
+fn parse_pass_entry(service: String, contents: &str) -> PlainEntry {
+    let mut lines = contents.lines();
+    let password = SecretString::from(lines.next().unwrap_or(""));
+    let mut details = EntryDetails::default();
+    let mut notes = Vec::new();
+
+    for line in lines {
+        if line.trim().starts_with("otpauth://") {
+            details.totp = SecretString::from(line.trim());
+            continue;
+        }
+        let field = line
+            .split_once(':')
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim()));
+        match field {
+            Some((name, value)) if ["login", "username", "user"].contains(&name.as_str()) => {
+                details.username = value.to_string()
+            }
+            Some((name, value)) if ["url", "website"].contains(&name.as_str()) => {
+                details.url = value.to_string()
+            }
+            _ => notes.push(line),
+        }
+    }
+    details.notes = SecretString::from(notes.join("\n").trim());
+
+    PlainEntry {
+        service,
+        password,
+        details,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::utils::encrypt;
+    use aes_gcm::{aead::generic_array::GenericArray, NewAead};
+    use base64::encode;
+
+    fn entry(service: &str, username: &str, password: &str) -> PlainEntry {
+        PlainEntry {
+            service: service.to_string(),
+            password: SecretString::from(password),
+            details: EntryDetails {
+                username: username.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_format_names() {
+        assert_eq!(
+            ImportFormat::from_name("firefox"),
+            Some(ImportFormat::BrowserCsv)
+        );
+        assert_eq!(
+            ImportFormat::from_name("1password"),
+            Some(ImportFormat::OnePassword)
+        );
+        assert_eq!(ImportFormat::from_name("lastpass"), None);
+    }
+
+    #[test]
+    fn test_parse_keepass() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<KeePassFile>
+  <Meta><RecycleBinUUID>Ymlu</RecycleBinUUID></Meta>
+  <Root>
+    <Group>
+      <UUID>cm9vdA==</UUID>
+      <Name>Database</Name>
+      <Entry>
+        <String><Key>Title</Key><Value>github</Value></String>
+        <String><Key>UserName</Key><Value>ernie</Value></String>
+        <String><Key>Password</Key><Value Protected="True">hunter2</Value></String>
+        <String><Key>URL</Key><Value>https://github.com</Value></String>
+        <String><Key>Notes</Key><Value>2fa on</Value></String>
+        <String><Key>otp</Key><Value>otpauth://totp/github?secret=JBSWY3DPEHPK3PXP</Value></String>
+        <Tags>code;work</Tags>
+        <History>
+          <Entry>
+            <String><Key>Title</Key><Value>github</Value></String>
+            <String><Key>Password</Key><Value>old</Value></String>
+          </Entry>
+        </History>
+      </Entry>
+      <Group>
+        <UUID>YmFuaw==</UUID>
+        <Name>Banking</Name>
+        <Entry>
+          <String><Key>Title</Key><Value>bank</Value></String>
+          <String><Key>Password</Key><Value>money</Value></String>
+        </Entry>
+      </Group>
+      <Group>
+        <UUID>Ymlu</UUID>
+        <Name>Recycle Bin</Name>
+        <Entry>
+          <String><Key>Title</Key><Value>deleted</Value></String>
+          <String><Key>Password</Key><Value>gone</Value></String>
+        </Entry>
+      </Group>
+    </Group>
+  </Root>
+</KeePassFile>"#;
+        let entries = parse_keepass(xml).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].service, "github");
+        assert_eq!(entries[0].password, "hunter2");
+        assert_eq!(
+            entries[0].details,
+            EntryDetails {
+                username: String::from("ernie"),
+                url: String::from("https://github.com"),
+                notes: SecretString::from("2fa on"),
+                tags: vec![String::from("code"), String::from("work")],
+                totp: SecretString::from("otpauth://totp/github?secret=JBSWY3DPEHPK3PXP"),
+            }
+        );
+        assert_eq!(entries[1].service, "bank");
+        assert_eq!(entries[1].details.tags, vec![String::from("Banking")]);
+    }
+
+    #[test]
+    fn test_parse_keepass_rejects_other_xml() {
+        assert!(parse_keepass("<html></html>").is_err());
+        assert!(parse_keepass("not xml").is_err());
+    }
+
+    #[test]
+    fn test_parse_bitwarden() {
+        let json = r#"{
+            "encrypted": false,
+            "folders": [{"id": "f1", "name": "Work"}],
+            "items": [
+                {
+                    "type": 1,
+                    "name": "github",
+                    "notes": null,
+                    "folderId": "f1",
+                    "login": {
+                        "username": "ernie",
+                        "password": "hunter2",
+                        "uris": [{"match": null, "uri": "https://github.com"}],
+                        "totp": "JBSWY3DPEHPK3PXP"
+                    }
+                },
+                {"type": 2, "name": "wifi", "notes": "pin 1234", "folderId": null, "secureNote": {"type": 0}}
+            ]
+        }"#;
+        let entries = parse_bitwarden(json).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].password, "hunter2");
+        assert_eq!(entries[0].details.url, "https://github.com");
+        assert_eq!(entries[0].details.tags, vec![String::from("Work")]);
+        assert_eq!(entries[0].details.totp, "JBSWY3DPEHPK3PXP");
+        assert!(entries[1].password.is_empty());
+    }
+
+    #[test]
+    fn test_parse_encrypted_bitwarden_is_err() {
+        assert!(parse_bitwarden(r#"{"encrypted": true, "items": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_1password_csv() {
+        let csv = "Title,Url,Username,Password,OTPAuth,Favorite,Archived,Tags,Notes\n\
+                   github,https://github.com,ernie,\"hun,ter2\",otpauth://totp/gh?secret=JBSWY3DPEHPK3PXP,false,false,code;work,\"two\nlines\"\n";
+        let entries = parse_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].service, "github");
+        assert_eq!(entries[0].password, "hun,ter2");
+        assert_eq!(entries[0].details.notes, "two\nlines");
+        assert_eq!(
+            entries[0].details.totp,
+            "otpauth://totp/gh?secret=JBSWY3DPEHPK3PXP"
+        );
+        assert_eq!(
+            entries[0].details.tags,
+            vec![String::from("code"), String::from("work")]
+        );
+    }
+
+    #[test]
+    fn test_parse_browser_csv() {
+        let chrome = "name,url,username,password\nexample.com,https://example.com/login,ernie,pw\n";
+        let firefox =
+            "\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\"\n\
+                       \"https://accounts.example.org:443\",\"bert\",\"pw2\",,\"\",\"{1}\"\n";
+
+        let chrome = parse_csv(chrome.as_bytes()).unwrap();
+        assert_eq!(chrome[0].service, "example.com");
+        assert_eq!(chrome[0].details.url, "https://example.com/login");
+
+        let firefox = parse_csv(firefox.as_bytes()).unwrap();
+        assert_eq!(firefox[0].service, "accounts.example.org");
+        assert_eq!(firefox[0].details.username, "bert");
+        assert_eq!(firefox[0].password, "pw2");
+    }
+
+    #[test]
+    fn test_csv_without_password_column_is_err() {
+        assert!(parse_csv("name,url\na,b\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_parse_pass_entry() {
+        let entry = parse_pass_entry(
+            String::from("email/gmail"),
+            "hunter2\nlogin: ernie\nurl: https://mail.google.com\nrecovery: 1234\notpauth://totp/gmail?secret=JBSWY3DPEHPK3PXP\n",
+        );
+
+        assert_eq!(entry.service, "email/gmail");
+        assert_eq!(entry.password, "hunter2");
+        assert_eq!(entry.details.username, "ernie");
+        assert_eq!(entry.details.url, "https://mail.google.com");
+        assert_eq!(entry.details.notes, "recovery: 1234");
+        assert_eq!(
+            entry.details.totp,
+            "otpauth://totp/gmail?secret=JBSWY3DPEHPK3PXP"
+        );
+    }
+
+    #[test]
+    fn test_find_gpg_files_skips_hidden() {
+        let dir = std::env::temp_dir().join(format!("passcurses-pass-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("email")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join("email/gmail.gpg"), b"").unwrap();
+        fs::write(dir.join(".git/x.gpg"), b"").unwrap();
+        fs::write(dir.join(".gpg-id"), b"").unwrap();
+
+        let mut files = Vec::new();
+        find_gpg_files(&dir, &mut files).unwrap();
+        assert_eq!(files, vec![dir.join("email/gmail.gpg")]);
+    }
+
+    #[test]
+    fn test_plan_reports_duplicates_and_conflicts() {
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        let (same, same_nonce) = encrypt("same", &key).unwrap();
+        let (other, other_nonce) = encrypt("other", &key).unwrap();
+        let mut existing = HashMap::new();
+        existing.insert(
+            String::from("unchanged"),
+            PasswordEntry {
+                username: String::from("ernie"),
+                ..PasswordEntry::new(encode(same), same_nonce)
+            },
+        );
+        existing.insert(
+            String::from("changed"),
+            PasswordEntry::new(encode(other), other_nonce),
+        );
+
+        let entries = vec![
+            entry("new", "", "pw"),
+            entry("unchanged", "ernie", "same"),
+            entry("changed", "", "new"),
+            entry("site", "a", "pw1"),
+            entry("site", "a", "pw1"),
+            entry("site", "b", "pw2"),
+            entry("note", "", ""),
+        ];
+        let (to_write, report) = plan(entries, &existing, false, &key);
+
+        assert_eq!(
+            report,
+            ImportReport {
+                added: vec![
+                    String::from("new"),
+                    String::from("site"),
+                    String::from("site (b)")
+                ],
+                replaced: vec![],
+                duplicates: vec![String::from("unchanged"), String::from("site")],
+                conflicts: vec![String::from("changed")],
+                skipped: vec![String::from("note")],
+                invalid_totp: vec![],
+            }
+        );
+        assert_eq!(to_write.len(), 3);
+    }
+
+    #[test]
+    fn test_plan_renames_second_login_when_first_is_stored() {
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        let (same, same_nonce) = encrypt("pw1", &key).unwrap();
+        let mut existing = HashMap::new();
+        existing.insert(
+            String::from("site"),
+            PasswordEntry {
+                username: String::from("a"),
+                ..PasswordEntry::new(encode(same), same_nonce)
+            },
+        );
+
+        let entries = vec![entry("site", "a", "pw1"), entry("site", "b", "pw2")];
+        let (_, report) = plan(entries, &existing, false, &key);
+        assert_eq!(report.duplicates, vec![String::from("site")]);
+        assert_eq!(report.added, vec![String::from("site (b)")]);
+    }
+
+    #[test]
+    fn test_plan_leaves_out_invalid_totp() {
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        let mut valid = entry("github", "", "pw");
+        valid.details.totp = SecretString::from("otpauth://totp/gh?secret=JBSWY3DPEHPK3PXP");
+        let mut invalid = entry("gitlab", "", "pw");
+        invalid.details.totp = SecretString::from("not base32!");
+
+        let (to_write, report) = plan(vec![valid, invalid], &HashMap::new(), false, &key);
+        assert_eq!(report.added, vec![String::from("github")]);
+        assert_eq!(report.invalid_totp, vec![String::from("gitlab")]);
+        assert_eq!(to_write.len(), 1);
+    }
+
+    #[test]
+    fn test_plan_overwrites_conflicts() {
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        let (other, other_nonce) = encrypt("other", &key).unwrap();
+        let mut existing = HashMap::new();
+        existing.insert(
+            String::from("changed"),
+            PasswordEntry::new(encode(other), other_nonce),
+        );
+
+        let (to_write, report) = plan(vec![entry("changed", "", "new")], &existing, true, &key);
+        assert_eq!(report.replaced, vec![String::from("changed")]);
+        assert_eq!(to_write[0].password, "new");
+    }
+}
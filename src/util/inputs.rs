@@ -1,89 +1,191 @@
+use crate::util::error::Error;
+use crate::util::keymap::{Action, Keymap};
+use crate::util::lock::LockScreen;
 use crate::util::stateful_table::{CurrentMode, StatefulPasswordTable};
 use std::io::Write;
 use termion::event::Key;
 
-use std::{error::Error, io};
+use std::io;
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum LeapDirection {
     TOP,
     MIDDLE,
     BOTTOM,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum MoveDirection {
     DOWN,
     UP,
 }
 
-pub fn password_table_input_handler(table: &mut StatefulPasswordTable, key: Key) {
+pub fn password_table_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    let action = match keymap.action(key) {
+        Some(action) => action,
+        None => return,
+    };
+    match action {
+        Action::MoveDown => table.select(MoveDirection::DOWN),
+        Action::MoveUp => table.select(MoveDirection::UP),
+        Action::MoveDown5 => table.move_by_5(MoveDirection::DOWN),
+        Action::MoveUp5 => table.move_by_5(MoveDirection::UP),
+        Action::JumpTop => table.leap(LeapDirection::TOP),
+        Action::JumpBottom => table.leap(LeapDirection::BOTTOM),
+        Action::JumpMiddle => table.leap(LeapDirection::MIDDLE),
+        Action::Search => table.start_search(),
+        Action::NextMatch => table.cycle_match(MoveDirection::DOWN),
+        Action::PreviousMatch => table.cycle_match(MoveDirection::UP),
+        Action::ClearSearch => table.clear_search(),
+        Action::CopyPassword => table.copy(),
+        Action::RevealPassword => table.decrypt(),
+        Action::ToggleCode => table.toggle_code(),
+        Action::CopyCode => table.copy_code(),
+        Action::Refresh => table.refresh_table(),
+        Action::NewPassword => table.current_mode = CurrentMode::NewUserName,
+        Action::EditPassword => table.start_edit(),
+        Action::History => table.open_history(),
+        Action::DeletePassword => table.current_mode = CurrentMode::DeletePassword,
+        Action::Audit => table.open_audit(),
+        Action::Rekey => table.current_mode = CurrentMode::RekeyOldKey,
+        Action::SwitchVault => table.current_mode = CurrentMode::SwitchVault,
+        // Only the popups have anything to pick or sort.
+        Action::Select | Action::SortAudit => {}
+        // Nor do the prompts' keys do anything here.
+        Action::Generate | Action::GeneratePassphrase | Action::Cancel => {}
+        Action::Help => table.current_mode = CurrentMode::WithHelp,
+        Action::Quit => table.current_mode = CurrentMode::Exit,
+    }
+}
+
+pub fn search_input_handler(table: &mut StatefulPasswordTable, key: Key) {
     match key {
-        Key::Char('c') => {
-            table.current_mode = CurrentMode::NewUserName;
-        }
-        Key::Char('j') | Key::Down => {
-            table.select(MoveDirection::DOWN);
+        Key::Esc => {
+            table.clear_search();
         }
-        Key::Char('k') | Key::Up => {
-            table.select(MoveDirection::UP);
+        Key::Char('\n') => {
+            table.current_mode = CurrentMode::Normal;
         }
-        Key::Char('d') => {
-            table.decrypt();
+        Key::Char(c) => {
+            table.search_query.push(c);
+            table.update_search();
         }
-        Key::Char('y') => {
-            table.copy();
+        Key::Backspace => {
+            table.search_query.pop();
+            table.update_search();
         }
-        Key::Char('D') => {
-            table.current_mode = CurrentMode::DeletePassword;
+        Key::Down | Key::Ctrl('n') => {
+            table.cycle_match(MoveDirection::DOWN);
         }
-        Key::Char('q') => {
-            table.current_mode = CurrentMode::Exit;
+        Key::Up | Key::Ctrl('p') => {
+            table.cycle_match(MoveDirection::UP);
         }
-        Key::Ctrl('d') => {
-            table.move_by_5(MoveDirection::DOWN);
+        _ => {}
+    }
+}
+
+pub fn with_help_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    match keymap.action(key) {
+        Some(Action::Help) => {
+            table.current_mode = CurrentMode::Normal;
         }
-        Key::Ctrl('u') => {
-            table.move_by_5(MoveDirection::UP);
+        Some(Action::Quit) => {
+            table.current_mode = CurrentMode::Exit;
         }
-        Key::Char('g') => {
-            table.leap(LeapDirection::TOP);
+        _ => {}
+    }
+}
+
+pub fn lock_screen_input_handler(
+    screen: &mut LockScreen,
+    key: Key,
+    login_attempts: u8,
+) -> Result<(), Error> {
+    match key {
+        Key::Esc => screen.exit = true,
+        Key::Char('\n') => screen.unlock(login_attempts)?,
+        Key::Char(c) => screen.input.push(c),
+        Key::Backspace => {
+            screen.input.pop();
         }
-        Key::Char('M') => {
-            table.leap(LeapDirection::MIDDLE);
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Moves with the keymap's bindings, the rest of the keys are as shown in the
+/// popup's title.
+pub fn history_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    if keymap.closes_popup(key) {
+        table.close_history();
+        return;
+    }
+    match keymap.action(key) {
+        Some(Action::MoveDown) => {
+            table.select_previous(MoveDirection::DOWN);
         }
-        Key::Char('G') => {
-            table.leap(LeapDirection::BOTTOM);
+        Some(Action::MoveUp) => {
+            table.select_previous(MoveDirection::UP);
         }
-        Key::Char('?') => {
-            table.current_mode = CurrentMode::WithHelp;
+        Some(Action::RevealPassword) => {
+            table.toggle_previous();
         }
-        Key::Char('r') => {
-            table.refresh_table();
+        Some(Action::Select) => {
+            table.restore_previous();
         }
         _ => {}
     }
 }
 
-pub fn with_help_input_handler(table: &mut StatefulPasswordTable, key: Key) {
-    match key {
-        Key::Char('?') => {
-            table.current_mode = CurrentMode::Normal;
+/// Like `history_input_handler`, and the key that opened the audit closes it.
+pub fn audit_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    if keymap.closes_popup(key) {
+        table.close_audit();
+        return;
+    }
+    match keymap.action(key) {
+        Some(Action::MoveDown) => {
+            table.select_audited(MoveDirection::DOWN);
         }
-        Key::Char('q') => {
-            table.current_mode = CurrentMode::Exit;
+        Some(Action::MoveUp) => {
+            table.select_audited(MoveDirection::UP);
+        }
+        Some(Action::Audit) => {
+            table.close_audit();
+        }
+        Some(Action::SortAudit) => {
+            table.cycle_audit_sort();
+        }
+        Some(Action::Select) => {
+            table.goto_audited();
         }
         _ => {}
     }
 }
 
+/// Closes a popup that only reports something, on any key.
+pub fn notice_input_handler(table: &mut StatefulPasswordTable, _key: Key) {
+    table.current_mode = CurrentMode::Normal;
+}
+
+pub fn error_input_handler(table: &mut StatefulPasswordTable, _key: Key) {
+    table.dismiss_error();
+}
+
+/// The prompts type in whatever isn't bound to one of the keymap's prompt
+/// actions.
 pub fn add_password_input_handler(
     table: &mut StatefulPasswordTable,
     key: Key,
-) -> Result<(), Box<dyn Error>> {
+    keymap: &Keymap,
+) -> Result<(), Error> {
     io::stdout().flush().ok();
+    let action = keymap.prompt_action(key);
 
     match table.current_mode {
         CurrentMode::NewUserName => match key {
-            Key::Esc => {
+            _ if action == Some(Action::Cancel) => {
                 table.current_mode = CurrentMode::Normal;
                 table.clear_inputs();
             }
@@ -102,13 +204,19 @@ pub fn add_password_input_handler(
             _ => {}
         },
         CurrentMode::NewPassword => match key {
-            Key::Esc => {
+            _ if action == Some(Action::Cancel) => {
                 table.current_mode = CurrentMode::Normal;
                 table.clear_inputs();
             }
             Key::Char('\n') => {
                 table.new_password();
             }
+            _ if action == Some(Action::Generate) => {
+                table.generate_input(false);
+            }
+            _ if action == Some(Action::GeneratePassphrase) => {
+                table.generate_input(true);
+            }
             Key::Char(c) => {
                 table.input.push(c);
             }
@@ -117,6 +225,30 @@ pub fn add_password_input_handler(
             }
             _ => {}
         },
+        CurrentMode::NewLogin
+        | CurrentMode::NewUrl
+        | CurrentMode::NewTags
+        | CurrentMode::NewNotes
+        | CurrentMode::NewTotp
+        | CurrentMode::InvalidTotp => match key {
+            _ if action == Some(Action::Cancel) => {
+                table.current_mode = CurrentMode::Normal;
+                table.clear_inputs();
+            }
+            Key::Char('\n') => {
+                table.new_detail();
+            }
+            Key::Char(c) => {
+                table.input.push(c);
+            }
+            Key::Ctrl('w') => {
+                table.pop_one_word();
+            }
+            Key::Backspace => {
+                table.input.pop();
+            }
+            _ => {}
+        },
         CurrentMode::PasswordCreated | CurrentMode::PasswordExists => {
             table.current_mode = CurrentMode::Normal;
         }
@@ -125,10 +257,11 @@ pub fn add_password_input_handler(
     Ok(())
 }
 
-pub fn delete_password_input_handler(table: &mut StatefulPasswordTable, key: Key) {
+pub fn delete_password_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    let action = keymap.prompt_action(key);
     match table.current_mode {
         CurrentMode::DeletePassword => match key {
-            Key::Esc => {
+            _ if action == Some(Action::Cancel) => {
                 table.current_mode = CurrentMode::Normal;
                 table.clear_inputs();
             }
@@ -149,3 +282,115 @@ pub fn delete_password_input_handler(table: &mut StatefulPasswordTable, key: Key
         _ => {}
     }
 }
+
+pub fn switch_vault_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    let action = keymap.prompt_action(key);
+    match table.current_mode {
+        CurrentMode::SwitchVault => match key {
+            _ if action == Some(Action::Cancel) => {
+                table.current_mode = CurrentMode::Normal;
+                table.clear_inputs();
+            }
+            Key::Char('\n') => {
+                table.switch_vault();
+            }
+            Key::Char('\t') => {
+                table.next_vault_name();
+            }
+            Key::Char(c) => {
+                table.input.push(c);
+            }
+            Key::Backspace => {
+                table.input.pop();
+            }
+            _ => {}
+        },
+        CurrentMode::NoSuchVault => {
+            table.current_mode = CurrentMode::Normal;
+        }
+        _ => {}
+    }
+}
+
+pub fn edit_password_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    let action = keymap.prompt_action(key);
+    match table.current_mode {
+        CurrentMode::EditUserName | CurrentMode::EditPassword => match key {
+            _ if action == Some(Action::Cancel) => {
+                table.current_mode = CurrentMode::Normal;
+                table.clear_inputs();
+            }
+            Key::Char('\n') => {
+                if table.current_mode == CurrentMode::EditUserName {
+                    table.edit_username();
+                } else {
+                    table.edit_password();
+                }
+            }
+            _ if action == Some(Action::Generate)
+                && table.current_mode == CurrentMode::EditPassword =>
+            {
+                table.generate_input(false);
+            }
+            _ if action == Some(Action::GeneratePassphrase)
+                && table.current_mode == CurrentMode::EditPassword =>
+            {
+                table.generate_input(true);
+            }
+            Key::Char(c) => {
+                table.input.push(c);
+            }
+            Key::Ctrl('w') => {
+                table.pop_one_word();
+            }
+            Key::Backspace => {
+                table.input.pop();
+            }
+            _ => {}
+        },
+        CurrentMode::ConfirmEdit => match key {
+            Key::Char('y') => {
+                table.confirm_edit();
+            }
+            _ if key == Key::Char('n') || action == Some(Action::Cancel) => {
+                table.current_mode = CurrentMode::Normal;
+                table.clear_inputs();
+            }
+            _ => {}
+        },
+        CurrentMode::PasswordEdited => {
+            table.current_mode = CurrentMode::Normal;
+        }
+        _ => {}
+    }
+}
+
+pub fn rekey_input_handler(table: &mut StatefulPasswordTable, key: Key, keymap: &Keymap) {
+    let action = keymap.prompt_action(key);
+    match table.current_mode {
+        CurrentMode::RekeyOldKey | CurrentMode::RekeyNewKey | CurrentMode::RekeyConfirmKey => {
+            match key {
+                _ if action == Some(Action::Cancel) => {
+                    table.current_mode = CurrentMode::Normal;
+                    table.clear_inputs();
+                }
+                Key::Char('\n') => match table.current_mode {
+                    CurrentMode::RekeyOldKey => table.check_current_key(),
+                    CurrentMode::RekeyNewKey => table.new_key(),
+                    _ => table.confirm_new_key(),
+                },
+                Key::Char(c) => {
+                    table.input.push(c);
+                }
+                Key::Backspace => {
+                    table.input.pop();
+                }
+                _ => {}
+            }
+        }
+        CurrentMode::Rekeyed | CurrentMode::WrongKey | CurrentMode::KeyMismatch => {
+            table.current_mode = CurrentMode::Normal;
+        }
+        _ => {}
+    }
+}
@@ -1,19 +1,29 @@
-use crate::util::configs::{CursesConfigs, RawConfigs};
+use crate::util::clipboard::ClipboardSettings;
+use crate::util::configs::{
+    default_clipboard_timeout, default_history_size, default_lock_timeout,
+    default_max_password_age, CursesConfigs, RawConfigs, CONFIG_VERSION,
+};
+use crate::util::error::Error;
+use crate::util::generator::GeneratorSettings;
+use crate::util::keymap::KeymapSettings;
+use crate::util::paths;
+use crate::util::secret::SecretString;
 use crate::util::stateful_table::EntryState;
-use crate::util::utils::encrypt;
+use crate::util::utils::{
+    encrypt, encrypt_optional, keygen, legacy_keygen, rekey_entries, rekey_entry, unix_now,
+};
+use crate::util::vault::{VaultContainer, VAULT_VERSION};
 use aes_gcm::Aes128Gcm;
 use argon2::Config;
 use base64::encode;
-use dirs::home_dir;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
-use std::error::Error;
 use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Write};
-use std::path::Path;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Copy)]
 pub enum FileType {
@@ -32,119 +42,872 @@ impl std::fmt::Display for FileType {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+pub const PASSRC_VERSION: u32 = 1;
+
+/// A single schema upgrade, taking a file's JSON from one version to the next.
+type Migration = fn(serde_json::Value) -> Result<serde_json::Value, Error>;
+
+impl FileType {
+    /// The schema version this build reads and writes.
+    pub fn current_version(self) -> u32 {
+        match self {
+            FileType::Passwords => VAULT_VERSION,
+            FileType::Config => CONFIG_VERSION,
+            FileType::Passrc => PASSRC_VERSION,
+        }
+    }
+
+    /// Upgrade steps in order: the step at index `n` takes version `n` to `n + 1`,
+    /// so there is always one step per version below `current_version`. Files
+    /// written before versioning existed count as version 0.
+    ///
+    /// For the passwords file the steps see the decrypted map of entries rather
+    /// than the sealed container around it.
+    fn migrations(self) -> &'static [Migration] {
+        match self {
+            // v1 sealed the plain map in a container; the entries are unchanged.
+            // v2 added login, URL, notes, tags and timestamps to each entry.
+            // v3 added 2FA secrets.
+            // v4 added the history of previous passwords.
+            FileType::Passwords => &[Ok, add_entry_metadata, add_totp, add_history],
+            // v1 only added the version field itself.
+            // v2 added the password generator settings.
+            // v3 added the clipboard timeout.
+            // v4 added the clipboard backend settings.
+            // v5 added the idle lock timeout.
+            // v6 added the password history size.
+            // v7 added the audit's maximum password age.
+            // v8 added the breached passwords file.
+            // v9 added configurable key bindings.
+            FileType::Config => &[
+                Ok,
+                add_generator_settings,
+                add_clipboard_timeout,
+                add_clipboard_settings,
+                add_lock_timeout,
+                add_history_size,
+                add_max_password_age,
+                add_breach_file,
+                add_keymap,
+            ],
+            FileType::Passrc => &[Ok],
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PasswordEntry {
     pub(crate) password: String,
     pub(crate) nonce: String,
+    /// The login used on the service, as opposed to the service name the entry
+    /// is keyed by.
+    #[serde(default)]
+    pub(crate) username: String,
+    #[serde(default)]
+    pub(crate) url: String,
+    /// Encrypted like the password, under its own nonce. Empty when there are
+    /// no notes.
+    #[serde(default)]
+    pub(crate) notes: String,
+    #[serde(default)]
+    pub(crate) notes_nonce: String,
+    /// A 2FA secret, as an `otpauth://` URI or bare base32, encrypted like the
+    /// notes. Empty when there is none.
+    #[serde(default)]
+    pub(crate) totp: String,
+    #[serde(default)]
+    pub(crate) totp_nonce: String,
+    #[serde(default)]
+    pub(crate) tags: Vec<String>,
+    /// Passwords this entry had before, newest first.
+    #[serde(default)]
+    pub(crate) history: Vec<PreviousPassword>,
+    /// Unix timestamps in seconds, 0 when unknown.
+    #[serde(default)]
+    pub(crate) created: u64,
+    #[serde(default)]
+    pub(crate) modified: u64,
 }
 
 impl PasswordEntry {
-    fn new(new_password: String, new_nonce: String) -> Self {
+    pub(crate) fn new(new_password: String, new_nonce: String) -> Self {
         PasswordEntry {
             password: new_password,
             nonce: new_nonce,
+            ..Default::default()
+        }
+    }
+
+    /// Moves the current password into the history, ahead of a new one being
+    /// set, keeping only the newest `limit`.
+    pub(crate) fn retire_password(&mut self, limit: usize) {
+        self.history.insert(
+            0,
+            PreviousPassword {
+                password: std::mem::take(&mut self.password),
+                nonce: std::mem::take(&mut self.nonce),
+                replaced: unix_now(),
+            },
+        );
+        self.history.truncate(limit);
+    }
+
+    /// Carries the non-secret fields of `other` over to this entry.
+    pub(crate) fn with_metadata_of(self, other: PasswordEntry) -> Self {
+        PasswordEntry {
+            username: other.username,
+            url: other.url,
+            tags: other.tags,
+            created: other.created,
+            modified: other.modified,
+            ..self
         }
     }
 }
 
-#[inline]
-pub fn read_passwords() -> Result<HashMap<String, PasswordEntry>, Box<dyn Error>> {
-    let bufreader = read_json_file(FileType::Passwords)?;
-    let map: HashMap<String, PasswordEntry>;
-    match serde_json::from_reader(bufreader) {
-        Ok(s) => map = s,
-        Err(e) => panic!("Error serializing from reader: {}", e),
+/// A password an entry used to have, encrypted like the current one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PreviousPassword {
+    pub(crate) password: String,
+    pub(crate) nonce: String,
+    /// When it stopped being the entry's password, as a unix timestamp.
+    pub(crate) replaced: u64,
+}
+
+/// The optional, user supplied parts of a new entry. The notes and 2FA secret
+/// are only ever stored encrypted, so they're kept as secrets until then.
+#[derive(Debug, Default, PartialEq)]
+pub struct EntryDetails {
+    pub(crate) username: String,
+    pub(crate) url: String,
+    pub(crate) notes: SecretString,
+    pub(crate) tags: Vec<String>,
+    /// A 2FA secret, as an `otpauth://` URI or bare base32.
+    pub(crate) totp: SecretString,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Passrc {
+    pub(crate) key: String,
+    pub(crate) salt: Vec<u8>,
+    /// Whether the passwords are encrypted under the Argon2-derived key rather
+    /// than the zero-padded master password older versions used.
+    #[serde(default)]
+    pub(crate) key_derived: bool,
+    #[serde(default)]
+    pub(crate) version: u32,
+}
+
+impl Passrc {
+    /// Checks a typed master key against the Argon2 hash stored in `passrc.json`.
+    pub fn verify(&self, key: &str) -> bool {
+        argon2::verify_encoded(&self.key, key.as_bytes()).unwrap_or(false)
     }
+}
+
+/// Reads and opens the sealed passwords file, upgrading older versions in memory.
+#[inline]
+pub fn read_passwords(key: &Aes128Gcm) -> Result<HashMap<String, PasswordEntry>, Error> {
+    let (entries, version) = open_passwords(key)?;
+    let entries = migrate(FileType::Passwords, entries, version)?;
 
-    Ok(map)
+    Ok(serde_json::from_value(entries)?)
 }
 
 #[inline]
-pub fn read_config() -> Result<CursesConfigs, Box<dyn Error>> {
-    let bufreader = read_json_file(FileType::Config)?;
-    let raw_config: RawConfigs = serde_json::from_reader(bufreader)?;
-    let cfg = CursesConfigs::new(
-        raw_config.border_type,
-        raw_config.border_style,
-        raw_config.title_style,
-    );
+pub fn read_config() -> Result<CursesConfigs, Error> {
+    let raw_config: RawConfigs = serde_json::from_value(read_versioned(FileType::Config)?)?;
+    let cfg = CursesConfigs::new(raw_config)?;
 
     Ok(cfg)
 }
 
 #[inline]
-pub fn read_json_file(file: FileType) -> Result<BufReader<File>, Box<dyn Error>> {
-    let full_path = format!("{}/{}.json", get_home_dir(), file);
-    let file = OpenOptions::new().read(true).write(true).open(&full_path)?;
+pub fn read_passrc() -> Result<Passrc, Error> {
+    Ok(serde_json::from_value(read_versioned(FileType::Passrc)?)?)
+}
+
+/// Reads config or passrc as JSON, upgraded in memory to the current version.
+#[inline]
+fn read_versioned(file_type: FileType) -> Result<serde_json::Value, Error> {
+    let value: serde_json::Value = serde_json::from_reader(read_json_file(file_type)?)?;
+    let version = file_version(&value);
+    let mut value = migrate(file_type, value, version)?;
+    if let Some(object) = value.as_object_mut() {
+        object.insert(String::from("version"), json!(file_type.current_version()));
+    }
+
+    Ok(value)
+}
+
+/// Returns the decrypted entries along with the version they were written at.
+/// A plain map from before the sealed container is version 0.
+#[inline]
+fn open_passwords(key: &Aes128Gcm) -> Result<(serde_json::Value, u32), Error> {
+    let value: serde_json::Value = serde_json::from_reader(read_json_file(FileType::Passwords)?)?;
+
+    if VaultContainer::is_container(&value) {
+        let container: VaultContainer = serde_json::from_value(value)?;
+        Ok((container.open(key)?, container.version))
+    } else {
+        Ok((value, 0))
+    }
+}
+
+fn add_generator_settings(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("generator")
+        .or_insert(serde_json::to_value(GeneratorSettings::default())?);
+
+    Ok(config)
+}
+
+fn add_clipboard_timeout(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("clipboard_timeout")
+        .or_insert(json!(default_clipboard_timeout()));
+
+    Ok(config)
+}
+
+fn add_clipboard_settings(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("clipboard")
+        .or_insert(serde_json::to_value(ClipboardSettings::default())?);
+
+    Ok(config)
+}
+
+fn add_lock_timeout(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("lock_timeout")
+        .or_insert(json!(default_lock_timeout()));
+
+    Ok(config)
+}
+
+fn add_history_size(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("history_size")
+        .or_insert(json!(default_history_size()));
+
+    Ok(config)
+}
+
+fn add_max_password_age(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("max_password_age")
+        .or_insert(json!(default_max_password_age()));
+
+    Ok(config)
+}
+
+fn add_breach_file(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("breach_file")
+        .or_insert(json!(""));
+
+    Ok(config)
+}
+
+fn add_keymap(mut config: serde_json::Value) -> Result<serde_json::Value, Error> {
+    config
+        .as_object_mut()
+        .ok_or_else(|| not_an_object("config.json"))?
+        .entry("keymap")
+        .or_insert(serde_json::to_value(KeymapSettings::default())?);
+
+    Ok(config)
+}
+
+fn add_entry_metadata(mut entries: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let entries_map = entries
+        .as_object_mut()
+        .ok_or_else(|| Error::Malformed(String::from("passwords.json entries aren't a map")))?;
+    for entry in entries_map.values_mut() {
+        let entry = entry
+            .as_object_mut()
+            .ok_or_else(|| not_an_object("passwords.json entry"))?;
+        for field in ["username", "url", "notes", "notes_nonce"].iter() {
+            entry.entry(*field).or_insert_with(|| json!(""));
+        }
+        entry.entry("tags").or_insert_with(|| json!([]));
+        entry.entry("created").or_insert_with(|| json!(0));
+        entry.entry("modified").or_insert_with(|| json!(0));
+    }
+
+    Ok(entries)
+}
+
+fn add_totp(mut entries: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let entries_map = entries
+        .as_object_mut()
+        .ok_or_else(|| Error::Malformed(String::from("passwords.json entries aren't a map")))?;
+    for entry in entries_map.values_mut() {
+        let entry = entry
+            .as_object_mut()
+            .ok_or_else(|| not_an_object("passwords.json entry"))?;
+        entry.entry("totp").or_insert_with(|| json!(""));
+        entry.entry("totp_nonce").or_insert_with(|| json!(""));
+    }
+
+    Ok(entries)
+}
+
+fn add_history(mut entries: serde_json::Value) -> Result<serde_json::Value, Error> {
+    let entries_map = entries
+        .as_object_mut()
+        .ok_or_else(|| Error::Malformed(String::from("passwords.json entries aren't a map")))?;
+    for entry in entries_map.values_mut() {
+        entry
+            .as_object_mut()
+            .ok_or_else(|| not_an_object("passwords.json entry"))?
+            .entry("history")
+            .or_insert_with(|| json!([]));
+    }
+
+    Ok(entries)
+}
+
+#[inline]
+fn file_version(value: &serde_json::Value) -> u32 {
+    value.get("version").and_then(|v| v.as_u64()).unwrap_or(0) as u32
+}
+
+#[inline]
+fn migrate(
+    file_type: FileType,
+    value: serde_json::Value,
+    from: u32,
+) -> Result<serde_json::Value, Error> {
+    apply_migrations(file_type, value, from, file_type.migrations())
+}
+
+fn apply_migrations(
+    file_type: FileType,
+    value: serde_json::Value,
+    from: u32,
+    steps: &[Migration],
+) -> Result<serde_json::Value, Error> {
+    match steps.get(from as usize..) {
+        Some(remaining) => remaining.iter().try_fold(value, |value, step| step(value)),
+        None => Err(Error::UnsupportedVersion {
+            file: format!("{}.json", file_type),
+            version: from,
+            supported: steps.len() as u32,
+        }),
+    }
+}
+
+#[inline]
+fn not_an_object(what: &str) -> Error {
+    Error::Malformed(format!("{} isn't an object", what))
+}
+
+/// A vault opened with the right master key.
+pub struct UnlockedVault {
+    pub key: Aes128Gcm,
+    /// Entries moved to `unreadable.json` while opening it, because they
+    /// wouldn't decrypt with the key. Reported once, when they're moved.
+    pub unreadable: Vec<String>,
+}
+
+impl UnlockedVault {
+    /// The entries moved aside, as an error to show the user, if there are any.
+    pub fn unreadable_error(&self) -> Option<Error> {
+        Some(Error::Unreadable(self.unreadable.clone())).filter(|_| !self.unreadable.is_empty())
+    }
+}
+
+/// Opens the vault in use with the master key `key`, or `None` if it's the
+/// wrong key. On the way it finishes or rolls back an interrupted re-key, moves
+/// a vault from an older version over to the derived key and upgrades any
+/// files older than the current version, so whichever vault is opened, and
+/// however, it's in the state the rest of the code expects.
+pub fn unlock_vault(key: &str) -> Result<Option<UnlockedVault>, Error> {
+    recover_interrupted_rekey()?;
+    let passrc = read_passrc()?;
+    if !passrc.verify(key) {
+        return Ok(None);
+    }
+
+    let aead = keygen(key.as_bytes(), &passrc.salt)?.aead;
+    let mut unreadable = Vec::new();
+    if !passrc.key_derived {
+        unreadable = migrate_to_derived_key(&legacy_keygen(key.as_bytes().to_vec())?.aead, &aead)?;
+    }
+    upgrade_files(&aead)?;
+
+    Ok(Some(UnlockedVault {
+        key: aead,
+        unreadable,
+    }))
+}
+
+/// Rewrites any file older than the current version in its upgraded form. The
+/// original is first copied to `<file>.json.v<old version>.bak`, which, unlike
+/// the rolling backup, is never overwritten by later saves. The passwords are
+/// sealed in that copy even if they weren't in the original.
+pub fn upgrade_files(key: &Aes128Gcm) -> Result<(), Error> {
+    for file_type in [FileType::Config, FileType::Passrc].iter() {
+        let raw: serde_json::Value = serde_json::from_reader(read_json_file(*file_type)?)?;
+        let version = file_version(&raw);
+        if version < file_type.current_version() {
+            backup_before_upgrade(*file_type, version)?;
+            let upgraded = read_versioned(*file_type)?;
+            write_to_file(*file_type, serde_json::to_string_pretty(&upgraded)?)?;
+        }
+    }
+
+    let (entries, version) = open_passwords(key)?;
+    if version < FileType::Passwords.current_version() {
+        let backup = VaultContainer::seal_version(&entries, version, key)?;
+        let contents = serde_json::to_string_pretty(&backup)?;
+        write_synced(
+            &upgrade_backup_path(FileType::Passwords, version)?,
+            |file| file.write_all(contents.as_bytes()),
+        )?;
+        write_passwords(&read_passwords(key)?, key)?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn backup_before_upgrade(file_type: FileType, version: u32) -> Result<(), Error> {
+    copy_synced(
+        &file_path(file_type)?,
+        &upgrade_backup_path(file_type, version)?,
+    )
+}
+
+#[inline]
+fn upgrade_backup_path(file_type: FileType, version: u32) -> Result<PathBuf, Error> {
+    Ok(file_path(file_type)?.with_extension(format!("json.v{}.bak", version)))
+}
+
+#[inline]
+pub fn read_json_file(file: FileType) -> Result<BufReader<File>, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(file_path(file)?)?;
 
     Ok(BufReader::new(file))
 }
 
 pub fn write_new_password(
     new_username: String,
-    new_password: String,
+    new_password: &str,
+    details: EntryDetails,
     key: &Aes128Gcm,
-) -> Result<(), Box<dyn Error>> {
-    let bufreader = read_json_file(FileType::Passwords)?;
-    if let Ok(mut map) = serde_json::from_reader::<_, HashMap<String, PasswordEntry>>(bufreader) {
-        let (encrypted_pwd, pwd_nonce) = encrypt(&new_password, &key);
-        let new_entry = PasswordEntry::new(encode(encrypted_pwd), pwd_nonce);
+) -> Result<(), Error> {
+    let mut map = read_passwords(key)?;
+    map.insert(new_username, new_entry(new_password, details, key)?);
 
-        map.insert(new_username, new_entry);
+    write_passwords(&map, key)
+}
 
-        write_to_passwords_file(serde_json::to_string_pretty(&map)?)
-    } else {
-        panic!("Unable to read from passwords file!");
+/// Adds, or replaces, many entries with a single write, so they either all
+/// land or none do. A replaced entry's password goes into the new one's history.
+pub fn write_new_passwords(
+    entries: Vec<(String, PasswordEntry)>,
+    key: &Aes128Gcm,
+) -> Result<(), Error> {
+    let mut map = read_passwords(key)?;
+    let limit = read_config().unwrap_or_default().history_size;
+    for (service, mut entry) in entries {
+        if let Some(mut old) = map.remove(&service) {
+            old.retire_password(limit);
+            entry.history = old.history;
+        }
+        map.insert(service, entry);
+    }
+
+    write_passwords(&map, key)
+}
+
+/// Encrypts a new password, its notes and its 2FA secret under fresh nonces.
+pub fn new_entry(
+    password: &str,
+    details: EntryDetails,
+    key: &Aes128Gcm,
+) -> Result<PasswordEntry, Error> {
+    let (encrypted_pwd, pwd_nonce) = encrypt(password, key)?;
+    let (notes, notes_nonce) = encrypt_optional(details.notes.expose(), key)?;
+    let (totp, totp_nonce) = encrypt_optional(details.totp.expose(), key)?;
+    let now = unix_now();
+
+    Ok(PasswordEntry {
+        username: details.username,
+        url: details.url,
+        notes,
+        notes_nonce,
+        totp,
+        totp_nonce,
+        tags: details.tags,
+        created: now,
+        modified: now,
+        ..PasswordEntry::new(encode(encrypted_pwd), pwd_nonce)
+    })
+}
+
+pub fn delete_password(username_key: &str, key: &Aes128Gcm) -> Result<EntryState, Error> {
+    let mut map = read_passwords(key)?;
+    if map.remove_entry(username_key).is_none() {
+        return Ok(EntryState::NoSuchPassword);
+    };
+
+    write_passwords(&map, key)?;
+
+    Ok(EntryState::PasswordDeleted)
+}
+
+/// Renames an entry and, if a new password is given, replaces its password
+/// under a fresh nonce, keeping the old one in its history. Refuses to rename
+/// onto a service that already exists.
+pub fn edit_password(
+    service: &str,
+    new_service: String,
+    new_password: Option<&str>,
+    key: &Aes128Gcm,
+) -> Result<EntryState, Error> {
+    let mut map = read_passwords(key)?;
+    if new_service != service && map.contains_key(&new_service) {
+        return Ok(EntryState::PasswordExists);
+    }
+    let mut entry = match map.remove(service) {
+        Some(entry) => entry,
+        None => return Ok(EntryState::NoSuchPassword),
+    };
+
+    if let Some(new_password) = new_password {
+        entry.retire_password(read_config().unwrap_or_default().history_size);
+        let (encrypted_pwd, pwd_nonce) = encrypt(new_password, key)?;
+        entry.password = encode(encrypted_pwd);
+        entry.nonce = pwd_nonce;
     }
+    entry.modified = unix_now();
+    map.insert(new_service, entry);
+
+    write_passwords(&map, key)?;
+
+    Ok(EntryState::PasswordEdited)
 }
 
-pub fn delete_password(username_key: &str) -> Result<EntryState, Box<dyn Error>> {
-    let bufreader = read_json_file(FileType::Passwords)?;
-    if let Ok(mut map) = serde_json::from_reader::<_, HashMap<String, PasswordEntry>>(bufreader) {
-        if map.remove_entry(username_key).is_none() {
-            return Ok(EntryState::NoSuchPassword);
-        };
+/// Makes the entry's `index`th previous password its current one again. The
+/// password it replaces goes into the history, so a restore can be undone.
+pub fn restore_password(service: &str, index: usize, key: &Aes128Gcm) -> Result<EntryState, Error> {
+    let mut map = read_passwords(key)?;
+    let entry = match map.get_mut(service) {
+        Some(entry) => entry,
+        None => return Ok(EntryState::NoSuchPassword),
+    };
+    if index >= entry.history.len() {
+        return Err(Error::Input(format!(
+            "{} has only {} previous passwords",
+            service,
+            entry.history.len()
+        )));
+    }
+
+    let restored = entry.history.remove(index);
+    entry.retire_password(read_config().unwrap_or_default().history_size);
+    entry.password = restored.password;
+    entry.nonce = restored.nonce;
+    entry.modified = unix_now();
 
-        write_to_passwords_file(serde_json::to_string_pretty(&map)?)?;
+    write_passwords(&map, key)?;
 
-        Ok(EntryState::PasswordDeleted)
+    Ok(EntryState::PasswordEdited)
+}
+
+#[inline]
+fn write_passwords(map: &HashMap<String, PasswordEntry>, key: &Aes128Gcm) -> Result<(), Error> {
+    let contents = serde_json::to_string_pretty(&VaultContainer::seal(map, key)?)?;
+    // The first sealed write mustn't leave the plain map, service names and
+    // all, behind in the backup.
+    let backup = if passwords_sealed()? {
+        Backup::Previous
     } else {
-        panic!("Unable to read from passwords file!");
+        Backup::Current
+    };
+
+    atomic_write(&file_path(FileType::Passwords)?, backup, |file| {
+        file.write_all(contents.as_bytes())
+    })
+}
+
+#[inline]
+fn passwords_sealed() -> Result<bool, Error> {
+    if !file_path(FileType::Passwords)?.exists() {
+        return Ok(true);
     }
+    let value: serde_json::Value = serde_json::from_reader(read_json_file(FileType::Passwords)?)?;
+
+    Ok(VaultContainer::is_container(&value))
+}
+
+/// One-time migration of a vault written by an older version: re-encrypts every
+/// entry from the zero-padded legacy key to the Argon2-derived key and marks
+/// `passrc.json` so it only ever runs once.
+///
+/// Older versions encrypted new entries with whatever key was typed, so some
+/// may not decrypt with the vault's key. Rather than refusing to open the vault
+/// for good, those are moved as they are to `unreadable.json` beside it, and
+/// their names returned.
+///
+/// Both files are staged and committed the same way `rekey` does it, so an
+/// interrupted migration is finished or rolled back by `recover_interrupted_rekey`
+/// rather than leaving entries under a key passrc doesn't know about.
+pub fn migrate_to_derived_key(
+    legacy_key: &Aes128Gcm,
+    derived_key: &Aes128Gcm,
+) -> Result<Vec<String>, Error> {
+    let mut passrc = read_passrc()?;
+    if passrc.key_derived {
+        return Ok(Vec::new());
+    }
+
+    let mut map = HashMap::new();
+    let mut unreadable = HashMap::new();
+    for (service, entry) in read_passwords(derived_key)? {
+        // Only the ciphertext is kept aside, so it's no less safe than the vault.
+        let raw = serde_json::to_value(&entry)?;
+        match rekey_entry(entry, legacy_key, derived_key) {
+            Ok(rekeyed) => {
+                map.insert(service, rekeyed);
+            }
+            Err(_) => {
+                unreadable.insert(service, raw);
+            }
+        }
+    }
+    let mut names: Vec<String> = unreadable.keys().cloned().collect();
+    names.sort();
+    if !unreadable.is_empty() {
+        quarantine(unreadable)?;
+    }
+
+    let container = VaultContainer::seal(&map, derived_key)?;
+    passrc.key_derived = true;
+
+    stage_file(
+        FileType::Passwords,
+        serde_json::to_string_pretty(&container)?,
+    )?;
+    stage_file(FileType::Passrc, serde_json::to_string_pretty(&passrc)?)?;
+    commit_staged_file(FileType::Passwords)?;
+    commit_staged_file(FileType::Passrc)?;
+
+    Ok(names)
+}
+
+/// Adds `entries` to `unreadable.json`, keeping any already there from an
+/// earlier, interrupted, migration.
+fn quarantine(entries: HashMap<String, serde_json::Value>) -> Result<(), Error> {
+    let path = unreadable_path()?;
+    let mut all: HashMap<String, serde_json::Value> = match fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+    all.extend(entries);
+    let contents = serde_json::to_string_pretty(&all)?;
+
+    write_synced(&path, |file| file.write_all(contents.as_bytes()))
+}
+
+/// Where entries that wouldn't decrypt during the migration are kept.
+pub fn unreadable_path() -> Result<PathBuf, Error> {
+    Ok(paths::vault_dir()?.join("unreadable.json"))
+}
+
+/// Changes the master key: every entry is re-encrypted with a fresh nonce under a
+/// key derived from `new_key` and a new salt, and `passrc.json` is rewritten to
+/// match. Returns the new cipher.
+///
+/// Both files are staged next to the live ones first and then renamed into
+/// place, passwords before passrc, so a crash at any point leaves either the old
+/// vault or something `recover_interrupted_rekey` can finish. Neither backup is
+/// left holding the files under the old key.
+pub fn rekey(old_key: &Aes128Gcm, new_key: &str) -> Result<Aes128Gcm, Error> {
+    let passrc: Passrc = serde_json::from_value(new_passrc(new_key.as_bytes())?)?;
+    let new_aead = keygen(new_key.as_bytes(), &passrc.salt)?.aead;
+    let map = rekey_entries(read_passwords(old_key)?, old_key, &new_aead)?;
+    let container = VaultContainer::seal(&map, &new_aead)?;
+
+    stage_file(
+        FileType::Passwords,
+        serde_json::to_string_pretty(&container)?,
+    )?;
+    stage_file(FileType::Passrc, serde_json::to_string_pretty(&passrc)?)?;
+    commit_staged_file(FileType::Passwords)?;
+    commit_staged_file(FileType::Passrc)?;
+
+    Ok(new_aead)
+}
+
+/// Finishes or rolls back a re-key that was interrupted part way through.
+///
+/// If both staged files are still around the passwords were never swapped in, so
+/// the old vault is intact and the staged files are discarded. A lone staged
+/// passrc means the new passwords are already live and only passrc is missing.
+pub fn recover_interrupted_rekey() -> Result<(), Error> {
+    let staged_passwords = staged_path(FileType::Passwords)?;
+    let staged_passrc = staged_path(FileType::Passrc)?;
+
+    if Path::new(&staged_passwords).exists() {
+        fs::remove_file(&staged_passwords)?;
+        if Path::new(&staged_passrc).exists() {
+            fs::remove_file(&staged_passrc)?;
+        }
+    } else if Path::new(&staged_passrc).exists() {
+        commit_staged_file(FileType::Passrc)?;
+        // The crash may have come before the passwords' backup was replaced.
+        refresh_backup(&file_path(FileType::Passwords)?)?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn stage_file(file_type: FileType, contents: String) -> Result<(), Error> {
+    write_synced(&staged_path(file_type)?, |file| {
+        file.write_all(contents.as_bytes())
+    })
+}
+
+/// Staged files are only ever under a new key, so the file they replace isn't
+/// kept as a backup.
+#[inline]
+fn commit_staged_file(file_type: FileType) -> Result<(), Error> {
+    replace_with_backup(
+        &staged_path(file_type)?,
+        &file_path(file_type)?,
+        Backup::Current,
+    )
+}
+
+#[inline]
+fn staged_path(file_type: FileType) -> Result<PathBuf, Error> {
+    Ok(file_path(file_type)?.with_extension("json.rekey"))
 }
 
 #[inline]
-fn write_to_passwords_file(new_passwords: String) -> Result<(), Box<dyn Error>> {
-    let passwords_path = format!("{}/{}.json", &get_home_dir(), FileType::Passwords);
+fn write_to_file(file_type: FileType, contents: String) -> Result<(), Error> {
+    atomic_write(&file_path(file_type)?, Backup::Previous, |file| {
+        file.write_all(contents.as_bytes())
+    })
+}
+
+/// What the `.bak` beside a replaced file holds afterwards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Backup {
+    /// The version that was replaced, so it can be recovered.
+    Previous,
+    /// The new version, for when the one replaced mustn't be kept around: it
+    /// was never sealed, or it's under a master key that has been changed.
+    Current,
+}
+
+/// Replaces `path` without ever leaving it half written: the new contents go to
+/// a `.tmp` sibling which is flushed to disk and then renamed over the original.
+fn atomic_write<F>(path: &Path, backup: Backup, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let tmp_path = path.with_extension("json.tmp");
+    write_synced(&tmp_path, write)?;
+    replace_with_backup(&tmp_path, path, backup)
+}
+
+/// Writes a file and syncs it to disk, removing it again if anything fails.
+fn write_synced<F>(path: &Path, write: F) -> Result<(), Error>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
     let mut file = OpenOptions::new()
         .write(true)
+        .create(true)
         .truncate(true)
-        .open(&passwords_path)?;
+        .open(path)?;
+
+    if let Err(e) = write(&mut file).and_then(|_| file.sync_all()) {
+        drop(file);
+        fs::remove_file(path).ok();
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+/// Renames `staged` over `live`, keeping a `.bak` sibling as `backup` says.
+fn replace_with_backup(staged: &Path, live: &Path, backup: Backup) -> Result<(), Error> {
+    let backup_path = live.with_extension("json.bak");
+    if backup == Backup::Previous && live.exists() {
+        copy_synced(live, &backup_path)?;
+    }
+    fs::rename(staged, live)?;
+    if backup == Backup::Current {
+        refresh_backup(live)?;
+    }
+
+    // Persist the rename itself.
+    if let Some(dir) = live.parent() {
+        File::open(dir)?.sync_all()?;
+    }
 
-    file.write_all(new_passwords.as_bytes())?;
     Ok(())
 }
 
+/// Overwrites the backup of `live`, if it has one, with `live` as it is now.
 #[inline]
-pub fn check_directory_exists() -> Result<(), Box<dyn Error>> {
-    if !Path::new(&get_home_dir()).exists() {
-        match fs::create_dir(get_home_dir()) {
-            Ok(_s) => {}
-            Err(e) => panic!("Could not create passcurses directory: {}", e),
-        }
+fn refresh_backup(live: &Path) -> Result<(), Error> {
+    let backup_path = live.with_extension("json.bak");
+    if backup_path.exists() {
+        copy_synced(live, &backup_path)?;
     }
 
     Ok(())
 }
 
-pub fn check_files(key: &str) -> Result<(), Box<dyn Error>> {
-    let home_dir = &get_home_dir();
-    let build_path = |ft: &FileType| format!("{}/{}.json", home_dir, ft);
+#[inline]
+fn copy_synced(from: &Path, to: &Path) -> Result<(), Error> {
+    let contents = fs::read(from)?;
+    write_synced(to, |file| file.write_all(&contents))
+}
+
+#[inline]
+pub fn check_directory_exists() -> Result<(), Error> {
+    for dir in [paths::data_dir()?, paths::vault_dir()?].iter() {
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    Ok(())
+}
 
+pub fn check_files(key: &str) -> Result<(), Error> {
     for file_type in [FileType::Passwords, FileType::Config, FileType::Passrc].iter() {
-        let path = build_path(file_type);
-        if !Path::new(&path).exists() {
-            println!("Creating {} json file...", file_type);
+        let path = file_path(*file_type)?;
+        if !path.exists() {
+            eprintln!("Creating {} json file...", file_type);
             populate_new_file(
                 file_type,
                 path,
@@ -160,38 +923,422 @@ pub fn check_files(key: &str) -> Result<(), Box<dyn Error>> {
 }
 
 #[inline]
-fn populate_new_file(
-    file_type: &FileType,
-    path: String,
-    key: Option<&str>,
-) -> Result<(), Box<dyn Error>> {
-    let mut new_file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .open(&path)?;
-
+fn populate_new_file(file_type: &FileType, path: PathBuf, key: Option<&str>) -> Result<(), Error> {
     let template = match file_type {
         FileType::Passwords => json!({}).to_string(),
         FileType::Config => serde_json::to_string_pretty(&RawConfigs::default())?,
-        FileType::Passrc => serde_json::to_string_pretty(&new_passrc(key.unwrap().as_bytes()))?,
+        FileType::Passrc => {
+            let key = key.ok_or_else(|| Error::Input(String::from("No key for passrc.json")))?;
+            serde_json::to_string_pretty(&new_passrc(key.as_bytes())?)?
+        }
     };
 
-    Ok(new_file.write_all(template.as_bytes())?)
+    atomic_write(&path, Backup::Previous, |file| {
+        file.write_all(template.as_bytes())
+    })
 }
 
 #[inline]
-fn new_passrc(key: &[u8]) -> serde_json::Value {
+pub(crate) fn new_passrc(key: &[u8]) -> Result<serde_json::Value, Error> {
     let mut salt = [0_u8; 16];
-    thread_rng().try_fill(&mut salt[..]).unwrap();
+    thread_rng().try_fill(&mut salt[..])?;
 
-    json!({
-        "key": argon2::hash_encoded(&key, &salt, &Config::default()).unwrap(),
+    Ok(json!({
+        "key": argon2::hash_encoded(key, &salt, &Config::default())?,
         "salt": salt,
-    })
+        "key_derived": true,
+        "version": PASSRC_VERSION,
+    }))
 }
 
+/// The config is shared by every vault; the passwords and passrc belong to the
+/// vault in use.
 #[inline]
-fn get_home_dir() -> String {
-    home_dir().unwrap().into_os_string().into_string().unwrap() + "/.passcurses"
+fn file_path(file_type: FileType) -> Result<PathBuf, Error> {
+    let dir = match file_type {
+        FileType::Config => paths::data_dir()?,
+        FileType::Passwords | FileType::Passrc => paths::vault_dir()?,
+    };
+
+    Ok(dir.join(format!("{}.json", file_type)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::aead::generic_array::GenericArray;
+    use aes_gcm::NewAead;
+
+    #[test]
+    fn test_passrc_verifies_its_own_key() {
+        let passrc: Passrc = serde_json::from_value(new_passrc(b"master").unwrap()).unwrap();
+        assert!(passrc.verify("master"));
+        assert!(passrc.key_derived);
+    }
+
+    #[test]
+    fn test_passrc_rejects_wrong_key() {
+        let passrc: Passrc = serde_json::from_value(new_passrc(b"master").unwrap()).unwrap();
+        assert!(!passrc.verify("masterr"));
+        assert!(!passrc.verify(""));
+    }
+
+    #[test]
+    fn test_passrc_without_key_derived_is_legacy() {
+        let passrc: Passrc = serde_json::from_value(json!({
+            "key": "$argon2i$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaA",
+            "salt": vec![0_u8; 16],
+        }))
+        .unwrap();
+        assert!(!passrc.key_derived);
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("passcurses-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_atomic_write_creates_file_without_backup() {
+        let dir = test_dir("atomic-create");
+        let path = dir.join("passwords.json");
+
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"{}")).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+        assert!(!path.with_extension("json.bak").exists());
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn test_atomic_write_keeps_previous_version_as_backup() {
+        let dir = test_dir("atomic-backup");
+        let path = dir.join("passwords.json");
+
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"first")).unwrap();
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"second")).unwrap();
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"third")).unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third");
+        assert_eq!(
+            fs::read_to_string(path.with_extension("json.bak")).unwrap(),
+            "second"
+        );
+    }
+
+    #[test]
+    fn test_interrupted_write_leaves_original_intact() {
+        let dir = test_dir("atomic-interrupted");
+        let path = dir.join("passwords.json");
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"original")).unwrap();
+
+        let result = atomic_write(&path, Backup::Previous, |f| {
+            f.write_all(b"half writ")?;
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn test_stale_tmp_from_crash_is_ignored_and_replaced() {
+        let dir = test_dir("atomic-stale-tmp");
+        let path = dir.join("passwords.json");
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"original")).unwrap();
+        // A crash after writing the temp file but before the rename.
+        fs::write(path.with_extension("json.tmp"), b"{\"trunc").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "original");
+
+        atomic_write(&path, Backup::Previous, |f| f.write_all(b"updated")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "updated");
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[test]
+    fn test_first_sealed_write_replaces_plain_backup() {
+        let dir = paths::TestDataDir::new("first-seal");
+        let path = dir.path().join("passwords.json");
+        let plain = json!({"secret_service": {"password": "c2VjcmV0", "nonce": "asdfjklqasdf"}});
+        fs::write(&path, plain.to_string()).unwrap();
+        fs::write(path.with_extension("json.bak"), plain.to_string()).unwrap();
+
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        write_new_passwords(Vec::new(), &key).unwrap();
+
+        let backup = fs::read_to_string(path.with_extension("json.bak")).unwrap();
+        assert!(!backup.contains("secret_service"));
+        assert_eq!(backup, fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn test_upgrade_backup_of_plain_map_is_sealed() {
+        let dir = paths::TestDataDir::new("upgrade-plain");
+        let path = dir.path().join("passwords.json");
+        let plain = json!({"secret_service": {"password": "c2VjcmV0", "nonce": "asdfjklqasdf"}});
+        fs::write(&path, plain.to_string()).unwrap();
+        check_files("testing987654321").unwrap();
+
+        let key = Aes128Gcm::new(GenericArray::from_slice(b"testing987654321"));
+        upgrade_files(&key).unwrap();
+
+        let backup = fs::read_to_string(path.with_extension("json.v0.bak")).unwrap();
+        assert!(!backup.contains("secret_service"));
+        let container: VaultContainer = serde_json::from_str(&backup).unwrap();
+        assert_eq!(container.version, 0);
+        assert_eq!(container.open(&key).unwrap(), plain);
+        assert!(read_passwords(&key).unwrap().contains_key("secret_service"));
+    }
+
+    #[test]
+    fn test_legacy_entries_under_another_key_are_moved_aside() {
+        let dir = paths::TestDataDir::new("legacy-mixed");
+        check_files("testing987654321").unwrap();
+        let mut passrc = serde_json::to_value(read_passrc().unwrap()).unwrap();
+        passrc["key_derived"] = json!(false);
+        fs::write(dir.path().join("passrc.json"), passrc.to_string()).unwrap();
+        // Older versions encrypted with whatever key was typed in.
+        let legacy = legacy_keygen(b"testing987654321".to_vec()).unwrap().aead;
+        let typo = legacy_keygen(b"testing98765432".to_vec()).unwrap().aead;
+        let (good, good_nonce) = encrypt("good_pass", &legacy).unwrap();
+        let (stray, stray_nonce) = encrypt("stray_pass", &typo).unwrap();
+        let stray = encode(stray);
+        fs::write(
+            dir.path().join("passwords.json"),
+            json!({
+                "good": {"password": encode(good), "nonce": good_nonce},
+                "stray": {"password": stray, "nonce": stray_nonce},
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let vault = unlock_vault("testing987654321").unwrap().unwrap();
+        assert_eq!(vault.unreadable, vec![String::from("stray")]);
+        let entries = read_passwords(&vault.key).unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["good"]);
+        let decrypted = crate::util::utils::try_decrypt(crate::util::utils::EncryptionData {
+            password: &entries["good"].password,
+            nonce: &entries["good"].nonce,
+            key: &vault.key,
+        });
+        assert_eq!(decrypted.unwrap(), "good_pass");
+
+        let moved: serde_json::Value =
+            serde_json::from_slice(&fs::read(unreadable_path().unwrap()).unwrap()).unwrap();
+        assert_eq!(moved["stray"]["password"], stray);
+
+        // Moved once, so the next unlock has nothing to report.
+        let vault = unlock_vault("testing987654321").unwrap().unwrap();
+        assert!(vault.unreadable.is_empty());
+    }
+
+    #[test]
+    fn test_rekey_leaves_no_backup_under_the_old_key() {
+        let dir = paths::TestDataDir::new("rekey-backup");
+        check_files("old master key").unwrap();
+        let old_key = keygen(b"old master key", &read_passrc().unwrap().salt)
+            .unwrap()
+            .aead;
+        for service in ["first", "second"].iter() {
+            write_new_password(
+                service.to_string(),
+                "password",
+                EntryDetails::default(),
+                &old_key,
+            )
+            .unwrap();
+        }
+        let passrc_path = dir.path().join("passrc.json");
+        fs::copy(&passrc_path, passrc_path.with_extension("json.bak")).unwrap();
+
+        let new_key = rekey(&old_key, "new master key").unwrap();
+
+        let backup: VaultContainer = serde_json::from_str(
+            &fs::read_to_string(dir.path().join("passwords.json.bak")).unwrap(),
+        )
+        .unwrap();
+        assert!(backup.open(&old_key).is_err());
+        assert!(backup.open(&new_key).is_ok());
+        let passrc_backup: Passrc = serde_json::from_str(
+            &fs::read_to_string(passrc_path.with_extension("json.bak")).unwrap(),
+        )
+        .unwrap();
+        assert!(passrc_backup.verify("new master key"));
+    }
+
+    fn bump_counter(mut value: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let counter = value["counter"].as_u64().unwrap_or(0);
+        value["counter"] = json!(counter + 1);
+        Ok(value)
+    }
+
+    fn rename_field(mut value: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let counter = value["counter"].take();
+        value["renamed"] = counter;
+        Ok(value)
+    }
+
+    #[test]
+    fn test_migrations_apply_in_order_from_version_zero() {
+        let steps: [Migration; 3] = [bump_counter, bump_counter, rename_field];
+        let value = apply_migrations(FileType::Config, json!({}), 0, &steps).unwrap();
+        assert_eq!(value["renamed"], json!(2));
+    }
+
+    #[test]
+    fn test_migrations_skip_steps_already_applied() {
+        let steps: [Migration; 3] = [bump_counter, bump_counter, rename_field];
+        let value = apply_migrations(FileType::Config, json!({"counter": 5}), 2, &steps).unwrap();
+        assert_eq!(value["renamed"], json!(5));
+    }
+
+    #[test]
+    fn test_migrating_a_newer_file_is_err() {
+        let steps: [Migration; 1] = [bump_counter];
+        assert!(apply_migrations(FileType::Config, json!({}), 2, &steps).is_err());
+    }
+
+    #[test]
+    fn test_every_file_has_a_step_per_version() {
+        for file_type in [FileType::Passwords, FileType::Config, FileType::Passrc].iter() {
+            assert_eq!(
+                file_type.migrations().len() as u32,
+                file_type.current_version()
+            );
+        }
+    }
+
+    #[test]
+    fn test_unversioned_file_is_version_zero() {
+        assert_eq!(file_version(&json!({"border_type": "rounded"})), 0);
+        assert_eq!(file_version(&json!({"version": 1})), 1);
+    }
+
+    #[test]
+    fn test_add_entry_metadata_fills_defaults() {
+        let entries = json!({"test_user": {"password": "c2VjcmV0", "nonce": "asdfjklqasdf"}});
+        let upgraded = add_entry_metadata(entries).unwrap();
+        let map: HashMap<String, PasswordEntry> = serde_json::from_value(upgraded).unwrap();
+        let entry = &map["test_user"];
+        assert_eq!(entry.password, "c2VjcmV0");
+        assert!(entry.username.is_empty());
+        assert!(entry.tags.is_empty());
+        assert_eq!(entry.created, 0);
+    }
+
+    #[test]
+    fn test_add_entry_metadata_keeps_existing_fields() {
+        let entries = json!({"test_user": {
+            "password": "c2VjcmV0",
+            "nonce": "asdfjklqasdf",
+            "username": "me@example.com",
+            "tags": ["work"],
+        }});
+        let upgraded = add_entry_metadata(entries).unwrap();
+        assert_eq!(upgraded["test_user"]["username"], json!("me@example.com"));
+        assert_eq!(upgraded["test_user"]["tags"], json!(["work"]));
+    }
+
+    #[test]
+    fn test_add_totp() {
+        let entries = json!({"test_user": {"password": "c2VjcmV0", "nonce": "asdfjklqasdf"}});
+        let upgraded = add_totp(entries).unwrap();
+        assert_eq!(upgraded["test_user"]["totp"], json!(""));
+        assert_eq!(upgraded["test_user"]["totp_nonce"], json!(""));
+    }
+
+    #[test]
+    fn test_add_history() {
+        let entries = json!({"test_user": {"password": "c2VjcmV0", "nonce": "asdfjklqasdf"}});
+        let upgraded = add_history(entries).unwrap();
+        assert_eq!(upgraded["test_user"]["history"], json!([]));
+    }
+
+    #[test]
+    fn test_add_history_size() {
+        let upgraded = add_history_size(json!({"version": 5})).unwrap();
+        assert_eq!(upgraded["history_size"], 10);
+
+        let kept = add_history_size(json!({"history_size": 0})).unwrap();
+        assert_eq!(kept["history_size"], 0);
+    }
+
+    #[test]
+    fn test_add_max_password_age() {
+        let upgraded = add_max_password_age(json!({"version": 6})).unwrap();
+        assert_eq!(upgraded["max_password_age"], 365);
+
+        let kept = add_max_password_age(json!({"max_password_age": 30})).unwrap();
+        assert_eq!(kept["max_password_age"], 30);
+    }
+
+    #[test]
+    fn test_add_breach_file() {
+        let upgraded = add_breach_file(json!({"version": 7})).unwrap();
+        assert_eq!(upgraded["breach_file"], "");
+
+        let kept = add_breach_file(json!({"breach_file": "pwned.bin"})).unwrap();
+        assert_eq!(kept["breach_file"], "pwned.bin");
+    }
+
+    #[test]
+    fn test_add_keymap() {
+        let upgraded = add_keymap(json!({"version": 8})).unwrap();
+        let settings: KeymapSettings = serde_json::from_value(upgraded["keymap"].clone()).unwrap();
+        assert_eq!(settings, KeymapSettings::default());
+    }
+
+    #[test]
+    fn test_retire_password_keeps_newest() {
+        let mut entry = PasswordEntry::new(String::from("first"), String::from("nonce1"));
+        entry.retire_password(2);
+        entry.password = String::from("second");
+        entry.retire_password(2);
+        entry.password = String::from("third");
+        entry.retire_password(2);
+
+        let kept: Vec<&str> = entry.history.iter().map(|p| p.password.as_str()).collect();
+        assert_eq!(kept, vec!["third", "second"]);
+        assert!(entry.password.is_empty());
+    }
+
+    #[test]
+    fn test_add_generator_settings() {
+        let config = json!({"border_type": "rounded", "version": 1});
+        let upgraded = add_generator_settings(config).unwrap();
+        let settings: GeneratorSettings =
+            serde_json::from_value(upgraded["generator"].clone()).unwrap();
+        assert_eq!(settings, GeneratorSettings::default());
+    }
+
+    #[test]
+    fn test_add_clipboard_timeout() {
+        let upgraded = add_clipboard_timeout(json!({"version": 2})).unwrap();
+        assert_eq!(upgraded["clipboard_timeout"], 30);
+
+        let kept = add_clipboard_timeout(json!({"clipboard_timeout": 5})).unwrap();
+        assert_eq!(kept["clipboard_timeout"], 5);
+    }
+
+    #[test]
+    fn test_add_lock_timeout() {
+        let upgraded = add_lock_timeout(json!({"version": 4})).unwrap();
+        assert_eq!(upgraded["lock_timeout"], 300);
+
+        let kept = add_lock_timeout(json!({"lock_timeout": 0})).unwrap();
+        assert_eq!(kept["lock_timeout"], 0);
+    }
+
+    #[test]
+    fn test_add_clipboard_settings() {
+        let upgraded = add_clipboard_settings(json!({"version": 3})).unwrap();
+        let settings: ClipboardSettings =
+            serde_json::from_value(upgraded["clipboard"].clone()).unwrap();
+        assert_eq!(settings, ClipboardSettings::default());
+    }
 }
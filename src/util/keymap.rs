@@ -0,0 +1,570 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use termion::event::Key;
+
+use crate::util::error::Error;
+
+/// Everything a key can be bound to in the password table, its popups and the
+/// prompts that ask for input.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    MoveDown,
+    MoveUp,
+    MoveDown5,
+    MoveUp5,
+    JumpTop,
+    JumpBottom,
+    JumpMiddle,
+    Search,
+    NextMatch,
+    PreviousMatch,
+    ClearSearch,
+    CopyPassword,
+    RevealPassword,
+    ToggleCode,
+    CopyCode,
+    Refresh,
+    NewPassword,
+    EditPassword,
+    History,
+    DeletePassword,
+    Audit,
+    Rekey,
+    SwitchVault,
+    Select,
+    SortAudit,
+    Generate,
+    GeneratePassphrase,
+    Cancel,
+    Help,
+    Quit,
+}
+
+impl Action {
+    /// Every action, in the order the help window lists them.
+    pub const ALL: [Action; 30] = [
+        Action::MoveDown,
+        Action::MoveUp,
+        Action::MoveDown5,
+        Action::MoveUp5,
+        Action::JumpTop,
+        Action::JumpBottom,
+        Action::JumpMiddle,
+        Action::Search,
+        Action::NextMatch,
+        Action::PreviousMatch,
+        Action::ClearSearch,
+        Action::CopyPassword,
+        Action::RevealPassword,
+        Action::ToggleCode,
+        Action::CopyCode,
+        Action::Refresh,
+        Action::NewPassword,
+        Action::EditPassword,
+        Action::History,
+        Action::DeletePassword,
+        Action::Audit,
+        Action::Rekey,
+        Action::SwitchVault,
+        Action::Select,
+        Action::SortAudit,
+        Action::Generate,
+        Action::GeneratePassphrase,
+        Action::Cancel,
+        Action::Help,
+        Action::Quit,
+    ];
+
+    /// The name used for the action in `config.json`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveDown => "move_down",
+            Action::MoveUp => "move_up",
+            Action::MoveDown5 => "move_down_5",
+            Action::MoveUp5 => "move_up_5",
+            Action::JumpTop => "jump_top",
+            Action::JumpBottom => "jump_bottom",
+            Action::JumpMiddle => "jump_middle",
+            Action::Search => "search",
+            Action::NextMatch => "next_match",
+            Action::PreviousMatch => "previous_match",
+            Action::ClearSearch => "clear_search",
+            Action::CopyPassword => "copy_password",
+            Action::RevealPassword => "reveal_password",
+            Action::ToggleCode => "toggle_code",
+            Action::CopyCode => "copy_code",
+            Action::Refresh => "refresh",
+            Action::NewPassword => "new_password",
+            Action::EditPassword => "edit_password",
+            Action::History => "history",
+            Action::DeletePassword => "delete_password",
+            Action::Audit => "audit",
+            Action::Rekey => "rekey",
+            Action::SwitchVault => "switch_vault",
+            Action::Select => "select",
+            Action::SortAudit => "sort_audit",
+            Action::Generate => "generate",
+            Action::GeneratePassphrase => "generate_passphrase",
+            Action::Cancel => "cancel",
+            Action::Help => "help",
+            Action::Quit => "quit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Action::ALL
+            .iter()
+            .copied()
+            .find(|action| action.name() == name)
+    }
+
+    /// Whether the action is for the prompts rather than the table. Prompt
+    /// keys can share keys with the table's, as they're never both active.
+    pub fn in_prompt(self) -> bool {
+        matches!(
+            self,
+            Action::Generate | Action::GeneratePassphrase | Action::Cancel
+        )
+    }
+
+    /// What the action does, for the help window.
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::MoveDown => "move down",
+            Action::MoveUp => "move up",
+            Action::MoveDown5 => "move down x5",
+            Action::MoveUp5 => "move up x5",
+            Action::JumpTop => "jump to top",
+            Action::JumpBottom => "jump to bottom",
+            Action::JumpMiddle => "jump to middle",
+            Action::Search => "search",
+            Action::NextMatch => "next match",
+            Action::PreviousMatch => "previous match",
+            Action::ClearSearch => "clear search",
+            Action::CopyPassword => "copy password",
+            Action::RevealPassword => "decrypt the password",
+            Action::ToggleCode => "show/hide 2FA code",
+            Action::CopyCode => "copy 2FA code",
+            Action::Refresh => "refresh passwords",
+            Action::NewPassword => "create new password",
+            Action::EditPassword => "edit password",
+            Action::History => "previous passwords",
+            Action::DeletePassword => "delete password",
+            Action::Audit => "audit passwords",
+            Action::Rekey => "change master key",
+            Action::SwitchVault => "switch vault",
+            Action::Select => "restore/go to entry in a popup",
+            Action::SortAudit => "change the audit's sort",
+            Action::Generate => "generate a password in a prompt",
+            Action::GeneratePassphrase => "generate a passphrase in a prompt",
+            Action::Cancel => "cancel a prompt",
+            Action::Help => "hide/show help",
+            Action::Quit => "quit",
+        }
+    }
+}
+
+/// Key binding settings, stored under `keymap` in `config.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct KeymapSettings {
+    /// `vim` or `emacs`, the bindings to start from.
+    pub(crate) preset: String,
+    /// Keys to bind on top of the preset, e.g. `"Ctrl-j": "move_down"`. Binding
+    /// a key to `none` unbinds it.
+    pub(crate) bindings: BTreeMap<String, String>,
+}
+
+impl Default for KeymapSettings {
+    fn default() -> KeymapSettings {
+        KeymapSettings {
+            preset: String::from("vim"),
+            bindings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Which action each key does in the password table.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    /// In the order they were bound, so the help window lists keys the way the
+    /// preset does.
+    bindings: Vec<(Key, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::from_pairs(VIM)
+    }
+}
+
+impl Keymap {
+    pub fn from_settings(settings: &KeymapSettings) -> Result<Keymap, Error> {
+        let mut keymap = match settings.preset.as_str() {
+            "vim" => Keymap::from_pairs(VIM),
+            "emacs" => Keymap::from_pairs(EMACS),
+            other => return Err(invalid(format!("unknown preset {}", other))),
+        };
+
+        for (name, action) in &settings.bindings {
+            let key = parse_key(name).ok_or_else(|| invalid(format!("unknown key {}", name)))?;
+            if action == "none" {
+                keymap.bindings.retain(|(bound, _)| *bound != key);
+                continue;
+            }
+            let action = Action::from_name(action)
+                .ok_or_else(|| invalid(format!("unknown action {}", action)))?;
+            keymap.bindings.retain(|(bound, bound_action)| {
+                *bound != key || bound_action.in_prompt() != action.in_prompt()
+            });
+            keymap.bindings.push((key, action));
+        }
+
+        Ok(keymap)
+    }
+
+    fn from_pairs(pairs: &[(&str, Action)]) -> Keymap {
+        Keymap {
+            bindings: pairs
+                .iter()
+                .map(|(name, action)| (parse_key(name).expect("preset keys parse"), *action))
+                .collect(),
+        }
+    }
+
+    /// What `key` does in the table and its popups.
+    pub fn action(&self, key: Key) -> Option<Action> {
+        self.find(key, false)
+    }
+
+    /// What `key` does in a prompt, where the other keys are typed in.
+    pub fn prompt_action(&self, key: Key) -> Option<Action> {
+        self.find(key, true)
+    }
+
+    fn find(&self, key: Key, in_prompt: bool) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(bound, action)| *bound == key && action.in_prompt() == in_prompt)
+            .map(|(_, action)| *action)
+    }
+
+    /// The keys bound to `action`, joined for display, e.g. "j/Down". Empty if
+    /// it's unbound.
+    pub fn keys_for(&self, action: Action) -> String {
+        self.bindings
+            .iter()
+            .filter(|(_, bound)| *bound == action)
+            .map(|(key, _)| key_name(*key))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Whether `key` backs out of a popup: the keys that clear a search or quit.
+    pub fn closes_popup(&self, key: Key) -> bool {
+        matches!(
+            self.action(key),
+            Some(Action::ClearSearch) | Some(Action::Quit)
+        )
+    }
+
+    /// The keys that back out of a popup, joined for display like `keys_for`.
+    pub fn close_keys(&self) -> String {
+        [Action::ClearSearch, Action::Quit]
+            .iter()
+            .map(|action| self.keys_for(*action))
+            .filter(|keys| !keys.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// The keys and description of every bound action, in help window order.
+    pub fn help(&self) -> Vec<(String, &'static str)> {
+        Action::ALL
+            .iter()
+            .map(|action| (self.keys_for(*action), action.description()))
+            .filter(|(keys, _)| !keys.is_empty())
+            .collect()
+    }
+}
+
+/// The bindings passcurses has always had.
+const VIM: &[(&str, Action)] = &[
+    ("j", Action::MoveDown),
+    ("Down", Action::MoveDown),
+    ("k", Action::MoveUp),
+    ("Up", Action::MoveUp),
+    ("Ctrl-d", Action::MoveDown5),
+    ("Ctrl-u", Action::MoveUp5),
+    ("g", Action::JumpTop),
+    ("G", Action::JumpBottom),
+    ("M", Action::JumpMiddle),
+    ("/", Action::Search),
+    ("n", Action::NextMatch),
+    ("N", Action::PreviousMatch),
+    ("Esc", Action::ClearSearch),
+    ("y", Action::CopyPassword),
+    ("d", Action::RevealPassword),
+    ("t", Action::ToggleCode),
+    ("T", Action::CopyCode),
+    ("r", Action::Refresh),
+    ("c", Action::NewPassword),
+    ("e", Action::EditPassword),
+    ("H", Action::History),
+    ("D", Action::DeletePassword),
+    ("a", Action::Audit),
+    ("R", Action::Rekey),
+    ("v", Action::SwitchVault),
+    ("Enter", Action::Select),
+    ("s", Action::SortAudit),
+    ("Ctrl-g", Action::Generate),
+    ("Ctrl-p", Action::GeneratePassphrase),
+    ("Esc", Action::Cancel),
+    ("?", Action::Help),
+    ("q", Action::Quit),
+];
+
+const EMACS: &[(&str, Action)] = &[
+    ("Ctrl-n", Action::MoveDown),
+    ("Down", Action::MoveDown),
+    ("Ctrl-p", Action::MoveUp),
+    ("Up", Action::MoveUp),
+    ("Ctrl-v", Action::MoveDown5),
+    ("Alt-v", Action::MoveUp5),
+    ("Alt-<", Action::JumpTop),
+    ("Alt->", Action::JumpBottom),
+    ("Ctrl-l", Action::JumpMiddle),
+    ("Ctrl-s", Action::Search),
+    ("Alt-n", Action::NextMatch),
+    ("Alt-p", Action::PreviousMatch),
+    ("Ctrl-g", Action::ClearSearch),
+    ("Esc", Action::ClearSearch),
+    ("Alt-w", Action::CopyPassword),
+    ("Ctrl-o", Action::RevealPassword),
+    ("Ctrl-t", Action::ToggleCode),
+    ("Alt-t", Action::CopyCode),
+    ("Alt-g", Action::Refresh),
+    ("Alt-c", Action::NewPassword),
+    ("Alt-e", Action::EditPassword),
+    ("Alt-h", Action::History),
+    ("Ctrl-d", Action::DeletePassword),
+    ("Alt-a", Action::Audit),
+    ("Alt-k", Action::Rekey),
+    ("Alt-o", Action::SwitchVault),
+    ("Enter", Action::Select),
+    ("Alt-s", Action::SortAudit),
+    ("Alt-g", Action::Generate),
+    ("Alt-p", Action::GeneratePassphrase),
+    ("Ctrl-g", Action::Cancel),
+    ("Esc", Action::Cancel),
+    ("F1", Action::Help),
+    ("Ctrl-q", Action::Quit),
+];
+
+/// Reads a key as written in `config.json`: a character, a named key like
+/// `Down` or `F1`, or either of those after `Ctrl-` or `Alt-`.
+pub fn parse_key(name: &str) -> Option<Key> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(Key::Char(c));
+    }
+
+    let lower = name.to_ascii_lowercase();
+    for prefix in ["ctrl-", "alt-"] {
+        if lower.starts_with(prefix) {
+            let rest = &name[prefix.len()..];
+            let mut chars = rest.chars();
+            let c = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return None,
+            };
+            return Some(if prefix == "ctrl-" {
+                Key::Ctrl(c.to_ascii_lowercase())
+            } else {
+                Key::Alt(c)
+            });
+        }
+    }
+
+    Some(match lower.as_str() {
+        "up" => Key::Up,
+        "down" => Key::Down,
+        "left" => Key::Left,
+        "right" => Key::Right,
+        "home" => Key::Home,
+        "end" => Key::End,
+        "pageup" => Key::PageUp,
+        "pagedown" => Key::PageDown,
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "insert" => Key::Insert,
+        "esc" => Key::Esc,
+        "enter" => Key::Char('\n'),
+        "tab" => Key::Char('\t'),
+        "space" => Key::Char(' '),
+        f if f.starts_with('f') => Key::F(f[1..].parse().ok().filter(|n| (1..=12).contains(n))?),
+        _ => return None,
+    })
+}
+
+/// The name `parse_key` reads back as `key`.
+pub fn key_name(key: Key) -> String {
+    match key {
+        Key::Char('\n') => String::from("Enter"),
+        Key::Char('\t') => String::from("Tab"),
+        Key::Char(' ') => String::from("Space"),
+        Key::Char(c) => c.to_string(),
+        Key::Ctrl(c) => format!("Ctrl-{}", c),
+        Key::Alt(c) => format!("Alt-{}", c),
+        Key::F(n) => format!("F{}", n),
+        Key::Up => String::from("Up"),
+        Key::Down => String::from("Down"),
+        Key::Left => String::from("Left"),
+        Key::Right => String::from("Right"),
+        Key::Home => String::from("Home"),
+        Key::End => String::from("End"),
+        Key::PageUp => String::from("PageUp"),
+        Key::PageDown => String::from("PageDown"),
+        Key::Backspace => String::from("Backspace"),
+        Key::Delete => String::from("Delete"),
+        Key::Insert => String::from("Insert"),
+        Key::Esc => String::from("Esc"),
+        _ => String::from("?"),
+    }
+}
+
+#[inline]
+fn invalid(reason: String) -> Error {
+    Error::Malformed(format!("Invalid keymap in config.json: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(parse_key("j"), Some(Key::Char('j')));
+        assert_eq!(parse_key("?"), Some(Key::Char('?')));
+        assert_eq!(parse_key("Ctrl-d"), Some(Key::Ctrl('d')));
+        assert_eq!(parse_key("ctrl-D"), Some(Key::Ctrl('d')));
+        assert_eq!(parse_key("Alt-<"), Some(Key::Alt('<')));
+        assert_eq!(parse_key("pagedown"), Some(Key::PageDown));
+        assert_eq!(parse_key("Enter"), Some(Key::Char('\n')));
+        assert_eq!(parse_key("F12"), Some(Key::F(12)));
+        assert_eq!(parse_key("F13"), None);
+        assert_eq!(parse_key("Ctrl-dd"), None);
+        assert_eq!(parse_key("Hyper-x"), None);
+        assert_eq!(parse_key(""), None);
+    }
+
+    #[test]
+    fn test_key_names_round_trip() {
+        for (name, _) in VIM.iter().chain(EMACS) {
+            let key = parse_key(name).unwrap();
+            assert_eq!(parse_key(&key_name(key)), Some(key));
+        }
+    }
+
+    #[test]
+    fn test_presets_bind_every_action() {
+        for preset in ["vim", "emacs"] {
+            let keymap = Keymap::from_settings(&KeymapSettings {
+                preset: String::from(preset),
+                ..Default::default()
+            })
+            .unwrap();
+            assert_eq!(keymap.help().len(), Action::ALL.len(), "{}", preset);
+        }
+    }
+
+    #[test]
+    fn test_vim_preset_keeps_the_old_bindings() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.action(Key::Char('j')), Some(Action::MoveDown));
+        assert_eq!(keymap.action(Key::Ctrl('u')), Some(Action::MoveUp5));
+        assert_eq!(keymap.action(Key::Char('x')), None);
+        assert_eq!(keymap.keys_for(Action::MoveDown), "j/Down");
+    }
+
+    #[test]
+    fn test_popup_keys() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.action(Key::Char('\n')), Some(Action::Select));
+        assert!(keymap.closes_popup(Key::Esc));
+        assert!(keymap.closes_popup(Key::Char('q')));
+        assert!(!keymap.closes_popup(Key::Char('d')));
+        assert_eq!(keymap.close_keys(), "Esc/q");
+
+        let emacs = Keymap::from_settings(&KeymapSettings {
+            preset: String::from("emacs"),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(emacs.close_keys(), "Ctrl-g/Esc/Ctrl-q");
+    }
+
+    #[test]
+    fn test_prompt_keys_dont_clash_with_the_table() {
+        let vim = Keymap::default();
+        assert_eq!(vim.prompt_action(Key::Ctrl('g')), Some(Action::Generate));
+        assert_eq!(vim.prompt_action(Key::Esc), Some(Action::Cancel));
+        assert_eq!(vim.action(Key::Esc), Some(Action::ClearSearch));
+        assert_eq!(vim.prompt_action(Key::Char('q')), None);
+
+        let emacs = Keymap::from_settings(&KeymapSettings {
+            preset: String::from("emacs"),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(emacs.prompt_action(Key::Ctrl('g')), Some(Action::Cancel));
+        assert_eq!(emacs.prompt_action(Key::Alt('g')), Some(Action::Generate));
+        assert_eq!(emacs.action(Key::Alt('g')), Some(Action::Refresh));
+        assert_eq!(emacs.action(Key::Ctrl('g')), Some(Action::ClearSearch));
+
+        let mut bindings = BTreeMap::new();
+        bindings.insert(String::from("Ctrl-g"), String::from("quit"));
+        let keymap = Keymap::from_settings(&KeymapSettings {
+            preset: String::from("emacs"),
+            bindings,
+        })
+        .unwrap();
+        assert_eq!(keymap.action(Key::Ctrl('g')), Some(Action::Quit));
+        assert_eq!(keymap.prompt_action(Key::Ctrl('g')), Some(Action::Cancel));
+    }
+
+    #[test]
+    fn test_bindings_override_the_preset() {
+        let mut bindings = BTreeMap::new();
+        bindings.insert(String::from("x"), String::from("delete_password"));
+        bindings.insert(String::from("D"), String::from("none"));
+        bindings.insert(String::from("j"), String::from("move_up"));
+        let keymap = Keymap::from_settings(&KeymapSettings {
+            preset: String::from("vim"),
+            bindings,
+        })
+        .unwrap();
+
+        assert_eq!(keymap.action(Key::Char('x')), Some(Action::DeletePassword));
+        assert_eq!(keymap.action(Key::Char('D')), None);
+        assert_eq!(keymap.keys_for(Action::DeletePassword), "x");
+        assert_eq!(keymap.keys_for(Action::MoveDown), "Down");
+        assert_eq!(keymap.keys_for(Action::MoveUp), "k/Up/j");
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let settings = |preset: &str, key: &str, action: &str| {
+            let mut bindings = BTreeMap::new();
+            bindings.insert(String::from(key), String::from(action));
+            KeymapSettings {
+                preset: String::from(preset),
+                bindings,
+            }
+        };
+        assert!(Keymap::from_settings(&settings("nano", "x", "quit")).is_err());
+        assert!(Keymap::from_settings(&settings("vim", "Super-x", "quit")).is_err());
+        assert!(Keymap::from_settings(&settings("vim", "x", "explode")).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+use crate::util::error::Error;
+use crate::util::json_utils::{unlock_vault, UnlockedVault};
+use crate::util::secret::SecretString;
+use std::time::{Duration, Instant};
+
+/// The screen shown once the TUI has locked itself, asking for the master key
+/// again before the passwords can be opened.
+pub struct LockScreen {
+    pub(crate) input: SecretString,
+    pub(crate) wrong_key: bool,
+    attempts: u8,
+    /// Set once the right key has been typed.
+    pub(crate) unlocked: Option<UnlockedVault>,
+    pub(crate) exit: bool,
+    /// The vault being opened, when it's shown after switching vaults rather
+    /// than after locking.
+    pub(crate) vault: Option<String>,
+}
+
+impl LockScreen {
+    pub fn new() -> LockScreen {
+        LockScreen {
+            input: SecretString::new(),
+            wrong_key: false,
+            attempts: 0,
+            unlocked: None,
+            exit: false,
+            vault: None,
+        }
+    }
+
+    /// Opens the vault with the typed key, the same way it's opened at startup.
+    /// Gives up and exits after `max_attempts` wrong keys.
+    pub fn unlock(&mut self, max_attempts: u8) -> Result<(), Error> {
+        let key = std::mem::take(&mut self.input);
+        if let Some(vault) = unlock_vault(key.expose())? {
+            self.unlocked = Some(vault);
+            return Ok(());
+        }
+
+        self.wrong_key = true;
+        self.attempts += 1;
+        if self.attempts >= max_attempts {
+            self.exit = true;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether the TUI has gone untouched for longer than `timeout`. A zero timeout
+/// never locks.
+pub fn is_idle(last_input: Instant, now: Instant, timeout: Duration) -> bool {
+    timeout > Duration::from_secs(0) && now.saturating_duration_since(last_input) >= timeout
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::json_utils::{check_files, read_passrc, read_passwords};
+    use crate::util::paths::TestDataDir;
+    use crate::util::utils::{encrypt_known, legacy_keygen, try_decrypt, EncryptionData};
+    use serde_json::json;
+    use std::fs;
+
+    #[test]
+    fn test_unlock_with_right_key() {
+        let _dir = TestDataDir::new("unlock");
+        check_files("testing987654321").unwrap();
+        let mut screen = LockScreen::new();
+        screen.input.push_str("testing987654321");
+        screen.unlock(3).unwrap();
+        assert!(screen.unlocked.is_some());
+        assert!(screen.input.is_empty());
+    }
+
+    #[test]
+    fn test_wrong_keys_exit_after_max_attempts() {
+        let _dir = TestDataDir::new("unlock-wrong");
+        check_files("testing987654321").unwrap();
+        let mut screen = LockScreen::new();
+
+        screen.input.push_str("wrong");
+        screen.unlock(2).unwrap();
+        assert!(screen.unlocked.is_none());
+        assert!(screen.wrong_key);
+        assert!(!screen.exit);
+
+        screen.input.push_str("still wrong");
+        screen.unlock(2).unwrap();
+        assert!(screen.exit);
+    }
+
+    #[test]
+    fn test_unlock_migrates_legacy_vault() {
+        let dir = TestDataDir::new("unlock-legacy");
+        check_files("testing987654321").unwrap();
+        let mut passrc = serde_json::to_value(read_passrc().unwrap()).unwrap();
+        passrc["key_derived"] = json!(false);
+        fs::write(dir.path().join("passrc.json"), passrc.to_string()).unwrap();
+        let legacy = legacy_keygen(b"testing987654321".to_vec()).unwrap().aead;
+        let password = encrypt_known(EncryptionData {
+            password: "test_pass",
+            nonce: "asdfjklqasdf",
+            key: &legacy,
+        });
+        fs::write(
+            dir.path().join("passwords.json"),
+            json!({"test_user": {"password": password, "nonce": "asdfjklqasdf"}}).to_string(),
+        )
+        .unwrap();
+
+        let mut screen = LockScreen::new();
+        screen.input.push_str("testing987654321");
+        screen.unlock(3).unwrap();
+        let key = screen.unlocked.unwrap().key;
+        let entry = &read_passwords(&key).unwrap()["test_user"];
+        let decrypted = try_decrypt(EncryptionData {
+            password: &entry.password,
+            nonce: &entry.nonce,
+            key: &key,
+        });
+        assert_eq!(decrypted.unwrap(), "test_pass");
+        assert!(read_passrc().unwrap().key_derived);
+    }
+
+    #[test]
+    fn test_is_idle() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(300);
+        assert!(!is_idle(now, now + Duration::from_secs(299), timeout));
+        assert!(is_idle(now, now + Duration::from_secs(300), timeout));
+        assert!(!is_idle(
+            now,
+            now + Duration::from_secs(3600),
+            Duration::from_secs(0)
+        ));
+    }
+}
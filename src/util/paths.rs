@@ -0,0 +1,237 @@
+use crate::util::error::Error;
+use lazy_static::lazy_static;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+/// Overrides the data directory, unless `--dir` is given.
+pub const DIR_ENV: &str = "PASSCURSES_DIR";
+/// Picks the vault to open, unless `--vault` is given.
+pub const VAULT_ENV: &str = "PASSCURSES_VAULT";
+/// The vault whose files sit directly in the data directory, as they did
+/// before there could be more than one.
+pub const DEFAULT_VAULT: &str = "default";
+
+/// Where the files are read from and written to. Set once at startup from the
+/// command line and environment, and again whenever the TUI switches vaults.
+#[derive(Debug, Default)]
+struct Location {
+    dir: Option<PathBuf>,
+    vault: Option<String>,
+}
+
+lazy_static! {
+    static ref LOCATION: RwLock<Location> = RwLock::new(Location::default());
+}
+
+/// Uses `dir` as the data directory instead of the usual one.
+pub fn set_data_dir(dir: PathBuf) {
+    if let Ok(mut location) = LOCATION.write() {
+        location.dir = Some(dir);
+    }
+}
+
+/// Switches to the vault called `name`. The name isn't checked against the
+/// vaults on disk, so a new one is created the first time it's opened.
+pub fn set_vault(name: &str) -> Result<(), Error> {
+    validate_vault_name(name)?;
+    if let Ok(mut location) = LOCATION.write() {
+        location.vault = Some(name.to_string());
+    }
+
+    Ok(())
+}
+
+/// The vault in use: the one last set, or `PASSCURSES_VAULT`, or the default.
+pub fn current_vault() -> String {
+    LOCATION
+        .read()
+        .ok()
+        .and_then(|location| location.vault.clone())
+        .or_else(|| std::env::var(VAULT_ENV).ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| String::from(DEFAULT_VAULT))
+}
+
+/// The directory holding `config.json` and the vaults.
+pub fn data_dir() -> Result<PathBuf, Error> {
+    if let Some(dir) = LOCATION.read().ok().and_then(|l| l.dir.clone()) {
+        return Ok(dir);
+    }
+
+    let legacy = dirs::home_dir().map(|home| home.join(".passcurses"));
+    resolve_data_dir(
+        std::env::var_os(DIR_ENV).map(PathBuf::from),
+        legacy.filter(|dir| dir.exists()),
+        dirs::data_dir(),
+    )
+}
+
+/// The directory holding the current vault's `passwords.json` and
+/// `passrc.json`.
+pub fn vault_dir() -> Result<PathBuf, Error> {
+    let vault = current_vault();
+    validate_vault_name(&vault)?;
+
+    Ok(vault_path(data_dir()?, &vault))
+}
+
+/// Every vault in the data directory that has been set up, default first.
+pub fn list_vaults() -> Result<Vec<String>, Error> {
+    let dir = data_dir()?;
+    let mut vaults = Vec::new();
+    if dir.join("passrc.json").exists() {
+        vaults.push(String::from(DEFAULT_VAULT));
+    }
+
+    let mut named: Vec<String> = match fs::read_dir(dir.join("vaults")) {
+        Ok(entries) => entries
+            .flatten()
+            .filter(|entry| entry.path().join("passrc.json").exists())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    named.sort();
+    vaults.extend(named);
+
+    Ok(vaults)
+}
+
+/// Picks the data directory: `PASSCURSES_DIR`, then `~/.passcurses` if it's
+/// already there, then `passcurses` in the XDG data directory.
+fn resolve_data_dir(
+    env_dir: Option<PathBuf>,
+    existing_legacy: Option<PathBuf>,
+    xdg_data: Option<PathBuf>,
+) -> Result<PathBuf, Error> {
+    env_dir
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .or(existing_legacy)
+        .or_else(|| xdg_data.map(|dir| dir.join("passcurses")))
+        .ok_or(Error::NoHomeDirectory)
+}
+
+/// A fresh data directory for a test, used in place of the real one until the
+/// guard is dropped. The location is global, so tests holding a guard take
+/// turns rather than reading and writing each other's files.
+#[cfg(test)]
+pub struct TestDataDir {
+    dir: PathBuf,
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+#[cfg(test)]
+impl TestDataDir {
+    pub fn new(name: &str) -> TestDataDir {
+        // A test that failed while holding the lock leaves nothing to clean up.
+        let lock = TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir =
+            std::env::temp_dir().join(format!("passcurses-data-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        if let Ok(mut location) = LOCATION.write() {
+            *location = Location {
+                dir: Some(dir.clone()),
+                vault: None,
+            };
+        }
+
+        TestDataDir { dir, _lock: lock }
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.dir
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDataDir {
+    fn drop(&mut self) {
+        if let Ok(mut location) = LOCATION.write() {
+            *location = Location::default();
+        }
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+#[inline]
+fn vault_path(data_dir: PathBuf, vault: &str) -> PathBuf {
+    if vault == DEFAULT_VAULT {
+        data_dir
+    } else {
+        data_dir.join("vaults").join(vault)
+    }
+}
+
+/// Vault names become directory names, so they're kept to something that can't
+/// point outside the data directory.
+fn validate_vault_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::Input(format!("Invalid vault name: {}", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_dir_wins() {
+        let dir = resolve_data_dir(
+            Some(PathBuf::from("/env")),
+            Some(PathBuf::from("/home/me/.passcurses")),
+            Some(PathBuf::from("/home/me/.local/share")),
+        );
+        assert_eq!(dir.unwrap(), PathBuf::from("/env"));
+    }
+
+    #[test]
+    fn test_existing_legacy_dir_before_xdg() {
+        let dir = resolve_data_dir(
+            None,
+            Some(PathBuf::from("/home/me/.passcurses")),
+            Some(PathBuf::from("/home/me/.local/share")),
+        );
+        assert_eq!(dir.unwrap(), PathBuf::from("/home/me/.passcurses"));
+    }
+
+    #[test]
+    fn test_xdg_dir_for_new_installs() {
+        let dir = resolve_data_dir(
+            Some(PathBuf::new()),
+            None,
+            Some(PathBuf::from("/home/me/.local/share")),
+        );
+        assert_eq!(
+            dir.unwrap(),
+            PathBuf::from("/home/me/.local/share/passcurses")
+        );
+        assert!(resolve_data_dir(None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_vault_path() {
+        let data = PathBuf::from("/data");
+        assert_eq!(vault_path(data.clone(), DEFAULT_VAULT), data);
+        assert_eq!(vault_path(data, "team"), PathBuf::from("/data/vaults/team"));
+    }
+
+    #[test]
+    fn test_vault_names() {
+        assert!(validate_vault_name("ops-2").is_ok());
+        assert!(validate_vault_name("").is_err());
+        assert!(validate_vault_name("..").is_err());
+        assert!(validate_vault_name("team/../../etc").is_err());
+    }
+}
@@ -0,0 +1,128 @@
+/// A row that matched the search query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    /// Index of the matching entry in the table's full list of items.
+    pub index: usize,
+    /// Char positions in the searched text that matched the query, for
+    /// highlighting.
+    pub positions: Vec<usize>,
+    score: i64,
+}
+
+/// Fuzzy matches `query` against `text`: every query character has to appear in
+/// `text` in order, ignoring case, but not necessarily next to each other.
+///
+/// Returns a score, higher being a better match, and the matched char positions.
+/// Runs of consecutive characters and matches at the start of a word score more,
+/// characters skipped over score less.
+pub fn fuzzy_match(query: &str, text: &str) -> Option<(i64, Vec<usize>)> {
+    let mut positions = Vec::new();
+    let mut score = 0;
+    let mut query_chars = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut previous: Option<char> = None;
+
+    for (i, c) in text.chars().enumerate() {
+        let wanted = match query_chars.peek() {
+            Some(wanted) => *wanted,
+            None => break,
+        };
+        if c.to_lowercase().eq(std::iter::once(wanted)) {
+            score += 1;
+            if positions.last().is_some_and(|last| last + 1 == i) {
+                score += 5;
+            }
+            if previous.map_or(true, |p| !p.is_alphanumeric()) {
+                score += 3;
+            }
+            positions.push(i);
+            query_chars.next();
+        } else {
+            // Skipped characters count against the match.
+            score -= 1;
+        }
+        previous = Some(c);
+    }
+
+    if query_chars.peek().is_some() {
+        None
+    } else {
+        Some((score, positions))
+    }
+}
+
+/// Matches `query` against every text, best match first. An empty query matches
+/// everything in the original order.
+pub fn filter<'a, I>(query: &str, texts: I) -> Vec<SearchMatch>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut matches: Vec<SearchMatch> = texts
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, text)| {
+            fuzzy_match(query, text).map(|(score, positions)| SearchMatch {
+                index,
+                positions,
+                score,
+            })
+        })
+        .collect();
+
+    // Stable, so equally good matches keep the table's order.
+    matches.sort_by_key(|m| std::cmp::Reverse(m.score));
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsequence_matches() {
+        let (_, positions) = fuzzy_match("gml", "gmail").unwrap();
+        assert_eq!(positions, vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn test_match_ignores_case() {
+        assert!(fuzzy_match("GIT", "github").is_some());
+        assert!(fuzzy_match("git", "GitHub").is_some());
+    }
+
+    #[test]
+    fn test_out_of_order_is_no_match() {
+        assert!(fuzzy_match("lmg", "gmail").is_none());
+        assert!(fuzzy_match("gmailx", "gmail").is_none());
+    }
+
+    #[test]
+    fn test_empty_query_matches_everything() {
+        assert_eq!(fuzzy_match("", "gmail"), Some((0, vec![])));
+    }
+
+    #[test]
+    fn test_consecutive_match_scores_higher() {
+        let (consecutive, _) = fuzzy_match("bank", "bank").unwrap();
+        let (spread, _) = fuzzy_match("bank", "b-a-n-k").unwrap();
+        assert!(consecutive > spread);
+    }
+
+    #[test]
+    fn test_filter_orders_best_first() {
+        let texts = ["ebay", "amazon-bank", "bank"];
+        let matches = filter("bank", texts.iter().copied());
+        let indices: Vec<usize> = matches.iter().map(|m| m.index).collect();
+        assert_eq!(indices, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_filter_empty_query_keeps_order() {
+        let texts = ["c", "a", "b"];
+        let indices: Vec<usize> = filter("", texts.iter().copied())
+            .iter()
+            .map(|m| m.index)
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use zeroize::Zeroize;
+
+/// Smallest buffer a secret starts with, so typing a password into a prompt
+/// rarely has to move it.
+const MIN_CAPACITY: usize = 64;
+
+lazy_static! {
+    /// How many live secrets have a buffer on each locked page, by the page's
+    /// address. `mlock` works on whole pages and doesn't nest, so a page is only
+    /// unlocked once the last secret on it is gone.
+    static ref LOCKED_PAGES: Mutex<HashMap<usize, usize>> = Mutex::new(HashMap::new());
+}
+
+/// A string holding a secret: a password, the master key or whatever is being
+/// typed into a prompt.
+///
+/// The buffer is locked into memory where the OS allows it, so it can't be
+/// swapped out, and is wiped before it's freed. Growing it moves the contents
+/// to a fresh buffer and wipes the old one, rather than leaving the allocator
+/// to copy it and free the original as is.
+pub struct SecretString {
+    inner: String,
+}
+
+impl SecretString {
+    pub fn new() -> SecretString {
+        SecretString::with_capacity(MIN_CAPACITY)
+    }
+
+    fn with_capacity(capacity: usize) -> SecretString {
+        let inner = String::with_capacity(capacity.max(MIN_CAPACITY));
+        lock_memory(&inner);
+        SecretString { inner }
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.inner
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn push(&mut self, c: char) {
+        self.reserve(c.len_utf8());
+        self.inner.push(c);
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.reserve(s.len());
+        self.inner.push_str(s);
+    }
+
+    /// Removes the last character, wiping the bytes it took up.
+    pub fn pop(&mut self) -> Option<char> {
+        let len = self.inner.len();
+        let c = self.inner.pop()?;
+
+        let mut bytes = std::mem::take(&mut self.inner).into_bytes();
+        // Resizing back up within the capacity overwrites the popped bytes in
+        // place without reallocating.
+        bytes.resize(len, 0);
+        bytes.truncate(len - c.len_utf8());
+        self.inner = String::from_utf8(bytes).unwrap_or_default();
+
+        Some(c)
+    }
+
+    /// Wipes the contents, keeping the (locked) buffer for reuse.
+    pub fn clear(&mut self) {
+        self.inner.zeroize();
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        let needed = self.inner.len() + additional;
+        if needed > self.inner.capacity() {
+            let mut grown = SecretString::with_capacity(needed.max(self.inner.capacity() * 2));
+            grown.inner.push_str(&self.inner);
+            // The old buffer is wiped and unlocked as it's dropped.
+            std::mem::swap(self, &mut grown);
+        }
+    }
+}
+
+impl Default for SecretString {
+    fn default() -> SecretString {
+        SecretString::new()
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.inner.zeroize();
+        unlock_memory(&self.inner);
+    }
+}
+
+/// Takes over a secret that arrived as a plain `String`, wiping the original.
+impl From<String> for SecretString {
+    fn from(mut s: String) -> SecretString {
+        let mut secret = SecretString::with_capacity(s.len());
+        secret.inner.push_str(&s);
+        s.zeroize();
+        secret
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(s: &str) -> SecretString {
+        let mut secret = SecretString::with_capacity(s.len());
+        secret.inner.push_str(s);
+        secret
+    }
+}
+
+impl Clone for SecretString {
+    fn clone(&self) -> SecretString {
+        SecretString::from(self.expose())
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool {
+        self.expose() == other.expose()
+    }
+}
+
+impl PartialEq<str> for SecretString {
+    fn eq(&self, other: &str) -> bool {
+        self.expose() == other
+    }
+}
+
+impl PartialEq<&str> for SecretString {
+    fn eq(&self, other: &&str) -> bool {
+        self.expose() == *other
+    }
+}
+
+/// Never prints the secret itself, so it can't end up in a log or panic message.
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretString(<{} bytes>)", self.inner.len())
+    }
+}
+
+/// Locks the pages `buffer` is on. Must be paired with `unlock_memory` on the
+/// same, still allocated, buffer.
+fn lock_memory(buffer: &String) {
+    let page = page_size();
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    for start in page_starts(buffer, page) {
+        let count = locked.entry(start).or_insert(0);
+        if *count == 0 {
+            // SAFETY: the page holds part of `buffer`'s allocation, so all of it
+            // is mapped. Locking never touches its contents, and failing to lock,
+            // e.g. past RLIMIT_MEMLOCK, still leaves the wiping.
+            unsafe {
+                libc::mlock(start as *const libc::c_void, page);
+            }
+        }
+        *count += 1;
+    }
+}
+
+/// Unlocks the pages `buffer` is on that no other secret needs any more. Must
+/// be called before the buffer is freed, as a page may be handed out again.
+fn unlock_memory(buffer: &String) {
+    let page = page_size();
+    let mut locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+    for start in page_starts(buffer, page) {
+        if let Entry::Occupied(mut count) = locked.entry(start) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+                // SAFETY: as in `lock_memory`, the buffer is still allocated so
+                // the page is mapped, and unlocking leaves its contents alone.
+                unsafe {
+                    libc::munlock(start as *const libc::c_void, page);
+                }
+            }
+        }
+    }
+}
+
+/// The address of each page `buffer`'s allocation is on.
+fn page_starts(buffer: &String, page: usize) -> impl Iterator<Item = usize> {
+    let start = buffer.as_ptr() as usize;
+    let first = start - start % page;
+    // An empty string has no allocation, just a dangling pointer.
+    let end = if buffer.capacity() == 0 {
+        first
+    } else {
+        start + buffer.capacity()
+    };
+
+    (first..end).step_by(page)
+}
+
+fn page_size() -> usize {
+    // SAFETY: sysconf only reads a system setting.
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as usize
+    } else {
+        4096
+    }
+}
+
+/// Stops the process from writing core dumps, which would contain whatever
+/// secrets are in memory at the time, and on Linux from being attached to by
+/// other processes of the same user.
+pub fn harden_process() {
+    let no_core = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: both calls only change limits on this process, and `no_core`
+    // outlives the call it's passed to.
+    unsafe {
+        libc::setrlimit(libc::RLIMIT_CORE, &no_core);
+        #[cfg(target_os = "linux")]
+        libc::prctl(libc::PR_SET_DUMPABLE, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop() {
+        let mut secret = SecretString::new();
+        secret.push_str("hunter");
+        secret.push('2');
+        assert_eq!(secret, "hunter2");
+
+        assert_eq!(secret.pop(), Some('2'));
+        assert_eq!(secret.pop(), Some('r'));
+        assert_eq!(secret, "hunte");
+    }
+
+    #[test]
+    fn test_pop_wipes_removed_bytes() {
+        let mut secret = SecretString::from("ab");
+        secret.pop();
+        // SAFETY: the buffer has at least `MIN_CAPACITY` bytes, all initialized.
+        let spare = unsafe { std::slice::from_raw_parts(secret.inner.as_ptr(), 2) };
+        assert_eq!(spare, b"a\0");
+    }
+
+    #[test]
+    fn test_clear_wipes_buffer() {
+        let mut secret = SecretString::from("hunter2");
+        let capacity = secret.inner.capacity();
+        secret.clear();
+        assert!(secret.is_empty());
+        assert_eq!(secret.inner.capacity(), capacity);
+        // SAFETY: `clear` zeroed the whole capacity, so it's all initialized.
+        let spare = unsafe { std::slice::from_raw_parts(secret.inner.as_ptr(), capacity) };
+        assert!(spare.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_grows_past_initial_capacity() {
+        let long = "x".repeat(MIN_CAPACITY * 3);
+        let mut secret = SecretString::new();
+        long.chars().for_each(|c| secret.push(c));
+        assert_eq!(secret, long.as_str());
+    }
+
+    #[test]
+    fn test_from_string_keeps_contents() {
+        let original = String::from("hunter2");
+        let secret = SecretString::from(original);
+        assert_eq!(secret, "hunter2");
+    }
+
+    #[test]
+    fn test_shared_page_stays_locked_until_last_secret_goes() {
+        let first = SecretString::from("hunter2");
+        let second = SecretString::from("hunter3");
+        drop(second);
+
+        let page = page_size();
+        let locked = LOCKED_PAGES.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(page_starts(&first.inner, page).all(|start| locked[&start] > 0));
+    }
+
+    #[test]
+    fn test_page_starts() {
+        let buffer = String::with_capacity(10);
+        let page = page_size();
+        let pages: Vec<usize> = page_starts(&buffer, page).collect();
+        assert!(!pages.is_empty() && pages.len() <= 2);
+        assert!(pages.iter().all(|start| start % page == 0));
+        assert_eq!(page_starts(&String::new(), page).count(), 0);
+    }
+
+    #[test]
+    fn test_debug_hides_secret() {
+        let secret = SecretString::from("hunter2");
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+    }
+}
@@ -1,78 +1,286 @@
+use crate::util::audit::{self, AuditRow, AuditSort};
+use crate::util::breach::BreachIndex;
+use crate::util::clipboard::{self, Clipboard, ClipboardTimer};
+use crate::util::error::Error;
+use crate::util::generator::{generate_passphrase, generate_password};
 use crate::util::inputs::{LeapDirection, MoveDirection};
-use crate::util::json_utils::{delete_password, read_passwords, write_new_password};
+use crate::util::json_utils::{
+    delete_password, edit_password, read_config, read_passrc, read_passwords, rekey,
+    restore_password, write_new_password, EntryDetails, PasswordEntry, PreviousPassword,
+};
+use crate::util::paths;
+use crate::util::search::{self, SearchMatch};
+use crate::util::secret::SecretString;
+use crate::util::totp::Totp;
 use crate::util::utils::{
-    build_table_rows, copy_to_clipboard, decrypt, encrypt_known, EncryptionData,
+    build_table_rows, decrypt_optional, format_date, parse_tags, try_decrypt, unix_now,
+    EncryptionData,
 };
 use aes_gcm::Aes128Gcm;
-use tui::text::Span;
-use tui::widgets::{Cell, Row, TableState};
-
-use std::convert::TryInto;
+use std::time::Instant;
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Cell, ListState, Row, TableState};
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CurrentMode {
     Normal,
     WithHelp,
     NewUserName,
+    NewLogin,
     NewPassword,
+    NewUrl,
+    NewTags,
+    NewNotes,
+    NewTotp,
+    /// Asking for the 2FA secret again, after one that didn't parse.
+    InvalidTotp,
     PasswordCreated,
     DeletePassword,
     PasswordDeleted,
     NoSuchPassword,
     PasswordExists,
+    Search,
+    EditUserName,
+    EditPassword,
+    ConfirmEdit,
+    PasswordEdited,
+    RekeyOldKey,
+    RekeyNewKey,
+    RekeyConfirmKey,
+    Rekeyed,
+    WrongKey,
+    KeyMismatch,
+    CopyFailed,
+    DecryptFailed,
+    NoTotp,
+    History,
+    NoHistory,
+    PasswordRestored,
+    Audit,
+    Error,
+    SwitchVault,
+    NoSuchVault,
+    VaultSwitched,
+    Locked,
     Exit,
 }
 
 pub enum EntryState {
     PasswordDeleted,
+    PasswordEdited,
+    PasswordExists,
     NoSuchPassword,
 }
 
-enum EncryptionMode {
-    ENCRYPT,
-    DECRYPT,
-}
-
+/// One row of the table, as read from `passwords.json`. The password stays
+/// encrypted for as long as the row exists; see `Revealed`.
 #[derive(Debug)]
 pub struct TableEntry {
     pub(crate) service: String,
-    pub(crate) password: String,
-    pub(crate) nonce: String,
+    pub(crate) username: String,
+    pub(crate) url: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) modified: u64,
+    /// Whether the password turned up in the breached passwords file.
+    pub(crate) breached: bool,
+    password: String,
+    nonce: String,
+    totp: String,
+    totp_nonce: String,
+    history: Vec<PreviousPassword>,
 }
 
 impl TableEntry {
-    pub fn new(service: String, password: String, nonce: String) -> Self {
+    pub fn new(service: String, entry: PasswordEntry) -> Self {
         Self {
             service,
-            password,
-            nonce,
+            username: entry.username,
+            url: entry.url,
+            tags: entry.tags,
+            modified: entry.modified,
+            breached: false,
+            password: entry.password,
+            nonce: entry.nonce,
+            totp: entry.totp,
+            totp_nonce: entry.totp_nonce,
+            history: entry.history,
         }
     }
 
-    pub fn to_cells(&self) -> Row {
+    pub(crate) fn has_totp(&self) -> bool {
+        !self.totp.is_empty()
+    }
+
+    /// Builds the row's cells, highlighting the characters of the service name
+    /// at `matched` positions and marking breached passwords. The revealed
+    /// password, and the 2FA code, are shown in place of the encrypted ones if
+    /// they belong to this row.
+    pub fn to_cells<'a>(
+        &'a self,
+        matched: &[usize],
+        revealed: Option<&'a Revealed>,
+        code: Option<&ShownCode>,
+    ) -> Row<'a> {
+        let password = match revealed {
+            Some(revealed) if revealed.service == self.service => revealed.password.expose(),
+            _ => &self.password,
+        };
+        let code = match code {
+            Some(code) if code.service == self.service => {
+                format!("{} ({}s)", code.code, code.seconds_left)
+            }
+            _ if self.has_totp() => String::from("******"),
+            _ => String::new(),
+        };
+
+        let mut service: Vec<Span> = Vec::new();
+        if self.breached {
+            service.push(Span::styled(
+                "! ",
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ));
+        }
+        service.extend(self.service.chars().enumerate().map(|(i, c)| {
+            if matched.contains(&i) {
+                Span::styled(
+                    c.to_string(),
+                    Style::default()
+                        .fg(Color::Yellow)
+                        .add_modifier(Modifier::BOLD),
+                )
+            } else {
+                Span::raw(c.to_string())
+            }
+        }));
+
         Row::new(
-            [&self.service, &self.password, &self.nonce]
-                .iter()
-                .map(|e| Cell::from(Span::raw(*e)))
-                .collect::<Vec<Cell>>(),
+            vec![
+                Spans::from(service),
+                Spans::from(self.username.as_str()),
+                Spans::from(self.url.as_str()),
+                Spans::from(self.tags.join(", ")),
+                Spans::from(format_date(self.modified)),
+                Spans::from(code),
+                Spans::from(password),
+            ]
+            .into_iter()
+            .map(Cell::from)
+            .collect::<Vec<Cell>>(),
         )
     }
+
+    fn decrypt(&self, key: &Aes128Gcm) -> Result<SecretString, Error> {
+        try_decrypt(EncryptionData {
+            password: &self.password,
+            nonce: &self.nonce,
+            key,
+        })
+        .map(SecretString::from)
+    }
+
+    fn decrypt_previous(&self, idx: usize, key: &Aes128Gcm) -> Result<SecretString, Error> {
+        try_decrypt(EncryptionData {
+            password: &self.history[idx].password,
+            nonce: &self.history[idx].nonce,
+            key,
+        })
+        .map(SecretString::from)
+    }
+
+    fn decrypt_totp(&self, key: &Aes128Gcm) -> Result<Option<Totp>, Error> {
+        if !self.has_totp() {
+            return Ok(None);
+        }
+        let uri = SecretString::from(decrypt_optional(&self.totp, &self.totp_nonce, key)?);
+
+        Totp::parse(uri.expose()).map(Some)
+    }
+}
+
+/// A decrypted password on show. It's kept apart from `items`, and tied to the
+/// service it was decrypted from, so it can't be drawn against another row or
+/// end up written back over the ciphertext.
+pub struct Revealed {
+    pub(crate) service: String,
+    pub(crate) password: SecretString,
+}
+
+/// The selected row's 2FA secret while its code is on show. The code itself is
+/// worked out afresh for every frame, so it changes over as the clock ticks.
+pub struct LiveTotp {
+    pub(crate) service: String,
+    pub(crate) totp: Totp,
+}
+
+/// A 2FA code as drawn, with the seconds it has left.
+pub struct ShownCode {
+    pub(crate) service: String,
+    pub(crate) code: String,
+    pub(crate) seconds_left: u64,
+}
+
+/// The previous passwords of one entry, while the history popup is open.
+pub struct HistoryView {
+    /// Index into `items` of the entry.
+    item: usize,
+    pub(crate) state: ListState,
+    /// A previous password on show, with its position in the history.
+    pub(crate) revealed: Option<(usize, SecretString)>,
+}
+
+pub struct HistoryUIDetails<'a> {
+    pub service: &'a str,
+    /// When each password was replaced, and the password itself if revealed.
+    pub rows: Vec<(String, Option<&'a str>)>,
+    pub state: &'a mut ListState,
+}
+
+/// The audit report, while it's on screen. Only the findings are kept, never
+/// the passwords decrypted to work them out.
+pub struct AuditView {
+    pub(crate) rows: Vec<AuditRow>,
+    pub(crate) sort: AuditSort,
+    pub(crate) state: TableState,
 }
 
 pub struct TableUIDetails<'a> {
     pub state: &'a mut TableState,
     pub items: &'a Vec<TableEntry>,
-    pub decrypted: &'a bool,
+    pub revealed: Option<&'a Revealed>,
+    pub code: Option<ShownCode>,
+    pub matches: Option<&'a Vec<SearchMatch>>,
+    pub search_query: &'a str,
+    /// Seconds until the clipboard is cleared, if a password is waiting to be.
+    pub clipboard_countdown: Option<u64>,
+    pub vault: String,
 }
 
 pub struct StatefulPasswordTable {
     pub(crate) current_mode: CurrentMode,
-    pub(crate) decrypted: bool,
-    pub(crate) input: String,
+    /// The selected row's decrypted password while it's revealed.
+    pub(crate) revealed: Option<Revealed>,
+    /// The selected row's 2FA secret while its code is shown.
+    pub(crate) live_totp: Option<LiveTotp>,
+    /// The selected row's previous passwords while they're listed.
+    pub(crate) history: Option<HistoryView>,
+    pub(crate) audit: Option<AuditView>,
+    pub(crate) input: SecretString,
     pub(crate) items: Vec<TableEntry>,
     pub(crate) key: Aes128Gcm,
     pub(crate) new_username: String,
-    pub(crate) new_password: String,
+    pub(crate) new_password: SecretString,
+    pub(crate) new_details: EntryDetails,
+    pub(crate) new_key: SecretString,
+    pub(crate) editing: String,
+    pub(crate) search_query: String,
+    /// The rows matching `search_query`, best first, while a search filter is
+    /// applied.
+    pub(crate) matches: Option<Vec<SearchMatch>>,
+    pub(crate) clipboard: Box<dyn Clipboard>,
+    pub(crate) clipboard_timer: Option<ClipboardTimer>,
+    /// What went wrong, while `CurrentMode::Error` shows it.
+    pub(crate) error: Option<Error>,
     pub(crate) state: TableState,
 }
 
@@ -80,102 +288,474 @@ impl StatefulPasswordTable {
     pub(crate) fn new(key: Aes128Gcm) -> StatefulPasswordTable {
         StatefulPasswordTable {
             current_mode: CurrentMode::Normal,
-            decrypted: false,
-            input: String::new(),
+            revealed: None,
+            live_totp: None,
+            history: None,
+            audit: None,
+            input: SecretString::new(),
             items: Vec::new(),
             key,
             new_username: String::new(),
-            new_password: String::new(),
+            new_password: SecretString::new(),
+            new_details: EntryDetails::default(),
+            new_key: SecretString::new(),
+            editing: String::new(),
+            search_query: String::new(),
+            matches: None,
+            clipboard: clipboard::from_settings(&read_config().unwrap_or_default().clipboard),
+            clipboard_timer: None,
+            error: None,
             state: TableState::default(),
         }
     }
 
     pub fn select(&mut self, direction: MoveDirection) {
-        self.re_encrypt();
+        self.hide_revealed();
+        if self.view_len() == 0 {
+            return;
+        }
         self.state.select(Some(match self.state.selected() {
             Some(i) => match direction {
-                MoveDirection::DOWN => (i + 1) % self.items.len(),
+                MoveDirection::DOWN => (i + 1) % self.view_len(),
                 MoveDirection::UP => self.decrement_wraparound(i),
             },
             None => match direction {
                 MoveDirection::DOWN => 0,
-                MoveDirection::UP => self.items.len() - 1,
+                MoveDirection::UP => self.view_len() - 1,
             },
         }));
     }
 
     pub fn move_by_5(&mut self, direction: MoveDirection) {
-        self.re_encrypt();
+        self.hide_revealed();
+        let len = self.view_len();
+        if len == 0 {
+            return;
+        }
         self.state.select(Some(match self.state.selected() {
             Some(i) => match direction {
-                MoveDirection::DOWN => {
-                    if i >= (self.items.len() - 5) {
-                        self.items.len() - 1
-                    } else {
-                        i + 5
-                    }
-                }
-                MoveDirection::UP => {
-                    if i < 5 {
-                        0
-                    } else {
-                        i - 5
-                    }
-                }
+                MoveDirection::DOWN => (i + 5).min(len - 1),
+                MoveDirection::UP => i.saturating_sub(5),
             },
             None => match direction {
-                MoveDirection::DOWN => 5,
-                MoveDirection::UP => self.items.len() - 5,
+                MoveDirection::DOWN => 5.min(len - 1),
+                MoveDirection::UP => len.saturating_sub(5),
             },
         }));
     }
 
     pub fn leap(&mut self, direction: LeapDirection) {
-        self.re_encrypt();
+        self.hide_revealed();
+        let len = self.view_len();
+        if len == 0 {
+            return;
+        }
         self.state.select(Some(match direction {
             LeapDirection::TOP => 0,
             LeapDirection::MIDDLE => {
-                if self.items.len() % 2 == 0 {
-                    (self.items.len() / 2) - 1
+                if len % 2 == 0 {
+                    (len / 2) - 1
                 } else {
-                    self.items.len() / 2
+                    len / 2
                 }
             }
-            LeapDirection::BOTTOM => self.items.len() - 1,
+            LeapDirection::BOTTOM => len - 1,
         }));
     }
 
+    /// Reveals the selected row's password, or hides it again.
     pub fn decrypt(&mut self) {
-        if self.items.is_empty() {
-            // So we don't subscript an array that's empty.
+        if self.revealed.is_some() {
+            self.hide_revealed();
+        } else if let Some(i) = self.selected_item() {
+            match self.decrypt_item(i) {
+                Ok(password) => {
+                    self.revealed = Some(Revealed {
+                        service: self.items[i].service.clone(),
+                        password,
+                    })
+                }
+                Err(_) => self.current_mode = CurrentMode::DecryptFailed,
+            }
+        }
+    }
+
+    pub fn copy(&mut self) {
+        if let Some(i) = self.selected_item() {
+            let password = match self.revealed.take() {
+                Some(revealed) if revealed.service == self.items[i].service => revealed.password,
+                _ => match self.decrypt_item(i) {
+                    Ok(password) => password,
+                    Err(_) => {
+                        self.current_mode = CurrentMode::DecryptFailed;
+                        return;
+                    }
+                },
+            };
+            self.copy_to_clipboard(password);
+        }
+    }
+
+    /// Shows the selected row's current 2FA code, or hides it again.
+    pub fn toggle_code(&mut self) {
+        if self.live_totp.is_some() {
+            self.live_totp = None;
+        } else if let Some(i) = self.selected_item() {
+            match self.items[i].decrypt_totp(&self.key) {
+                Ok(Some(totp)) => {
+                    self.live_totp = Some(LiveTotp {
+                        service: self.items[i].service.clone(),
+                        totp,
+                    })
+                }
+                Ok(None) => self.current_mode = CurrentMode::NoTotp,
+                Err(e) => self.show_error(e),
+            }
+        }
+    }
+
+    /// Copies the selected row's current 2FA code.
+    pub fn copy_code(&mut self) {
+        if let Some(i) = self.selected_item() {
+            let totp = match self.live_totp.take() {
+                Some(live) if live.service == self.items[i].service => live.totp,
+                _ => match self.items[i].decrypt_totp(&self.key) {
+                    Ok(Some(totp)) => totp,
+                    Ok(None) => {
+                        self.current_mode = CurrentMode::NoTotp;
+                        return;
+                    }
+                    Err(e) => return self.show_error(e),
+                },
+            };
+            self.copy_to_clipboard(SecretString::from(totp.code_at(unix_now())));
+        }
+    }
+
+    /// Lists the selected row's previous passwords.
+    pub fn open_history(&mut self) {
+        self.hide_revealed();
+        if let Some(i) = self.selected_item() {
+            if self.items[i].history.is_empty() {
+                self.current_mode = CurrentMode::NoHistory;
+                return;
+            }
+            let mut state = ListState::default();
+            state.select(Some(0));
+            self.history = Some(HistoryView {
+                item: i,
+                state,
+                revealed: None,
+            });
+            self.current_mode = CurrentMode::History;
+        }
+    }
+
+    pub fn close_history(&mut self) {
+        self.history = None;
+        self.current_mode = CurrentMode::Normal;
+    }
+
+    pub fn select_previous(&mut self, direction: MoveDirection) {
+        if let Some(view) = self.history.as_mut() {
+            let len = self.items[view.item].history.len();
+            let i = view.state.selected().unwrap_or(0);
+            view.state.select(Some(match direction {
+                MoveDirection::DOWN => (i + 1) % len,
+                MoveDirection::UP => (i + len - 1) % len,
+            }));
+            view.revealed = None;
+        }
+    }
+
+    /// Reveals the selected previous password, or hides it again.
+    pub fn toggle_previous(&mut self) {
+        let view = match self.history.as_mut() {
+            Some(view) => view,
+            None => return,
+        };
+        if view.revealed.take().is_some() {
             return;
         }
-        if let Some(i) = self.state.selected() {
-            let mode: EncryptionMode;
-            if self.decrypted {
-                self.decrypted = false;
-                mode = EncryptionMode::ENCRYPT;
-            } else {
-                self.decrypted = true;
-                mode = EncryptionMode::DECRYPT;
+        let i = view.state.selected().unwrap_or(0);
+        match self.items[view.item].decrypt_previous(i, &self.key) {
+            Ok(password) => view.revealed = Some((i, password)),
+            Err(_) => {
+                self.history = None;
+                self.current_mode = CurrentMode::DecryptFailed;
             }
-            self.items[i].password = self.encryption(mode, i);
+        }
+    }
+
+    /// Makes the selected previous password the entry's current one again.
+    pub fn restore_previous(&mut self) {
+        let view = match self.history.take() {
+            Some(view) => view,
+            None => return,
         };
+        let service = self.items[view.item].service.clone();
+        let i = view.state.selected().unwrap_or(0);
+        match restore_password(&service, i, &self.key) {
+            Ok(EntryState::PasswordEdited) => {
+                self.current_mode = CurrentMode::PasswordRestored;
+                self.refresh_table();
+            }
+            Ok(_) => self.current_mode = CurrentMode::NoSuchPassword,
+            Err(e) => self.show_error(e),
+        }
     }
 
-    pub fn copy(&mut self) {
-        if let Some(i) = self.state.selected() {
-            if self.decrypted {
-                if let Err(error) = copy_to_clipboard(&self.items[i].password) {
-                    panic!("Error copying to clipboard: {}", error);
-                }
-                self.decrypted = false;
-                self.items[i].password = self.encryption(EncryptionMode::ENCRYPT, i);
-            } else if let Err(error) =
-                copy_to_clipboard(&self.encryption(EncryptionMode::DECRYPT, i))
-            {
-                panic!("Error copying to clipboard: {}", error);
+    pub fn history_details(&mut self) -> Option<HistoryUIDetails<'_>> {
+        let HistoryView {
+            item,
+            state,
+            revealed,
+        } = self.history.as_mut()?;
+        let revealed = &*revealed;
+        let entry = &self.items[*item];
+        let rows = entry
+            .history
+            .iter()
+            .enumerate()
+            .map(move |(i, previous)| {
+                let password = match revealed {
+                    Some((revealed, password)) if *revealed == i => Some(password.expose()),
+                    _ => None,
+                };
+                (format_date(previous.replaced), password)
+            })
+            .collect();
+
+        Some(HistoryUIDetails {
+            service: &entry.service,
+            rows,
+            state,
+        })
+    }
+
+    /// Decrypts every entry and shows the audit report, riskiest first.
+    pub fn open_audit(&mut self) {
+        self.hide_revealed();
+        let decrypted = self
+            .items
+            .iter()
+            .map(|entry| {
+                entry
+                    .decrypt(&self.key)
+                    .map(|password| (entry.service.clone(), password, entry.modified))
+            })
+            .collect::<Result<Vec<_>, Error>>();
+        let decrypted = match decrypted {
+            Ok(decrypted) => decrypted,
+            Err(_) => {
+                self.current_mode = CurrentMode::DecryptFailed;
+                return;
             }
+        };
+
+        let max_age = read_config().unwrap_or_default().max_password_age;
+        let rows = BreachIndex::configured()
+            .and_then(|mut index| audit::audit(&decrypted, max_age, unix_now(), index.as_mut()));
+        let mut rows = match rows {
+            Ok(rows) => rows,
+            Err(e) => return self.show_error(e),
+        };
+        audit::sort(&mut rows, AuditSort::Risk);
+        let mut state = TableState::default();
+        if !rows.is_empty() {
+            state.select(Some(0));
+        }
+        self.audit = Some(AuditView {
+            rows,
+            sort: AuditSort::Risk,
+            state,
+        });
+        self.current_mode = CurrentMode::Audit;
+    }
+
+    pub fn close_audit(&mut self) {
+        self.audit = None;
+        self.current_mode = CurrentMode::Normal;
+    }
+
+    pub fn select_audited(&mut self, direction: MoveDirection) {
+        if let Some(view) = self.audit.as_mut() {
+            let len = view.rows.len();
+            if len == 0 {
+                return;
+            }
+            let i = view.state.selected().unwrap_or(0);
+            view.state.select(Some(match direction {
+                MoveDirection::DOWN => (i + 1) % len,
+                MoveDirection::UP => (i + len - 1) % len,
+            }));
+        }
+    }
+
+    /// Re-sorts the report by the next order, keeping the same row selected.
+    pub fn cycle_audit_sort(&mut self) {
+        if let Some(view) = self.audit.as_mut() {
+            let selected = view.state.selected().map(|i| view.rows[i].service.clone());
+            view.sort = view.sort.next();
+            audit::sort(&mut view.rows, view.sort);
+            view.state.select(
+                selected.and_then(|service| view.rows.iter().position(|r| r.service == service)),
+            );
+        }
+    }
+
+    /// Closes the report with the table's selection on the audited entry.
+    pub fn goto_audited(&mut self) {
+        let service = self
+            .audit
+            .as_ref()
+            .and_then(|view| view.state.selected().map(|i| view.rows[i].service.clone()));
+        self.close_audit();
+        if let Some(service) = service {
+            self.matches = None;
+            self.search_query.clear();
+            self.state
+                .select(self.items.iter().position(|entry| entry.service == service));
+        }
+    }
+
+    /// Marks the rows whose passwords turn up in the breached passwords file,
+    /// if one is set up.
+    pub fn mark_breaches(&mut self) {
+        let marked = match BreachIndex::configured() {
+            Ok(Some(mut index)) => self.mark_breaches_in(&mut index),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = marked {
+            self.show_error(e);
+        }
+    }
+
+    fn mark_breaches_in(&mut self, index: &mut BreachIndex) -> Result<(), Error> {
+        for i in 0..self.items.len() {
+            let password = self.decrypt_item(i)?;
+            self.items[i].breached = index.times_seen(password.expose())?.is_some();
+        }
+
+        Ok(())
+    }
+
+    fn copy_to_clipboard(&mut self, secret: SecretString) {
+        if self.clipboard.copy(secret.expose()).is_err() {
+            self.current_mode = CurrentMode::CopyFailed;
+            return;
+        }
+
+        let timeout = read_config().unwrap_or_default().clipboard_timeout;
+        self.clipboard_timer = if timeout.as_secs() > 0 {
+            Some(ClipboardTimer::new(secret, timeout, Instant::now()))
+        } else {
+            None
+        };
+    }
+
+    /// Clears the copied password from the clipboard once its timeout is up.
+    pub fn clear_expired_clipboard(&mut self, now: Instant) {
+        if self
+            .clipboard_timer
+            .as_ref()
+            .is_some_and(|timer| timer.is_expired(now))
+        {
+            self.clear_clipboard();
+        }
+    }
+
+    /// Hides everything that was revealed before the TUI locks itself: the
+    /// decrypted row, anything typed into a prompt and the copied password.
+    pub fn lock(&mut self) {
+        self.hide_revealed();
+        self.clear_inputs();
+        self.clear_clipboard();
+        self.current_mode = CurrentMode::Locked;
+    }
+
+    /// Cycles the input through the vaults there are to switch to.
+    pub fn next_vault_name(&mut self) {
+        let vaults = match paths::list_vaults() {
+            Ok(vaults) if !vaults.is_empty() => vaults,
+            Ok(_) => return,
+            Err(e) => return self.show_error(e),
+        };
+        let next = vaults
+            .iter()
+            .position(|v| self.input == v.as_str())
+            .map_or(0, |i| (i + 1) % vaults.len());
+        self.input = SecretString::from(vaults[next].as_str());
+    }
+
+    /// Switches to the vault named in the input. The table is locked on the
+    /// way out so the new vault's key has to be typed in before it opens.
+    pub fn switch_vault(&mut self) {
+        let name = self.input.expose().trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        self.input.clear();
+        if name == paths::current_vault() {
+            self.current_mode = CurrentMode::Normal;
+            return;
+        }
+
+        match paths::list_vaults() {
+            Ok(vaults) if vaults.contains(&name) => match paths::set_vault(&name) {
+                Ok(()) => {
+                    self.lock();
+                    self.current_mode = CurrentMode::VaultSwitched;
+                }
+                Err(e) => self.show_error(e),
+            },
+            Ok(_) => self.current_mode = CurrentMode::NoSuchVault,
+            Err(e) => self.show_error(e),
+        }
+    }
+
+    /// Clears the copied password from the clipboard straight away, e.g. on exit.
+    pub fn clear_clipboard(&mut self) {
+        if let Some(timer) = self.clipboard_timer.take() {
+            // Nothing more can be done if the clipboard has gone away.
+            let _ = timer.clear(self.clipboard.as_mut());
+        }
+    }
+
+    /// Enters search mode, starting from an empty query that matches every row.
+    pub fn start_search(&mut self) {
+        self.hide_revealed();
+        self.current_mode = CurrentMode::Search;
+        self.update_search();
+    }
+
+    /// Re-filters the rows against the search query and selects the best match.
+    pub fn update_search(&mut self) {
+        let matches = search::filter(
+            &self.search_query,
+            self.items.iter().map(|e| e.service.as_str()),
+        );
+        self.state
+            .select(if matches.is_empty() { None } else { Some(0) });
+        self.matches = Some(matches);
+    }
+
+    /// Drops the filter, keeping whichever entry was selected selected.
+    pub fn clear_search(&mut self) {
+        self.hide_revealed();
+        let selected = self.selected_item();
+        self.matches = None;
+        self.search_query.clear();
+        self.state.select(selected);
+        self.current_mode = CurrentMode::Normal;
+    }
+
+    /// Moves to the next or previous match, wrapping around. Only does anything
+    /// while a search filter is applied.
+    pub fn cycle_match(&mut self, direction: MoveDirection) {
+        if self.matches.is_some() {
+            self.select(direction);
         }
     }
 
@@ -187,9 +767,9 @@ impl StatefulPasswordTable {
             self.current_mode = CurrentMode::PasswordExists;
             self.input.clear();
         } else {
-            self.new_username.push_str(&self.input);
+            self.new_username.push_str(self.input.expose());
             self.input.clear();
-            self.current_mode = CurrentMode::NewPassword;
+            self.current_mode = CurrentMode::NewLogin;
         }
     }
 
@@ -197,38 +777,206 @@ impl StatefulPasswordTable {
         if self.input.is_empty() {
             // do nothing
         } else {
-            self.new_password.push_str(&self.input);
+            self.new_password.push_str(self.input.expose());
             self.input.clear();
-            self.current_mode = CurrentMode::PasswordCreated;
-
-            if !self.new_username.is_empty()
-                && !self.new_password.is_empty()
-                && write_new_password(
-                    self.new_username.drain(..).collect(),
-                    self.new_password.drain(..).collect(),
-                    &self.key,
-                )
-                .is_ok()
-            {
-                self.refresh_table();
+            self.current_mode = CurrentMode::NewUrl;
+        }
+    }
+
+    /// Stores the optional detail currently being prompted for, which may be
+    /// left empty, and moves on to the next prompt. The last one saves the entry.
+    /// An invalid 2FA secret is asked for again, keeping everything else typed.
+    pub fn new_detail(&mut self) {
+        // Notes and 2FA secrets stay secrets until they're encrypted.
+        let value = std::mem::take(&mut self.input);
+        match self.current_mode {
+            CurrentMode::NewLogin => {
+                self.new_details.username = value.expose().to_string();
+                self.current_mode = CurrentMode::NewPassword;
+            }
+            CurrentMode::NewUrl => {
+                self.new_details.url = value.expose().to_string();
+                self.current_mode = CurrentMode::NewTags;
+            }
+            CurrentMode::NewTags => {
+                self.new_details.tags = parse_tags(value.expose());
+                self.current_mode = CurrentMode::NewNotes;
+            }
+            CurrentMode::NewNotes => {
+                self.new_details.notes = value;
+                self.current_mode = CurrentMode::NewTotp;
+            }
+            CurrentMode::NewTotp | CurrentMode::InvalidTotp => {
+                if !value.is_empty() && Totp::parse(value.expose()).is_err() {
+                    self.current_mode = CurrentMode::InvalidTotp;
+                    return;
+                }
+                self.new_details.totp = value;
+                self.save_new_entry();
             }
+            _ => {}
         }
     }
 
+    fn save_new_entry(&mut self) {
+        self.current_mode = CurrentMode::PasswordCreated;
+
+        if !self.new_username.is_empty() && !self.new_password.is_empty() {
+            match write_new_password(
+                self.new_username.drain(..).collect(),
+                self.new_password.expose(),
+                std::mem::take(&mut self.new_details),
+                &self.key,
+            ) {
+                Ok(()) => self.refresh_table(),
+                Err(e) => self.show_error(e),
+            }
+        }
+        self.new_password.clear();
+    }
+
     pub fn delete_entry(&mut self) {
         if self.input.is_empty() {
             return;
         }
-        match delete_password(&self.input).unwrap() {
-            EntryState::PasswordDeleted => {
+        match delete_password(self.input.expose(), &self.key) {
+            Ok(EntryState::PasswordDeleted) => {
                 self.current_mode = CurrentMode::PasswordDeleted;
                 self.input.clear();
                 self.refresh_table();
             }
-            EntryState::NoSuchPassword => {
+            Ok(EntryState::NoSuchPassword) => {
                 self.current_mode = CurrentMode::NoSuchPassword;
                 self.input.clear();
             }
+            Ok(_) => {}
+            Err(e) => {
+                self.input.clear();
+                self.show_error(e);
+            }
+        }
+    }
+
+    /// Starts editing the selected entry, with its service name pre-filled.
+    pub fn start_edit(&mut self) {
+        self.hide_revealed();
+        if let Some(service) = self.selected_item().map(|i| self.items[i].service.clone()) {
+            self.input = SecretString::from(service.as_str());
+            self.editing = service;
+            self.current_mode = CurrentMode::EditUserName;
+        }
+    }
+
+    pub fn edit_username(&mut self) {
+        if self.input.is_empty() {
+            // do nothing
+        } else if self.input != self.editing.as_str() && self.is_service_present() {
+            self.current_mode = CurrentMode::PasswordExists;
+            self.clear_inputs();
+        } else {
+            self.new_username.push_str(self.input.expose());
+            self.input.clear();
+            self.current_mode = CurrentMode::EditPassword;
+        }
+    }
+
+    /// An empty password keeps the current one.
+    pub fn edit_password(&mut self) {
+        self.new_password.push_str(self.input.expose());
+        self.input.clear();
+        self.current_mode = CurrentMode::ConfirmEdit;
+    }
+
+    pub fn confirm_edit(&mut self) {
+        let new_password = if self.new_password.is_empty() {
+            None
+        } else {
+            Some(self.new_password.expose())
+        };
+
+        let edited = edit_password(
+            &self.editing,
+            self.new_username.drain(..).collect(),
+            new_password,
+            &self.key,
+        );
+        self.clear_inputs();
+        match edited {
+            Ok(EntryState::PasswordEdited) => {
+                self.current_mode = CurrentMode::PasswordEdited;
+                self.refresh_table();
+            }
+            Ok(EntryState::PasswordExists) => self.current_mode = CurrentMode::PasswordExists,
+            Ok(_) => self.current_mode = CurrentMode::NoSuchPassword,
+            Err(e) => self.show_error(e),
+        }
+    }
+
+    /// What `confirm_edit` is about to write, for the confirmation prompt.
+    pub fn edit_summary(&self) -> String {
+        format!(
+            "{} -> {}, {}",
+            self.editing,
+            self.new_username,
+            if self.new_password.is_empty() {
+                "password unchanged"
+            } else {
+                "new password"
+            }
+        )
+    }
+
+    pub fn check_current_key(&mut self) {
+        if self.input.is_empty() {
+            return;
+        }
+        match read_passrc() {
+            Ok(passrc) if passrc.verify(self.input.expose()) => {
+                self.current_mode = CurrentMode::RekeyNewKey
+            }
+            Ok(_) => self.current_mode = CurrentMode::WrongKey,
+            Err(e) => self.show_error(e),
+        }
+        self.input.clear();
+    }
+
+    pub fn new_key(&mut self) {
+        if self.input.is_empty() {
+            return;
+        }
+        self.new_key.push_str(self.input.expose());
+        self.input.clear();
+        self.current_mode = CurrentMode::RekeyConfirmKey;
+    }
+
+    pub fn confirm_new_key(&mut self) {
+        if self.input != self.new_key {
+            self.current_mode = CurrentMode::KeyMismatch;
+        } else {
+            match rekey(&self.key, self.new_key.expose()) {
+                Ok(key) => {
+                    self.key = key;
+                    self.current_mode = CurrentMode::Rekeyed;
+                    self.refresh_table();
+                }
+                Err(e) => self.show_error(e),
+            }
+        }
+        self.clear_inputs();
+    }
+
+    /// Replaces the input with a generated password, or passphrase, using the
+    /// generator settings in `config.json`.
+    pub fn generate_input(&mut self, passphrase: bool) {
+        let settings = read_config().unwrap_or_default().generator;
+        let generated = if passphrase {
+            generate_passphrase(&settings)
+        } else {
+            generate_password(&settings)
+        };
+        match generated {
+            Ok(generated) => self.input = SecretString::from(generated),
+            Err(e) => self.show_error(e),
         }
     }
 
@@ -236,59 +984,105 @@ impl StatefulPasswordTable {
         self.input.clear();
         self.new_username.clear();
         self.new_password.clear();
+        self.new_details = EntryDetails::default();
+        self.new_key.clear();
+        self.editing.clear();
     }
 
     pub fn refresh_table(&mut self) {
-        if let Ok(items) = read_passwords() {
-            if self.decrypted {
-                self.decrypted = !self.decrypted;
+        match read_passwords(&self.key) {
+            Ok(items) => {
+                self.hide_revealed();
+                self.items = build_table_rows(items);
+                self.mark_breaches();
+                if self.matches.is_some() {
+                    self.update_search();
+                }
             }
-            self.items = build_table_rows(items);
+            Err(e) => self.show_error(e),
         }
     }
 
-    pub fn ui_details(&mut self) -> TableUIDetails {
+    /// Shows `error` in a popup, leaving the table as it was underneath.
+    pub fn show_error(&mut self, error: Error) {
+        self.error = Some(error);
+        self.current_mode = CurrentMode::Error;
+    }
+
+    /// Closes the error popup.
+    pub fn dismiss_error(&mut self) {
+        self.error = None;
+        self.current_mode = CurrentMode::Normal;
+    }
+
+    pub fn ui_details(&mut self) -> TableUIDetails<'_> {
         TableUIDetails {
             state: &mut self.state,
             items: &self.items,
-            decrypted: &self.decrypted,
+            revealed: self.revealed.as_ref(),
+            code: self.live_totp.as_ref().map(|live| {
+                let now = unix_now();
+                ShownCode {
+                    service: live.service.clone(),
+                    code: live.totp.code_at(now),
+                    seconds_left: live.totp.seconds_left(now),
+                }
+            }),
+            matches: self.matches.as_ref(),
+            search_query: &self.search_query,
+            clipboard_countdown: self
+                .clipboard_timer
+                .as_ref()
+                .map(|timer| timer.seconds_left(Instant::now())),
+            vault: paths::current_vault(),
         }
     }
 
     pub fn pop_one_word(&mut self) {
-        let mut words: Vec<&str> = self.input.split_whitespace().collect();
+        let mut words: Vec<&str> = self.input.expose().split_whitespace().collect();
         words.pop();
-        if !words.is_empty() {
-            self.input = words.join(" ") + " ";
-        } else {
-            self.input = words.join(" ");
+        let mut kept = SecretString::new();
+        for word in words {
+            kept.push_str(word);
+            kept.push(' ');
         }
+        self.input = kept;
     }
 
-    fn encryption(&self, mode: EncryptionMode, idx: usize) -> String {
-        let data = EncryptionData {
-            password: &self.items[idx].password,
-            nonce: &self.items[idx].nonce,
-            key: &self.key,
-        };
-        match mode {
-            EncryptionMode::ENCRYPT => encrypt_known(data),
-            EncryptionMode::DECRYPT => decrypt(data),
-        }
+    fn decrypt_item(&self, idx: usize) -> Result<SecretString, Error> {
+        self.items[idx].decrypt(&self.key)
     }
 
-    fn re_encrypt(&mut self) {
-        if let Some(i) = self.state.selected() {
-            if self.decrypted {
-                self.decrypted = false;
-                self.items[i].password = self.encryption(EncryptionMode::ENCRYPT, i);
-            }
+    /// Number of rows currently shown, which is fewer than `items` while a
+    /// search filter is applied.
+    fn view_len(&self) -> usize {
+        self.matches
+            .as_ref()
+            .map_or(self.items.len(), |matches| matches.len())
+    }
+
+    /// Index into `items` of the selected row. The table state's selection is a
+    /// position in the filtered view, not in `items`.
+    fn selected_item(&self) -> Option<usize> {
+        let i = self.state.selected()?;
+        match &self.matches {
+            Some(matches) => matches.get(i).map(|m| m.index),
+            None if i < self.items.len() => Some(i),
+            None => None,
         }
     }
 
+    /// Hides the decrypted password, the 2FA code and the previous passwords,
+    /// if any are shown.
+    fn hide_revealed(&mut self) {
+        self.revealed = None;
+        self.live_totp = None;
+        self.history = None;
+    }
+
     fn is_service_present(&self) -> bool {
         for entry in &self.items {
-            if entry.service == self.input {
+            if entry.service == self.input.expose() {
                 return true;
             }
         }
@@ -301,26 +1095,26 @@ impl StatefulPasswordTable {
     ///
     /// Follows this formula:
     ///
-    /// ((index - 1) + k) % k
+    /// (index + k - 1) % k
     fn decrement_wraparound(&self, idx: usize) -> usize {
-        // Should handle the results of these `try_into`s properly
-        // but I don't think it's mathematically possible for None
-        // to come out of either of them. The value coming in should
-        // always be >= 0.
-        let len_isize: isize = self.items.len().try_into().unwrap();
+        // Adding k before subtracting keeps it from going below 0.
+        let len = self.view_len();
 
-        (((idx as isize - 1) + len_isize) % len_isize)
-            .try_into()
-            .unwrap()
+        (idx + len - 1) % len
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::json_utils::delete_password;
+    use crate::util::clipboard::{ClipboardSettings, FakeClipboard};
+    use crate::util::json_utils::write_new_passwords;
+    use crate::util::paths::TestDataDir;
+    use crate::util::utils::{encrypt, encrypt_known};
     use aes_gcm::{aead::generic_array::GenericArray, Aes128Gcm, NewAead};
-    use std::process::Command;
+    use sha1::{Digest, Sha1};
+    use std::fs;
+    use std::time::Duration;
 
     // Only need these implementations for tests.
     impl Default for TableEntry {
@@ -335,18 +1129,37 @@ mod tests {
 
             Self {
                 service: String::from("test_user"),
+                username: String::from("test_login"),
+                url: String::new(),
+                tags: Vec::new(),
+                modified: 0,
+                breached: false,
                 password,
                 nonce: String::from(nonce),
+                totp: String::new(),
+                totp_nonce: String::new(),
+                history: Vec::new(),
             }
         }
     }
 
+    /// An empty vault sealed under the test key, in a data directory of its own.
+    fn test_vault(name: &str) -> TestDataDir {
+        let dir = TestDataDir::new(name);
+        fs::write(dir.path().join("passwords.json"), "{}").unwrap();
+        write_new_passwords(Vec::new(), &StatefulPasswordTable::default().key).unwrap();
+        dir
+    }
+
     impl Default for StatefulPasswordTable {
         fn default() -> Self {
             Self {
                 current_mode: CurrentMode::Normal,
-                decrypted: false,
-                input: String::new(),
+                revealed: None,
+                live_totp: None,
+                history: None,
+                audit: None,
+                input: SecretString::new(),
                 items: vec![
                     TableEntry::default(),
                     TableEntry::default(),
@@ -354,7 +1167,15 @@ mod tests {
                 ],
                 key: Aes128Gcm::new(GenericArray::from_slice(b"testing987654321")),
                 new_username: String::new(),
-                new_password: String::new(),
+                new_password: SecretString::new(),
+                new_details: EntryDetails::default(),
+                new_key: SecretString::new(),
+                editing: String::new(),
+                search_query: String::new(),
+                matches: None,
+                clipboard: Box::new(FakeClipboard::default()),
+                clipboard_timer: None,
+                error: None,
                 state: TableState::default(),
             }
         }
@@ -524,23 +1345,9 @@ mod tests {
     }
 
     #[test]
-    fn test_encryption_encrypt() {
-        let mut table = StatefulPasswordTable::default();
-        table.state.select(Some(0));
-        table.decrypt();
-        assert_eq!(
-            table.encryption(EncryptionMode::ENCRYPT, 0),
-            String::from("Fw6SsG9VijR6gMioOvBRcaFzUkgbiGD/hw==")
-        );
-    }
-
-    #[test]
-    fn test_encryption_decrypt() {
+    fn test_decrypt_item() {
         let table = StatefulPasswordTable::default();
-        assert_eq!(
-            table.encryption(EncryptionMode::DECRYPT, 0),
-            String::from("test_pass")
-        );
+        assert_eq!(table.decrypt_item(0).unwrap(), "test_pass");
     }
 
     #[test]
@@ -564,35 +1371,224 @@ mod tests {
 
     #[test]
     fn test_copy_to_clipboard() {
-        let mut table = StatefulPasswordTable::default();
+        let _dir = TestDataDir::new("copy");
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            ..Default::default()
+        };
         table.state.select(Some(0));
         table.copy();
 
-        // Took me way too long to figure this out. Turns out making
-        // system calls is very slow and we have to wait for the
-        // copied password text to make it into `pbcopy`/`xclip` before
-        // we can paste it out with `pbpaste`/`xclip`.
-        std::thread::sleep(std::time::Duration::from_millis(5));
-
-        let result = if cfg!(target_os = "macos") {
-            Command::new("pbpaste")
-                .output()
-                .expect("pbpaste command failed!")
-                .stdout
-        } else {
-            Command::new("xclip")
-                .arg("-select")
-                .arg("clipboard")
-                .arg("-o")
-                .output()
-                .expect("xclip command failed!")
-                .stdout
+        assert_eq!(clipboard.contents(), "test_pass");
+        assert!(table.clipboard_timer.is_some());
+    }
+
+    #[test]
+    fn test_copy_decrypted_password() {
+        let _dir = TestDataDir::new("copy-decrypted");
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            ..Default::default()
         };
+        let stored = table.items[0].password.clone();
+        table.state.select(Some(0));
+        table.decrypt();
+        table.copy();
 
-        assert_eq!(
-            String::from_utf8(result).unwrap(),
-            String::from("test_pass")
-        );
+        assert_eq!(clipboard.contents(), "test_pass");
+        assert!(table.revealed.is_none());
+        assert_eq!(table.items[0].password, stored);
+    }
+
+    #[test]
+    fn test_copy_uses_selected_row_not_stale_reveal() {
+        let _dir = TestDataDir::new("copy-selected");
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            ..Default::default()
+        };
+        set_password(&mut table, 1, "other_pass");
+        table.items[1].service = String::from("other_user");
+        table.state.select(Some(0));
+        table.decrypt();
+        table.state.select(Some(1));
+        table.copy();
+
+        assert_eq!(clipboard.contents(), "other_pass");
+    }
+
+    #[test]
+    fn test_wrong_key_reports_decrypt_failure() {
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            key: Aes128Gcm::new(GenericArray::from_slice(b"notthekey0123456")),
+            ..Default::default()
+        };
+        table.state.select(Some(0));
+        table.decrypt();
+        assert_eq!(table.current_mode, CurrentMode::DecryptFailed);
+        assert!(table.revealed.is_none());
+
+        table.current_mode = CurrentMode::Normal;
+        table.copy();
+        assert_eq!(table.current_mode, CurrentMode::DecryptFailed);
+        assert_eq!(clipboard.contents(), "");
+        assert!(table.clipboard_timer.is_none());
+    }
+
+    #[test]
+    fn test_refresh_failure_shows_error_and_keeps_rows() {
+        let _dir = test_vault("refresh-failure");
+        let mut table = StatefulPasswordTable {
+            key: Aes128Gcm::new(GenericArray::from_slice(b"notthekey0123456")),
+            ..Default::default()
+        };
+        table.refresh_table();
+        assert_eq!(table.current_mode, CurrentMode::Error);
+        assert!(matches!(table.error, Some(Error::Decrypt)));
+        assert_eq!(table.items.len(), 3);
+
+        table.dismiss_error();
+        assert_eq!(table.current_mode, CurrentMode::Normal);
+        assert!(table.error.is_none());
+    }
+
+    #[test]
+    fn test_switch_to_unknown_vault() {
+        let _dir = TestDataDir::new("switch-vault");
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::SwitchVault,
+            input: SecretString::from("no-such-vault"),
+            ..Default::default()
+        };
+        table.switch_vault();
+        assert_eq!(table.current_mode, CurrentMode::NoSuchVault);
+        assert!(table.input.is_empty());
+        assert_eq!(paths::current_vault(), paths::DEFAULT_VAULT);
+    }
+
+    #[test]
+    fn test_revealing_never_changes_items() {
+        let _dir = TestDataDir::new("reveal");
+        let mut table = searchable_table();
+        let stored: Vec<(String, String)> = table
+            .items
+            .iter()
+            .map(|e| (e.password.clone(), e.nonce.clone()))
+            .collect();
+
+        table.state.select(Some(0));
+        table.decrypt();
+        table.select(MoveDirection::DOWN);
+        table.decrypt();
+        table.leap(LeapDirection::BOTTOM);
+        table.decrypt();
+        table.copy();
+        table.decrypt();
+        table.start_search();
+        table.search_query.push_str("bank");
+        table.update_search();
+        table.decrypt();
+        table.clear_search();
+        table.decrypt();
+        table.decrypt();
+
+        let after: Vec<(String, String)> = table
+            .items
+            .iter()
+            .map(|e| (e.password.clone(), e.nonce.clone()))
+            .collect();
+        assert_eq!(after, stored);
+    }
+
+    #[test]
+    fn test_copy_failure_shows_popup() {
+        let mut table = StatefulPasswordTable {
+            clipboard: clipboard::from_settings(&ClipboardSettings {
+                backend: String::from("command"),
+                copy_command: vec![String::from("psc-no-such-program")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        table.state.select(Some(0));
+        table.copy();
+
+        assert_eq!(table.current_mode, CurrentMode::CopyFailed);
+        assert!(table.clipboard_timer.is_none());
+    }
+
+    #[test]
+    fn test_clipboard_cleared_after_timeout() {
+        let _dir = TestDataDir::new("clipboard-cleared");
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            ..Default::default()
+        };
+        table.state.select(Some(0));
+        table.copy();
+
+        table.clear_expired_clipboard(Instant::now());
+        assert_eq!(clipboard.contents(), "test_pass");
+
+        table.clear_expired_clipboard(Instant::now() + Duration::from_secs(3600));
+        assert_eq!(clipboard.contents(), "");
+        assert!(table.clipboard_timer.is_none());
+    }
+
+    #[test]
+    fn test_clipboard_not_cleared_if_overwritten() {
+        let _dir = TestDataDir::new("clipboard-overwritten");
+        let mut clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            ..Default::default()
+        };
+        table.state.select(Some(0));
+        table.copy();
+
+        clipboard.copy("copied elsewhere").unwrap();
+        table.clear_clipboard();
+        assert_eq!(clipboard.contents(), "copied elsewhere");
+    }
+
+    #[test]
+    fn test_lock_hides_revealed_secrets() {
+        let _dir = TestDataDir::new("lock");
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            current_mode: CurrentMode::NewPassword,
+            input: SecretString::from("half typed"),
+            ..Default::default()
+        };
+        let stored = table.items[0].password.clone();
+        table.state.select(Some(0));
+        table.copy();
+        table.decrypt();
+        table.lock();
+
+        assert_eq!(table.current_mode, CurrentMode::Locked);
+        assert!(table.revealed.is_none());
+        assert_eq!(table.items[0].password, stored);
+        assert!(table.input.is_empty());
+        assert_eq!(clipboard.contents(), "");
+    }
+
+    #[test]
+    fn test_clipboard_countdown() {
+        let _dir = TestDataDir::new("clipboard-countdown");
+        let mut table = StatefulPasswordTable::default();
+        assert_eq!(table.ui_details().clipboard_countdown, None);
+
+        table.state.select(Some(0));
+        table.copy();
+        assert!(table.ui_details().clipboard_countdown.is_some());
     }
 
     #[test]
@@ -602,7 +1598,7 @@ mod tests {
         table.new_username();
         assert_eq!(table.new_username, "new_test_user");
         assert!(table.input.is_empty());
-        assert_eq!(table.current_mode, CurrentMode::NewPassword);
+        assert_eq!(table.current_mode, CurrentMode::NewLogin);
     }
 
     #[test]
@@ -611,11 +1607,496 @@ mod tests {
         table.input.push_str("new_test_password");
         table.new_username.push_str("new_test_user");
         table.new_password();
+        assert_eq!(table.current_mode, CurrentMode::NewUrl);
+        assert!(table.input.is_empty());
+        assert_eq!(table.new_password, "new_test_password");
+    }
+
+    #[test]
+    fn test_generate_input_replaces_input() {
+        let _dir = TestDataDir::new("generate");
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::NewPassword,
+            ..Default::default()
+        };
+        table.input.push_str("typed");
+        table.generate_input(false);
+        assert_ne!(table.input, "typed");
+        assert!(!table.input.is_empty());
+    }
+
+    #[test]
+    fn test_new_details_can_be_left_empty() {
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::NewLogin,
+            ..Default::default()
+        };
+        table.new_detail();
+        assert_eq!(table.current_mode, CurrentMode::NewPassword);
+        assert!(table.new_details.username.is_empty());
+    }
+
+    #[test]
+    fn test_new_tags_are_split_and_trimmed() {
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::NewTags,
+            ..Default::default()
+        };
+        table.input.push_str("work, email,, personal ");
+        table.new_detail();
+        assert_eq!(table.current_mode, CurrentMode::NewNotes);
+        assert_eq!(table.new_details.tags, vec!["work", "email", "personal"]);
+    }
+
+    #[test]
+    fn test_new_entry_is_saved_after_last_detail() {
+        let _dir = test_vault("new-entry");
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::NewNotes,
+            ..Default::default()
+        };
+        table.new_username.push_str("new_test_user");
+        table.new_password.push_str("new_test_password");
+        table.new_details.username.push_str("me@example.com");
+        table.input.push_str("some notes");
+        table.new_detail();
+        assert_eq!(table.current_mode, CurrentMode::NewTotp);
+        table.input.push_str("JBSWY3DPEHPK3PXP");
+        table.new_detail();
         assert_eq!(table.current_mode, CurrentMode::PasswordCreated);
         assert!(table.input.is_empty());
         assert!(table.new_username.is_empty());
         assert!(table.new_password.is_empty());
-        delete_password("new_test_user").unwrap();
+        assert!(table.new_details.username.is_empty());
+    }
+
+    fn searchable_table() -> StatefulPasswordTable {
+        let mut table = StatefulPasswordTable::default();
+        for (entry, service) in table
+            .items
+            .iter_mut()
+            .zip(["amazon", "bank", "github"].iter())
+        {
+            entry.service = service.to_string();
+        }
+        table
+    }
+
+    #[test]
+    fn test_search_filters_and_selects_best_match() {
+        let mut table = searchable_table();
+        table.start_search();
+        assert_eq!(table.view_len(), 3);
+        table.search_query.push_str("gh");
+        table.update_search();
+        assert_eq!(table.view_len(), 1);
+        assert_eq!(table.state.selected(), Some(0));
+        assert_eq!(table.selected_item(), Some(2));
+    }
+
+    #[test]
+    fn test_search_without_matches_selects_nothing() {
+        let mut table = searchable_table();
+        table.start_search();
+        table.search_query.push_str("zzz");
+        table.update_search();
+        assert_eq!(table.view_len(), 0);
+        assert_eq!(table.selected_item(), None);
+        table.select(MoveDirection::DOWN);
+        table.decrypt();
+        assert!(table.revealed.is_none());
+    }
+
+    #[test]
+    fn test_cycle_match_stays_within_filter() {
+        let mut table = searchable_table();
+        table.start_search();
+        table.search_query.push('a');
+        table.update_search();
+        assert_eq!(table.view_len(), 2);
+        let first = table.selected_item();
+        table.cycle_match(MoveDirection::DOWN);
+        assert_ne!(table.selected_item(), first);
+        table.cycle_match(MoveDirection::DOWN);
+        assert_eq!(table.selected_item(), first);
+    }
+
+    #[test]
+    fn test_cycle_match_without_search_does_nothing() {
+        let mut table = searchable_table();
+        table.cycle_match(MoveDirection::DOWN);
+        assert_eq!(table.state.selected(), None);
+    }
+
+    #[test]
+    fn test_clear_search_keeps_selected_entry() {
+        let mut table = searchable_table();
+        table.start_search();
+        table.search_query.push_str("bank");
+        table.update_search();
+        table.clear_search();
+        assert_eq!(table.current_mode, CurrentMode::Normal);
+        assert!(table.matches.is_none());
+        assert_eq!(table.state.selected(), Some(1));
+    }
+
+    #[test]
+    fn test_decrypt_uses_filtered_selection() {
+        let mut table = searchable_table();
+        table.start_search();
+        table.search_query.push_str("github");
+        table.update_search();
+        set_password(&mut table, 2, "github_pass");
+        table.decrypt();
+        let revealed = table.revealed.as_ref().unwrap();
+        assert_eq!(revealed.service, "github");
+        assert_eq!(revealed.password, "github_pass");
+        assert_ne!(table.items[2].password, "github_pass");
+    }
+
+    #[test]
+    fn test_start_edit_prefills_service() {
+        let mut table = StatefulPasswordTable::default();
+        table.state.select(Some(1));
+        table.start_edit();
+        assert_eq!(table.current_mode, CurrentMode::EditUserName);
+        assert_eq!(table.input, "test_user");
+        assert_eq!(table.editing, "test_user");
+    }
+
+    #[test]
+    fn test_start_edit_without_selection_does_nothing() {
+        let mut table = StatefulPasswordTable::default();
+        table.start_edit();
+        assert_eq!(table.current_mode, CurrentMode::Normal);
+        assert!(table.input.is_empty());
+    }
+
+    #[test]
+    fn test_edit_username_onto_existing_service() {
+        let mut table = StatefulPasswordTable::default();
+        table.items[2].service = String::from("other_user");
+        table.state.select(Some(0));
+        table.start_edit();
+        table.input = SecretString::from("other_user");
+        table.edit_username();
+        assert_eq!(table.current_mode, CurrentMode::PasswordExists);
+        assert!(table.editing.is_empty());
+    }
+
+    #[test]
+    fn test_edit_keeping_name_and_password() {
+        let mut table = StatefulPasswordTable::default();
+        table.state.select(Some(0));
+        table.start_edit();
+        table.edit_username();
+        assert_eq!(table.current_mode, CurrentMode::EditPassword);
+        table.edit_password();
+        assert_eq!(table.current_mode, CurrentMode::ConfirmEdit);
+        assert_eq!(
+            table.edit_summary(),
+            "test_user -> test_user, password unchanged"
+        );
+    }
+
+    #[test]
+    fn test_confirm_edit_renames_and_replaces_password() {
+        let _dir = test_vault("edit");
+        let mut table = StatefulPasswordTable::default();
+        write_new_password(
+            String::from("edit_test_user"),
+            "old_password",
+            EntryDetails::default(),
+            &table.key,
+        )
+        .unwrap();
+        table.editing.push_str("edit_test_user");
+        table.new_username.push_str("edited_test_user");
+        table.new_password.push_str("new_password");
+        table.confirm_edit();
+        assert_eq!(table.current_mode, CurrentMode::PasswordEdited);
+        assert!(table.editing.is_empty());
+
+        let map = read_passwords(&table.key).unwrap();
+        assert!(!map.contains_key("edit_test_user"));
+        let entry = &map["edited_test_user"];
+        let password = try_decrypt(EncryptionData {
+            password: &entry.password,
+            nonce: &entry.nonce,
+            key: &table.key,
+        })
+        .unwrap();
+        assert_eq!(password, "new_password");
+    }
+
+    #[test]
+    fn test_new_key() {
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::RekeyNewKey,
+            ..Default::default()
+        };
+        table.input.push_str("new_master_key");
+        table.new_key();
+        assert_eq!(table.new_key, "new_master_key");
+        assert!(table.input.is_empty());
+        assert_eq!(table.current_mode, CurrentMode::RekeyConfirmKey);
+    }
+
+    #[test]
+    fn test_confirm_new_key_mismatch() {
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::RekeyConfirmKey,
+            ..Default::default()
+        };
+        table.new_key.push_str("new_master_key");
+        table.input.push_str("new_master_kye");
+        table.confirm_new_key();
+        assert_eq!(table.current_mode, CurrentMode::KeyMismatch);
+        assert!(table.input.is_empty());
+        assert!(table.new_key.is_empty());
+    }
+
+    #[test]
+    fn test_entry_without_totp_reports_it() {
+        let mut table = StatefulPasswordTable::default();
+        table.state.select(Some(0));
+        table.toggle_code();
+        assert_eq!(table.current_mode, CurrentMode::NoTotp);
+        assert!(table.live_totp.is_none());
+    }
+
+    #[test]
+    fn test_show_and_hide_totp_code() {
+        let mut table = StatefulPasswordTable::default();
+        set_totp(&mut table, 0, "JBSWY3DPEHPK3PXP");
+        table.state.select(Some(0));
+        table.toggle_code();
+
+        let code = table.ui_details().code.unwrap();
+        assert_eq!(code.service, "test_user");
+        assert_eq!(code.code.len(), 6);
+        assert!((1..=30).contains(&code.seconds_left));
+
+        table.select(MoveDirection::DOWN);
+        assert!(table.live_totp.is_none());
+        assert!(table.ui_details().code.is_none());
+    }
+
+    #[test]
+    fn test_copy_totp_code() {
+        let _dir = TestDataDir::new("copy-totp");
+        let clipboard = FakeClipboard::default();
+        let mut table = StatefulPasswordTable {
+            clipboard: Box::new(clipboard.clone()),
+            ..Default::default()
+        };
+        set_totp(&mut table, 1, "JBSWY3DPEHPK3PXP");
+        table.state.select(Some(1));
+        table.copy_code();
+
+        let expected = Totp::parse("JBSWY3DPEHPK3PXP").unwrap();
+        let now = unix_now();
+        // The code may roll over between copying and checking.
+        assert!(
+            clipboard.contents() == expected.code_at(now)
+                || clipboard.contents() == expected.code_at(now - 1)
+        );
+        assert!(table.clipboard_timer.is_some());
+    }
+
+    #[test]
+    fn test_failed_rekey_shows_why() {
+        // An empty data directory, so there's no vault to re-key.
+        let _dir = TestDataDir::new("rekey-failed");
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::RekeyConfirmKey,
+            ..Default::default()
+        };
+        table.new_key.push_str("new master key");
+        table.input.push_str("new master key");
+        table.confirm_new_key();
+
+        assert_eq!(table.current_mode, CurrentMode::Error);
+        assert!(matches!(table.error, Some(Error::Io(_))));
+    }
+
+    #[test]
+    fn test_invalid_totp_is_asked_for_again() {
+        let mut table = StatefulPasswordTable {
+            current_mode: CurrentMode::NewTotp,
+            ..Default::default()
+        };
+        table.new_username.push_str("never_written");
+        table.new_password.push_str("hunter2");
+        table.new_details.notes = SecretString::from("some notes");
+        table.input.push_str("not base32!");
+        table.new_detail();
+
+        assert_eq!(table.current_mode, CurrentMode::InvalidTotp);
+        assert!(table.input.is_empty());
+        assert_eq!(table.new_username, "never_written");
+        assert_eq!(table.new_password, "hunter2");
+        assert_eq!(table.new_details.notes, "some notes");
+        assert!(table.new_details.totp.is_empty());
+    }
+
+    #[test]
+    fn test_entry_without_history_reports_it() {
+        let mut table = StatefulPasswordTable::default();
+        table.state.select(Some(0));
+        table.open_history();
+        assert_eq!(table.current_mode, CurrentMode::NoHistory);
+        assert!(table.history.is_none());
+    }
+
+    #[test]
+    fn test_browse_and_reveal_history() {
+        let mut table = StatefulPasswordTable::default();
+        add_previous(&mut table, 1, "older_pass", 1_600_000_000);
+        add_previous(&mut table, 1, "newer_pass", 1_700_000_000);
+        table.state.select(Some(1));
+        table.open_history();
+        assert_eq!(table.current_mode, CurrentMode::History);
+
+        table.toggle_previous();
+        let details = table.history_details().unwrap();
+        assert_eq!(details.rows[0].1, Some("newer_pass"));
+        assert_eq!(details.rows[1].1, None);
+
+        table.select_previous(MoveDirection::DOWN);
+        table.toggle_previous();
+        let details = table.history_details().unwrap();
+        assert_eq!(details.rows[0].1, None);
+        assert_eq!(details.rows[1].1, Some("older_pass"));
+
+        table.select_previous(MoveDirection::DOWN);
+        assert_eq!(table.history.as_ref().unwrap().state.selected(), Some(0));
+        table.close_history();
+        assert!(table.history.is_none());
+        assert_eq!(table.current_mode, CurrentMode::Normal);
+    }
+
+    #[test]
+    fn test_restore_previous_password() {
+        let _dir = test_vault("restore");
+        let mut table = StatefulPasswordTable::default();
+        write_new_password(
+            String::from("history_test_user"),
+            "first_password",
+            EntryDetails::default(),
+            &table.key,
+        )
+        .unwrap();
+        edit_password(
+            "history_test_user",
+            String::from("history_test_user"),
+            Some("second_password"),
+            &table.key,
+        )
+        .unwrap();
+        table.refresh_table();
+        let i = table
+            .items
+            .iter()
+            .position(|entry| entry.service == "history_test_user")
+            .unwrap();
+        table.state.select(Some(i));
+        table.open_history();
+        table.restore_previous();
+        assert_eq!(table.current_mode, CurrentMode::PasswordRestored);
+
+        let entry = &table.items[i];
+        assert_eq!(entry.decrypt(&table.key).unwrap(), "first_password");
+        assert_eq!(entry.history.len(), 1);
+        assert_eq!(
+            entry.decrypt_previous(0, &table.key).unwrap(),
+            "second_password"
+        );
+    }
+
+    #[test]
+    fn test_audit_report() {
+        let _dir = TestDataDir::new("audit");
+        let mut table = StatefulPasswordTable::default();
+        table.items[1].service = String::from("strong");
+        set_password(&mut table, 1, "v9#Lq2!xZr8@Kp4$Wm7&");
+        table.items[2].service = String::from("reused");
+        table.open_audit();
+        assert_eq!(table.current_mode, CurrentMode::Audit);
+
+        let view = table.audit.as_ref().unwrap();
+        assert_eq!(view.rows[0].service, "reused");
+        assert_eq!(view.rows[0].reused_with, vec![String::from("test_user")]);
+        assert_eq!(view.rows[2].service, "strong");
+        assert_eq!(view.rows[2].risk(), 0);
+        assert_eq!(view.state.selected(), Some(0));
+
+        table.cycle_audit_sort();
+        let view = table.audit.as_ref().unwrap();
+        assert_eq!(view.sort, AuditSort::Service);
+        assert_eq!(view.rows[view.state.selected().unwrap()].service, "reused");
+
+        table.select_audited(MoveDirection::DOWN);
+        table.goto_audited();
+        assert!(table.audit.is_none());
+        assert_eq!(table.current_mode, CurrentMode::Normal);
+        assert_eq!(table.state.selected(), Some(1));
+    }
+
+    #[test]
+    fn test_mark_breached_rows() {
+        let mut table = StatefulPasswordTable::default();
+        set_password(&mut table, 1, "v9#Lq2!xZr8@Kp4$Wm7&");
+        let hash: String = Sha1::digest(b"test_pass")
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("passcurses-mark-hibp-{}", std::process::id()));
+        std::fs::write(&path, format!("{}:3\n", hash)).unwrap();
+
+        let mut index = BreachIndex::open(&path).unwrap();
+        table.mark_breaches_in(&mut index).unwrap();
+        let marked: Vec<bool> = table.items.iter().map(|entry| entry.breached).collect();
+        assert_eq!(marked, vec![true, false, true]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_audit_with_wrong_key_fails() {
+        let mut table = StatefulPasswordTable {
+            key: Aes128Gcm::new(GenericArray::from_slice(b"wrongkey87654321")),
+            ..Default::default()
+        };
+        table.open_audit();
+        assert_eq!(table.current_mode, CurrentMode::DecryptFailed);
+        assert!(table.audit.is_none());
+    }
+
+    fn add_previous(table: &mut StatefulPasswordTable, idx: usize, password: &str, replaced: u64) {
+        let (encrypted, nonce) = encrypt(password, &table.key).unwrap();
+        table.items[idx].history.insert(
+            0,
+            PreviousPassword {
+                password: base64::encode(encrypted),
+                nonce,
+                replaced,
+            },
+        );
+    }
+
+    fn set_totp(table: &mut StatefulPasswordTable, idx: usize, secret: &str) {
+        let (totp, totp_nonce) = encrypt(secret, &table.key).unwrap();
+        table.items[idx].totp = base64::encode(totp);
+        table.items[idx].totp_nonce = totp_nonce;
+    }
+
+    fn set_password(table: &mut StatefulPasswordTable, idx: usize, password: &str) {
+        table.items[idx].password = encrypt_known(EncryptionData {
+            password,
+            nonce: &table.items[idx].nonce,
+            key: &table.key,
+        });
     }
 
     fn more_table_entries(num: u8) -> Vec<TableEntry> {
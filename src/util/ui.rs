@@ -1,11 +1,12 @@
+use crate::util::audit::AuditRow;
 use crate::util::banner::BANNER;
 use crate::util::configs::CursesConfigs;
-use crate::util::stateful_table::{CurrentMode, TableUIDetails};
+use crate::util::keymap::{Action, Keymap};
+use crate::util::lock::LockScreen;
+use crate::util::stateful_table::{AuditView, CurrentMode, HistoryUIDetails, TableUIDetails};
 
 use std::io::Stdout;
 
-use lazy_static::lazy_static;
-
 use termion::input::MouseTerminal;
 use termion::raw::RawTerminal;
 use termion::screen::AlternateScreen;
@@ -20,18 +21,48 @@ use tui::Frame;
 pub type Backend = TermionBackend<AlternateScreen<MouseTerminal<RawTerminal<Stdout>>>>;
 pub type HelpList = Vec<ListItem<'static>>;
 
-static NEW_USERNAME_TITLE: &str = "Enter a new username. Press Esc to cancel";
-static NEW_PASSWORD_TITLE: &str = "Enter a new password. Press Esc to cancel";
-static DELETE_PASSWORD: &str = "Enter username of password to delete. Press Esc to cancel";
+// The prompts' titles get the keys to cancel, or generate, added on the end.
+static NEW_USERNAME_TITLE: &str = "Enter a new service name";
+static NEW_LOGIN_TITLE: &str = "Enter the login username, or leave empty";
+static NEW_PASSWORD_TITLE: &str = "Enter a new password";
+static NEW_URL_TITLE: &str = "Enter the URL, or leave empty";
+static NEW_TAGS_TITLE: &str = "Enter comma separated tags, or leave empty";
+static NEW_NOTES_TITLE: &str = "Enter notes, or leave empty";
+static NEW_TOTP_TITLE: &str = "Enter a 2FA secret or otpauth:// URI, or leave empty";
+static INVALID_TOTP_TITLE: &str =
+    "Not a valid 2FA secret! Enter base32 or an otpauth:// URI, or leave empty";
+static DELETE_PASSWORD: &str = "Enter service of password to delete";
 static PASSWORD_CREATED: &str = "Password created! Press any key to close";
 static PASSWORD_DELETED: &str = "Password deleted! Press any key to close";
 static NO_SUCH_PASSWORD: &str = "No such password! Press any key to close";
 static PASSWORD_EXISTS: &str = "Password already exists for this service! Press any key to close";
+static EDIT_USERNAME: &str = "Edit the service name";
+static EDIT_PASSWORD: &str = "Enter a new password, or leave empty to keep it";
+static CONFIRM_EDIT: &str = "Overwrite this entry? y to confirm, n to cancel";
+static PASSWORD_EDITED: &str = "Password updated! Press any key to close";
+static REKEY_OLD_KEY: &str = "Enter your current key";
+static REKEY_NEW_KEY: &str = "Enter a new key";
+static REKEY_CONFIRM_KEY: &str = "Enter the new key again";
+static REKEYED: &str = "Key changed! Press any key to close";
+static WRONG_KEY: &str = "Wrong key! Press any key to close";
+static KEY_MISMATCH: &str = "Keys don't match! Press any key to close";
+static COPY_FAILED: &str = "Unable to copy to the clipboard! Press any key to close";
+static DECRYPT_FAILED: &str =
+    "Unable to decrypt this password with the current key! Press any key to close";
+static NO_TOTP: &str = "This entry has no 2FA secret! Press any key to close";
+static NO_HISTORY: &str = "This entry has no previous passwords! Press any key to close";
+static PASSWORD_RESTORED: &str = "Password restored! Press any key to close";
+static ERROR: &str = "Something went wrong! Press any key to close";
+static SWITCH_VAULT: &str = "Enter a vault name, Tab to cycle through them";
+static NO_SUCH_VAULT: &str = "No such vault! Press any key to close";
+static UNLOCK_VAULT: &str = "Enter the key for this vault, or Esc to go back";
+static UNLOCK_VAULT_WRONG_KEY: &str = "Wrong key! Enter the key for this vault, or Esc to go back";
+static LOCKED: &str = "Locked after inactivity. Enter your key, or Esc to quit";
+static LOCKED_WRONG_KEY: &str = "Wrong key! Enter your key, or Esc to quit";
 static BOX_WIDTH: u16 = 70;
 static BOX_HEIGHT: u16 = 20;
 
 static HELP_PROMPT_HEIGHT: u16 = 3;
-static HELP_BOX_HEIGHT: u16 = 16;
 
 // static ADD_DEL_PASSWORD_BOX_WIDTH: u16 = BOX_WIDTH;
 // static ADD_DEL_PASSWORD_BOX_HEIGHT: u16 = 8;
@@ -39,48 +70,28 @@ static HELP_BOX_HEIGHT: u16 = 16;
 static BANNER_LEN: u16 = 70;
 static BANNER_HEIGHT: u16 = 10;
 
-static BUTTONS: [&str; 14] = [
-    "j/down", "k/up", "Ctrl-d", "Ctrl-u", "g", "G", "M", "y", "d", "r", "c", "D", "?", "q",
-];
-static EFFECTS: [&str; 14] = [
-    "move down",
-    "move up",
-    "move down x5",
-    "move up x5",
-    "jump to top",
-    "jump to bottom",
-    "Jump to middle",
-    "copy password",
-    "decrypt the password",
-    "refresh passwords",
-    "create new password",
-    "delete password",
-    "hide/show help",
-    "quit",
-];
-
 static HELP_MSG_SPACING: usize = 40;
 
-lazy_static! {
-    static ref HELP_MESSAGES: HelpList = {
-        BUTTONS
-            .iter()
-            .zip(EFFECTS.iter())
-            .map(|(b, e)| {
-                let main_str = format!(
-                    "{} {:.<spacing$} {}",
-                    b,
-                    ".",
-                    e,
-                    spacing = (HELP_MSG_SPACING - e.len()) - b.len()
-                );
-                ListItem::new(Text::styled(
-                    format!("{:^69}", main_str),
-                    Style::default().add_modifier(Modifier::ITALIC),
-                ))
-            })
-            .collect::<HelpList>()
-    };
+/// The help window's lines, one per bound action, so it always matches the
+/// keys in use.
+fn help_messages(keymap: &Keymap) -> HelpList {
+    keymap
+        .help()
+        .iter()
+        .map(|(b, e)| {
+            let main_str = format!(
+                "{} {:.<spacing$} {}",
+                b,
+                ".",
+                e,
+                spacing = HELP_MSG_SPACING.saturating_sub(e.len() + b.len()).max(1)
+            );
+            ListItem::new(Text::styled(
+                format!("{:^69}", main_str),
+                Style::default().add_modifier(Modifier::ITALIC),
+            ))
+        })
+        .collect::<HelpList>()
 }
 
 /// Draws the main view including the password table and, optionally, the banner.
@@ -90,7 +101,7 @@ pub fn draw_table(
     f: &mut Frame<Backend>,
     show_banner: Option<bool>,
 ) {
-    let highlight_colour = if *table_details.decrypted {
+    let highlight_colour = if table_details.revealed.is_some() {
         Color::Green
     } else {
         Color::Red
@@ -137,9 +148,25 @@ pub fn draw_table(
             height: f.size().height - 3,
         });
 
-    let rows: Vec<_> = table_details.items.iter().map(|i| i.to_cells()).collect();
+    let revealed = table_details.revealed;
+    let code = table_details.code.as_ref();
+    let rows: Vec<_> = match table_details.matches {
+        Some(matches) => matches
+            .iter()
+            .map(|m| table_details.items[m.index].to_cells(&m.positions, revealed, code))
+            .collect(),
+        None => table_details
+            .items
+            .iter()
+            .map(|i| i.to_cells(&[], revealed, code))
+            .collect(),
+    };
 
-    let header_cells = ["Username", "Password"].iter().map(|h| {
+    let header_cells = [
+        "Service", "Username", "URL", "Tags", "Modified", "2FA", "Password",
+    ]
+    .iter()
+    .map(|h| {
         Cell::from(*h).style(
             Style::default()
                 .add_modifier(Modifier::UNDERLINED)
@@ -154,14 +181,22 @@ pub fn draw_table(
         .block(
             Block::default()
                 .title(Span::styled(
-                    "Passwords",
+                    format!("Passwords [{}]", table_details.vault),
                     Style::default().add_modifier(cfg.title_style),
                 ))
                 .borders(Borders::ALL)
                 .border_style(Style::default().add_modifier(cfg.border_style)),
         )
         .highlight_style(Style::default().fg(Color::Black).bg(highlight_colour))
-        .widths(&[Constraint::Percentage(50), Constraint::Percentage(50)])
+        .widths(&[
+            Constraint::Percentage(20),
+            Constraint::Percentage(15),
+            Constraint::Percentage(20),
+            Constraint::Percentage(15),
+            Constraint::Length(10),
+            Constraint::Length(14),
+            Constraint::Percentage(30),
+        ])
         .style(Style::default().fg(Color::White))
         .column_spacing(1);
 
@@ -177,8 +212,23 @@ pub fn draw_table(
             height: HELP_PROMPT_HEIGHT,
         });
 
+    let keys = |action| cfg.keymap.keys_for(action);
+    let mut status = match table_details.matches {
+        Some(matches) => format!(
+            "/{}  ({} matches, {}/{} to cycle, {} to clear)",
+            table_details.search_query,
+            matches.len(),
+            keys(Action::NextMatch),
+            keys(Action::PreviousMatch),
+            keys(Action::ClearSearch)
+        ),
+        None => format!("{} for help", keys(Action::Help)),
+    };
+    if let Some(seconds) = table_details.clipboard_countdown {
+        status.push_str(&format!("  |  clipboard clears in {}s", seconds));
+    }
     let text = vec![Span::styled(
-        "? for help",
+        status,
         Style::default().add_modifier(Modifier::BOLD),
     )];
     let block = Block::default().borders(Borders::ALL);
@@ -189,28 +239,103 @@ pub fn draw_table(
     f.render_widget(paragraph, rects_2[0]);
 }
 
-/// Draws the help window.
-pub fn draw_help_window(f: &mut Frame<Backend>) {
+/// Draws the help window, listing the keys of the active keymap.
+pub fn draw_help_window(f: &mut Frame<Backend>, keymap: &Keymap) {
+    let messages = help_messages(keymap);
+    let y = (f.size().height / 2).saturating_sub(BOX_HEIGHT / 2);
     let rects = Layout::default()
         .constraints([Constraint::Percentage(100)].as_ref())
         .split(Rect {
             x: (f.size().width / 2) - BOX_WIDTH / 2,
-            y: ((f.size().height / 2) - BOX_HEIGHT / 2),
+            y,
             width: BOX_WIDTH,
-            height: HELP_BOX_HEIGHT,
+            // The list and its borders, as much of it as fits.
+            height: (messages.len() as u16 + 2).min(f.size().height - y),
         });
 
-    let help = List::new(HELP_MESSAGES.as_ref())
-        .block(Block::default().borders(Borders::ALL).title("Help"));
+    let help = List::new(messages).block(Block::default().borders(Borders::ALL).title("Help"));
 
     f.render_widget(help, rects[0]);
 }
 
+/// Draws the audit report over the whole screen, one row per entry.
+pub fn draw_audit(f: &mut Frame<Backend>, view: &mut AuditView, cfg: &CursesConfigs) {
+    let area = Layout::default()
+        .constraints([Constraint::Percentage(100)].as_ref())
+        .margin(1)
+        .split(f.size())[0];
+
+    let count = |issue: fn(&AuditRow) -> bool| view.rows.iter().filter(|r| issue(r)).count();
+    let keys = |action| cfg.keymap.keys_for(action);
+    let title = format!(
+        "Audit: {} breached, {} weak, {} reused, {} old. Sorted by {}, {} to sort, {} to go to entry, {} to close",
+        count(AuditRow::is_breached),
+        count(AuditRow::is_weak),
+        count(AuditRow::is_reused),
+        count(|r| r.old),
+        view.sort.name(),
+        keys(Action::SortAudit),
+        keys(Action::Select),
+        cfg.keymap.close_keys()
+    );
+
+    let rows: Vec<Row> = view
+        .rows
+        .iter()
+        .map(|row| {
+            let style = match row.risk() {
+                0 => Style::default().fg(Color::White),
+                1 => Style::default().fg(Color::Yellow),
+                _ => Style::default().fg(Color::Red),
+            };
+            Row::new(vec![
+                Cell::from(row.service.as_str()),
+                Cell::from(format!("{:.0} bits", row.bits)),
+                Cell::from(row.age()),
+                Cell::from(row.issues()),
+            ])
+            .style(style)
+        })
+        .collect();
+    let header = Row::new(["Service", "Strength", "Age", "Issues"].iter().map(|h| {
+        Cell::from(*h).style(
+            Style::default()
+                .add_modifier(Modifier::UNDERLINED)
+                .add_modifier(cfg.title_style),
+        )
+    }))
+    .style(Style::default().fg(Color::Yellow));
+
+    let t = Table::new(rows)
+        .header(header)
+        .block(
+            Block::default()
+                .title(Span::styled(
+                    title,
+                    Style::default().add_modifier(cfg.title_style),
+                ))
+                .borders(Borders::ALL)
+                .border_style(Style::default().add_modifier(cfg.border_style)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .widths(&[
+            Constraint::Percentage(25),
+            Constraint::Length(10),
+            Constraint::Length(8),
+            Constraint::Percentage(60),
+        ])
+        .column_spacing(1);
+
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(t, area, &mut view.state);
+}
+
 /// Draws the input box for adding/deleting a new password.
 pub fn draw_add_delete_password(
     f: &mut Frame<Backend>,
     current_mode: &CurrentMode,
     table_input: &str,
+    keymap: &Keymap,
 ) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -233,15 +358,73 @@ pub fn draw_add_delete_password(
 
     let title = match current_mode {
         CurrentMode::NewUserName => NEW_USERNAME_TITLE,
+        CurrentMode::NewLogin => NEW_LOGIN_TITLE,
         CurrentMode::NewPassword => NEW_PASSWORD_TITLE,
+        CurrentMode::NewUrl => NEW_URL_TITLE,
+        CurrentMode::NewTags => NEW_TAGS_TITLE,
+        CurrentMode::NewNotes => NEW_NOTES_TITLE,
+        CurrentMode::NewTotp => NEW_TOTP_TITLE,
+        CurrentMode::InvalidTotp => INVALID_TOTP_TITLE,
         CurrentMode::DeletePassword => DELETE_PASSWORD,
         CurrentMode::PasswordDeleted => PASSWORD_DELETED,
         CurrentMode::PasswordCreated => PASSWORD_CREATED,
         CurrentMode::NoSuchPassword => NO_SUCH_PASSWORD,
         CurrentMode::PasswordExists => PASSWORD_EXISTS,
+        CurrentMode::EditUserName => EDIT_USERNAME,
+        CurrentMode::EditPassword => EDIT_PASSWORD,
+        CurrentMode::ConfirmEdit => CONFIRM_EDIT,
+        CurrentMode::PasswordEdited => PASSWORD_EDITED,
+        CurrentMode::RekeyOldKey => REKEY_OLD_KEY,
+        CurrentMode::RekeyNewKey => REKEY_NEW_KEY,
+        CurrentMode::RekeyConfirmKey => REKEY_CONFIRM_KEY,
+        CurrentMode::Rekeyed => REKEYED,
+        CurrentMode::WrongKey => WRONG_KEY,
+        CurrentMode::KeyMismatch => KEY_MISMATCH,
+        CurrentMode::CopyFailed => COPY_FAILED,
+        CurrentMode::DecryptFailed => DECRYPT_FAILED,
+        CurrentMode::NoTotp => NO_TOTP,
+        CurrentMode::NoHistory => NO_HISTORY,
+        CurrentMode::PasswordRestored => PASSWORD_RESTORED,
+        CurrentMode::Error => ERROR,
+        CurrentMode::SwitchVault => SWITCH_VAULT,
+        CurrentMode::NoSuchVault => NO_SUCH_VAULT,
         _ => "UNKNOWN MODE",
     };
-    let text = Text::styled(table_input, Style::default());
+    let keys = |action| keymap.keys_for(action);
+    let title = match current_mode {
+        CurrentMode::NewPassword | CurrentMode::EditPassword => format!(
+            "{}. {}/{} to generate, {} to cancel",
+            title,
+            keys(Action::Generate),
+            keys(Action::GeneratePassphrase),
+            keys(Action::Cancel)
+        ),
+        CurrentMode::NewUserName
+        | CurrentMode::NewLogin
+        | CurrentMode::NewUrl
+        | CurrentMode::NewTags
+        | CurrentMode::NewNotes
+        | CurrentMode::NewTotp
+        | CurrentMode::InvalidTotp
+        | CurrentMode::DeletePassword
+        | CurrentMode::EditUserName
+        | CurrentMode::RekeyOldKey
+        | CurrentMode::RekeyNewKey
+        | CurrentMode::RekeyConfirmKey
+        | CurrentMode::SwitchVault => {
+            format!("{}. Press {} to cancel", title, keys(Action::Cancel))
+        }
+        _ => String::from(title),
+    };
+    // Master keys are never echoed back.
+    let masked = "*".repeat(table_input.chars().count());
+    let shown = match current_mode {
+        CurrentMode::RekeyOldKey | CurrentMode::RekeyNewKey | CurrentMode::RekeyConfirmKey => {
+            &masked
+        }
+        _ => table_input,
+    };
+    let text = Text::styled(shown, Style::default());
     let input = Paragraph::new(text)
         .style(
             Style::default()
@@ -258,3 +441,93 @@ pub fn draw_add_delete_password(
     f.render_widget(Clear, chunks[1]); // Clears the background of the popup.
     f.render_widget(input, chunks[1]);
 }
+
+/// Draws the popup listing an entry's previous passwords, newest first.
+pub fn draw_history(f: &mut Frame<Backend>, details: HistoryUIDetails, cfg: &CursesConfigs) {
+    let height = (details.rows.len() as u16 + 2).min(f.size().height);
+    let area = Rect {
+        x: (f.size().width / 2).saturating_sub(BOX_WIDTH / 2),
+        y: (f.size().height / 2).saturating_sub(height / 2),
+        width: BOX_WIDTH.min(f.size().width),
+        height,
+    };
+
+    let items: Vec<ListItem> = details
+        .rows
+        .iter()
+        .enumerate()
+        .map(|(i, (replaced, password))| {
+            ListItem::new(format!(
+                "{:>3}  replaced {}  {}",
+                i + 1,
+                replaced,
+                password.unwrap_or("********")
+            ))
+        })
+        .collect();
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().add_modifier(cfg.border_style))
+                .title(Span::styled(
+                    format!(
+                        "{}: {} to show, {} to restore, {} to close",
+                        details.service,
+                        cfg.keymap.keys_for(Action::RevealPassword),
+                        cfg.keymap.keys_for(Action::Select),
+                        cfg.keymap.close_keys()
+                    ),
+                    Style::default().add_modifier(cfg.title_style),
+                )),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(list, area, details.state);
+}
+
+/// Draws the lock screen: the banner and a masked prompt for the master key.
+pub fn draw_lock_screen(f: &mut Frame<Backend>, screen: &LockScreen, cfg: &CursesConfigs) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(2)
+        .constraints(
+            [
+                Constraint::Length(BANNER_HEIGHT),
+                Constraint::Length(3),
+                Constraint::Min(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    let banner = Paragraph::new(Text::raw(BANNER))
+        .style(Style::default().fg(Color::Red).add_modifier(Modifier::BOLD))
+        .alignment(Alignment::Center);
+    f.render_widget(banner, chunks[0]);
+
+    let title = match &screen.vault {
+        Some(vault) if screen.wrong_key => format!("[{}] {}", vault, UNLOCK_VAULT_WRONG_KEY),
+        Some(vault) => format!("[{}] {}", vault, UNLOCK_VAULT),
+        None if screen.wrong_key => String::from(LOCKED_WRONG_KEY),
+        None => String::from(LOCKED),
+    };
+    let masked = "*".repeat(screen.input.expose().chars().count());
+    let input = Paragraph::new(Text::styled(masked, Style::default()))
+        .style(
+            Style::default()
+                .bg(Color::DarkGray)
+                .add_modifier(Modifier::BOLD),
+        )
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .border_style(Style::default().add_modifier(cfg.border_style))
+                .title(Span::styled(
+                    title,
+                    Style::default().add_modifier(cfg.title_style),
+                )),
+        );
+    f.render_widget(input, chunks[1]);
+}
@@ -1,17 +1,18 @@
 use std::collections::HashMap;
-use std::error::Error;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use aes_gcm::aead::{generic_array::GenericArray, Aead};
 use aes_gcm::{Aes128Gcm, NewAead};
+use argon2::{Config, Variant};
 
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 
 use base64::{decode, encode};
+use zeroize::Zeroize;
 
-use super::json_utils::PasswordEntry;
+use super::json_utils::{PasswordEntry, PreviousPassword};
+use crate::util::error::Error;
 use crate::util::stateful_table::TableEntry;
 
 pub struct EncryptionData<'a> {
@@ -30,80 +31,86 @@ where
 impl AesWrapper<Aes128Gcm> {
     pub fn new(key: &[u8]) -> Self {
         Self {
-            aead: Aes128Gcm::new(&GenericArray::clone_from_slice(&key))
+            aead: Aes128Gcm::new(&GenericArray::clone_from_slice(key)),
         }
     }
 }
 
+/// Derives the AES key from the master password with Argon2id, salted with the
+/// `salt` stored in `passrc.json`.
 #[inline]
-pub fn keygen(mut key: Vec<u8>) -> Result<AesWrapper<Aes128Gcm>, &'static str> {
-    if key.len() < 16 {
-        // Padding.
-        let diff = 16 - key.len();
-        (0..diff).for_each(|_| key.push(0));
-        Ok(AesWrapper::new(&key))
-    } else if key.len() > 16 {
-        Err("Key is too long!")
-    } else {
-        Ok(AesWrapper::new(&key))
+pub fn keygen(key: &[u8], salt: &[u8]) -> Result<AesWrapper<Aes128Gcm>, argon2::Error> {
+    let config = Config {
+        variant: Variant::Argon2id,
+        hash_length: 16,
+        ..Config::default()
+    };
+
+    Ok(AesWrapper::new(&argon2::hash_raw(key, salt, &config)?))
+}
+
+/// The key scheme used before Argon2 derivation: the master password itself,
+/// zero-padded to 16 bytes. Only needed to migrate older vaults.
+#[inline]
+pub fn legacy_keygen(mut key: Vec<u8>) -> Result<AesWrapper<Aes128Gcm>, Error> {
+    if key.len() > 16 {
+        key.zeroize();
+        return Err(Error::Input(String::from("Key is too long!")));
     }
+
+    // Padding, in place so no copies of the key are left behind.
+    let mut padded = [0_u8; 16];
+    padded[..key.len()].copy_from_slice(&key);
+    let wrapper = AesWrapper::new(&padded);
+    padded.zeroize();
+    key.zeroize();
+
+    Ok(wrapper)
 }
 
 #[inline]
 pub fn build_table_rows(map: HashMap<String, PasswordEntry>) -> Vec<TableEntry> {
     let mut entries = map
         .into_iter()
-        .map(|(k, v)| TableEntry::new(k, v.password, v.nonce))
+        .map(|(k, v)| TableEntry::new(k, v))
         .collect::<Vec<TableEntry>>();
 
-    entries.sort_by(|a, b| a.service.partial_cmp(&b.service).unwrap());
+    entries.sort_by(|a, b| a.service.cmp(&b.service));
 
     entries
 }
 
-#[inline]
-pub fn copy_to_clipboard(string_to_copy: &str) -> Result<(), Box<dyn Error>> {
-    let process = if cfg!(target_os = "macos") {
-        Command::new("pbcopy").stdin(Stdio::piped()).spawn()?
-    } else {
-        Command::new("xclip")
-            .arg("-select")
-            .arg("clipboard")
-            .stdin(Stdio::piped())
-            .spawn()?
-    };
-
-    if let Err(e) = process
-        .stdin
-        .ok_or("Couldn't unwrap stdin.")?
-        .write_all(string_to_copy.as_bytes())
-    {
-        panic!("Couldn't copy to clipboard: {}", e);
-    }
-
-    Ok(())
+/// Splits a comma separated list of tags, dropping blanks.
+pub fn parse_tags(tags: &str) -> Vec<String> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(String::from)
+        .collect()
 }
 
 #[inline]
-pub fn encrypt(password: &str, aead: &Aes128Gcm) -> (Vec<u8>, String) {
+pub fn encrypt(password: &str, aead: &Aes128Gcm) -> Result<(Vec<u8>, String), Error> {
     let nonce: Vec<u8> = rand::thread_rng()
         .sample_iter(&Alphanumeric)
         .take(12)
         .collect();
 
     let cipher_text = aead
-        .encrypt(&mut GenericArray::from_slice(&nonce), password.as_bytes())
-        .unwrap();
+        .encrypt(GenericArray::from_slice(&nonce), password.as_bytes())
+        .map_err(|_| Error::Encrypt)?;
 
-    (cipher_text, String::from_utf8(nonce).unwrap())
+    Ok((cipher_text, String::from_utf8(nonce)?))
 }
 
-#[inline]
+/// Encrypts under a given nonce. Reusing a nonce under the same key breaks
+/// AES-GCM, so this is only for building test fixtures.
+#[cfg(test)]
 pub fn encrypt_known(data: EncryptionData) -> String {
     encode(
         data.key
             .encrypt(
-                &mut GenericArray::from_slice(data.nonce.as_bytes()),
+                GenericArray::from_slice(data.nonce.as_bytes()),
                 data.password.as_bytes(),
             )
             .unwrap(),
@@ -111,33 +118,152 @@ pub fn encrypt_known(data: EncryptionData) -> String {
 }
 
 #[inline]
-pub fn decrypt(data: EncryptionData) -> String {
-    let decoded_password = decode(data.password.as_bytes()).unwrap();
-    if let Ok(decrypted) = data.key.decrypt(
-        &mut GenericArray::from_slice(data.nonce.as_bytes()),
-        decoded_password.as_ref(),
-    ) {
-        String::from_utf8(decrypted).unwrap()
-    } else {
-        "Wrong login key for this password!".into()
+pub fn try_decrypt(data: EncryptionData) -> Result<String, Error> {
+    let decoded_password = decode(data.password.as_bytes())?;
+    let decrypted = data
+        .key
+        .decrypt(
+            GenericArray::from_slice(data.nonce.as_bytes()),
+            decoded_password.as_ref(),
+        )
+        .map_err(|_| Error::Decrypt)?;
+
+    Ok(String::from_utf8(decrypted)?)
+}
+
+/// Encrypts an optional field, like the notes, under its own nonce. Empty text
+/// stays empty, with no nonce.
+pub fn encrypt_optional(text: &str, key: &Aes128Gcm) -> Result<(String, String), Error> {
+    if text.is_empty() {
+        return Ok((String::new(), String::new()));
+    }
+    let (cipher_text, nonce) = encrypt(text, key)?;
+
+    Ok((encode(cipher_text), nonce))
+}
+
+/// Decrypts a field written by `encrypt_optional`.
+pub fn decrypt_optional(text: &str, nonce: &str, key: &Aes128Gcm) -> Result<String, Error> {
+    if text.is_empty() {
+        return Ok(String::new());
+    }
+
+    try_decrypt(EncryptionData {
+        password: text,
+        nonce,
+        key,
+    })
+}
+
+/// Decrypts every entry with `old_key` and re-encrypts it under `new_key` with a
+/// fresh nonce. Fails without touching anything if any entry won't decrypt.
+pub fn rekey_entries(
+    map: HashMap<String, PasswordEntry>,
+    old_key: &Aes128Gcm,
+    new_key: &Aes128Gcm,
+) -> Result<HashMap<String, PasswordEntry>, Error> {
+    map.into_iter()
+        .map(|(service, entry)| Ok((service, rekey_entry(entry, old_key, new_key)?)))
+        .collect()
+}
+
+/// Re-encrypts one entry, its notes, 2FA secret and history, from `old_key`
+/// to `new_key`.
+pub fn rekey_entry(
+    entry: PasswordEntry,
+    old_key: &Aes128Gcm,
+    new_key: &Aes128Gcm,
+) -> Result<PasswordEntry, Error> {
+    let password = try_decrypt(EncryptionData {
+        password: &entry.password,
+        nonce: &entry.nonce,
+        key: old_key,
+    })?;
+    let (encrypted_pwd, pwd_nonce) = encrypt(&password, new_key)?;
+    let notes = decrypt_optional(&entry.notes, &entry.notes_nonce, old_key)?;
+    let (notes, notes_nonce) = encrypt_optional(&notes, new_key)?;
+    let totp = decrypt_optional(&entry.totp, &entry.totp_nonce, old_key)?;
+    let (totp, totp_nonce) = encrypt_optional(&totp, new_key)?;
+    let history = entry
+        .history
+        .iter()
+        .map(|previous| {
+            let password = try_decrypt(EncryptionData {
+                password: &previous.password,
+                nonce: &previous.nonce,
+                key: old_key,
+            })?;
+            let (encrypted, nonce) = encrypt(&password, new_key)?;
+            Ok(PreviousPassword {
+                password: encode(encrypted),
+                nonce,
+                replaced: previous.replaced,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(PasswordEntry {
+        notes,
+        notes_nonce,
+        totp,
+        totp_nonce,
+        history,
+        ..PasswordEntry::new(encode(encrypted_pwd), pwd_nonce)
     }
+    .with_metadata_of(entry))
 }
 
 #[inline]
-pub fn verify_dev() -> bool {
-    let encrypted_password = match std::env::var_os("PASSCURSES_ENC_DEV_PASSWORD") {
-        Some(value) => value.into_string().unwrap(),
-        None => String::new(),
-    };
-    let raw_password = match std::env::var_os("PASSCURSES_RAW_DEV_PASSWORD") {
-        Some(value) => value.into_string().unwrap(),
-        None => String::new(),
-    };
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Formats a unix timestamp as a `YYYY-MM-DD` UTC date, or `-` if it's unknown.
+pub fn format_date(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return String::from("-");
+    }
+
+    // Days to civil date, from Howard Hinnant's `civil_from_days`.
+    let z = (timestamp / 86_400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[inline]
+pub fn verify_dev() -> Result<bool, Error> {
+    let encrypted_password = dev_env_var("PASSCURSES_ENC_DEV_PASSWORD")?;
+    let raw_password = dev_env_var("PASSCURSES_RAW_DEV_PASSWORD")?;
 
     if !encrypted_password.is_empty() {
-        argon2::verify_encoded(&encrypted_password, raw_password.as_bytes()).unwrap()
+        Ok(argon2::verify_encoded(
+            &encrypted_password,
+            raw_password.as_bytes(),
+        )?)
     } else {
-        false
+        Ok(false)
+    }
+}
+
+/// An unset variable reads as empty.
+#[inline]
+fn dev_env_var(name: &str) -> Result<String, Error> {
+    match std::env::var_os(name) {
+        Some(value) => value
+            .into_string()
+            .map_err(|_| Error::Input(format!("{} is not valid unicode", name))),
+        None => Ok(String::new()),
     }
 }
 
@@ -145,13 +271,130 @@ pub fn verify_dev() -> bool {
 mod tests {
     use super::*;
 
+    static SALT: &[u8] = b"saltsaltsaltsalt";
+
     #[test]
     fn test_short_key_does_not_panic() {
-        assert!(keygen("tooshort".as_bytes().to_vec()).is_ok());
+        assert!(keygen(b"tooshort", SALT).is_ok());
+    }
+
+    #[test]
+    fn test_over_long_key_is_ok() {
+        assert!(keygen(b"averyveryverylongkeyfortesting", SALT).is_ok());
+    }
+
+    #[test]
+    fn test_legacy_over_long_key_is_err() {
+        assert!(legacy_keygen(b"averyveryverylongkeyfortesting".to_vec()).is_err());
+    }
+
+    #[test]
+    fn test_malformed_dev_hash_is_err() {
+        std::env::set_var("PASSCURSES_ENC_DEV_PASSWORD", "not an argon2 hash");
+        let result = verify_dev();
+        std::env::remove_var("PASSCURSES_ENC_DEV_PASSWORD");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_derived_key_is_deterministic() {
+        let (cipher_text, nonce) =
+            encrypt("test_pass", &keygen(b"master", SALT).unwrap().aead).unwrap();
+        let password = encode(cipher_text);
+        let data = EncryptionData {
+            password: &password,
+            nonce: &nonce,
+            key: &keygen(b"master", SALT).unwrap().aead,
+        };
+        assert_eq!(try_decrypt(data).unwrap(), "test_pass");
+    }
+
+    #[test]
+    fn test_derived_key_depends_on_salt() {
+        let (cipher_text, nonce) =
+            encrypt("test_pass", &keygen(b"master", SALT).unwrap().aead).unwrap();
+        let password = encode(cipher_text);
+        let data = EncryptionData {
+            password: &password,
+            nonce: &nonce,
+            key: &keygen(b"master", b"pepperpepperpepp").unwrap().aead,
+        };
+        assert!(try_decrypt(data).is_err());
+    }
+
+    #[test]
+    fn test_rekey_entries_from_legacy_key() {
+        let legacy = legacy_keygen(b"master".to_vec()).unwrap().aead;
+        let derived = keygen(b"master", SALT).unwrap().aead;
+        let (cipher_text, nonce) = encrypt("test_pass", &legacy).unwrap();
+        let mut map = HashMap::new();
+        map.insert(
+            String::from("test_user"),
+            PasswordEntry::new(encode(cipher_text), nonce),
+        );
+
+        let rekeyed = rekey_entries(map, &legacy, &derived).unwrap();
+        let entry = &rekeyed["test_user"];
+        let data = EncryptionData {
+            password: &entry.password,
+            nonce: &entry.nonce,
+            key: &derived,
+        };
+        assert_eq!(try_decrypt(data).unwrap(), "test_pass");
+    }
+
+    #[test]
+    fn test_rekey_entries_with_wrong_key_is_err() {
+        let legacy = legacy_keygen(b"master".to_vec()).unwrap().aead;
+        let derived = keygen(b"master", SALT).unwrap().aead;
+        let (cipher_text, nonce) = encrypt("test_pass", &derived).unwrap();
+        let mut map = HashMap::new();
+        map.insert(
+            String::from("test_user"),
+            PasswordEntry::new(encode(cipher_text), nonce),
+        );
+
+        assert!(rekey_entries(map, &legacy, &derived).is_err());
+    }
+
+    #[test]
+    fn test_rekey_entries_keeps_metadata_and_notes() {
+        let old_key = keygen(b"master", SALT).unwrap().aead;
+        let new_key = keygen(b"new_master", SALT).unwrap().aead;
+        let (cipher_text, nonce) = encrypt("test_pass", &old_key).unwrap();
+        let (notes, notes_nonce) = encrypt("security answer", &old_key).unwrap();
+        let mut map = HashMap::new();
+        map.insert(
+            String::from("test_user"),
+            PasswordEntry {
+                username: String::from("me@example.com"),
+                notes: encode(notes),
+                notes_nonce,
+                tags: vec![String::from("work")],
+                created: 1_600_000_000,
+                modified: 1_600_000_001,
+                ..PasswordEntry::new(encode(cipher_text), nonce)
+            },
+        );
+
+        let rekeyed = rekey_entries(map, &old_key, &new_key).unwrap();
+        let entry = &rekeyed["test_user"];
+        assert_eq!(entry.username, "me@example.com");
+        assert_eq!(entry.tags, vec![String::from("work")]);
+        assert_eq!(entry.modified, 1_600_000_001);
+        let data = EncryptionData {
+            password: &entry.notes,
+            nonce: &entry.notes_nonce,
+            key: &new_key,
+        };
+        assert_eq!(try_decrypt(data).unwrap(), "security answer");
     }
 
     #[test]
-    fn test_over_long_key_is_err() {
-        assert!(keygen("averyveryverylongkeyfortesting".as_bytes().to_vec()).is_err());
+    fn test_format_date() {
+        assert_eq!(format_date(0), "-");
+        assert_eq!(format_date(86_400), "1970-01-02");
+        assert_eq!(format_date(951_782_400), "2000-02-29");
+        assert_eq!(format_date(1_700_000_000), "2023-11-14");
     }
 }
@@ -0,0 +1,177 @@
+use crate::util::error::Error;
+use crate::util::json_utils::PasswordEntry;
+use aes_gcm::aead::{generic_array::GenericArray, Aead, Payload};
+use aes_gcm::Aes128Gcm;
+use base64::{decode, encode};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub const VAULT_FORMAT: &str = "passcurses-vault";
+pub const VAULT_VERSION: u32 = 4;
+
+/// The on-disk form of `passwords.json`: the whole map of entries, service names
+/// included, sealed as a single AES-GCM blob. The header is authenticated as
+/// associated data, so it can't be altered without the vault failing to open.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VaultContainer {
+    pub(crate) format: String,
+    pub(crate) version: u32,
+    pub(crate) nonce: String,
+    pub(crate) ciphertext: String,
+}
+
+impl VaultContainer {
+    pub fn seal(map: &HashMap<String, PasswordEntry>, key: &Aes128Gcm) -> Result<Self, Error> {
+        Self::seal_version(&serde_json::to_value(map)?, VAULT_VERSION, key)
+    }
+
+    /// Seals entries still in the layout of an older `version`, so a copy kept
+    /// from before an upgrade is no more readable than the vault itself.
+    pub fn seal_version(
+        entries: &serde_json::Value,
+        version: u32,
+        key: &Aes128Gcm,
+    ) -> Result<Self, Error> {
+        let mut nonce = [0_u8; 12];
+        thread_rng().try_fill(&mut nonce[..])?;
+
+        let plaintext = serde_json::to_vec(entries)?;
+        let aad = header_aad(VAULT_FORMAT, version);
+        let ciphertext = key
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Encrypt)?;
+
+        Ok(Self {
+            format: VAULT_FORMAT.to_string(),
+            version,
+            nonce: encode(nonce),
+            ciphertext: encode(ciphertext),
+        })
+    }
+
+    /// Decrypts the entries. They're returned as raw JSON in the layout of
+    /// `self.version` so that older layouts can be migrated before use.
+    pub fn open(&self, key: &Aes128Gcm) -> Result<serde_json::Value, Error> {
+        if self.format != VAULT_FORMAT {
+            return Err(Error::Malformed(format!(
+                "Not a passwords file: {}",
+                self.format
+            )));
+        }
+        if self.version > VAULT_VERSION {
+            return Err(Error::UnsupportedVersion {
+                file: String::from("passwords.json"),
+                version: self.version,
+                supported: VAULT_VERSION,
+            });
+        }
+
+        let nonce = decode(&self.nonce)?;
+        if nonce.len() != 12 {
+            return Err(Error::Malformed(String::from(
+                "Corrupt passwords file nonce",
+            )));
+        }
+        let ciphertext = decode(&self.ciphertext)?;
+        let aad = header_aad(&self.format, self.version);
+        let plaintext = key
+            .decrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| Error::Decrypt)?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    /// Whether a parsed `passwords.json` is a sealed container rather than the
+    /// older plain map of service names to entries.
+    pub fn is_container(value: &serde_json::Value) -> bool {
+        value.get("format").and_then(|f| f.as_str()) == Some(VAULT_FORMAT)
+    }
+}
+
+#[inline]
+fn header_aad(format: &str, version: u32) -> Vec<u8> {
+    format!("{}:{}", format, version).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::NewAead;
+    use serde_json::json;
+
+    fn key(raw: &[u8; 16]) -> Aes128Gcm {
+        Aes128Gcm::new(GenericArray::from_slice(raw))
+    }
+
+    fn entries() -> HashMap<String, PasswordEntry> {
+        let mut map = HashMap::new();
+        map.insert(
+            String::from("secret_service"),
+            PasswordEntry::new(String::from("c2VjcmV0"), String::from("asdfjklqasdf")),
+        );
+        map
+    }
+
+    #[test]
+    fn test_seal_and_open_roundtrip() {
+        let cipher = key(b"testing987654321");
+        let container = VaultContainer::seal(&entries(), &cipher).unwrap();
+        let map: HashMap<String, PasswordEntry> =
+            serde_json::from_value(container.open(&cipher).unwrap()).unwrap();
+        assert_eq!(map["secret_service"].password, "c2VjcmV0");
+        assert_eq!(map["secret_service"].nonce, "asdfjklqasdf");
+    }
+
+    #[test]
+    fn test_sealed_container_hides_service_names() {
+        let container = VaultContainer::seal(&entries(), &key(b"testing987654321")).unwrap();
+        let serialized = serde_json::to_string(&container).unwrap();
+        assert!(!serialized.contains("secret_service"));
+    }
+
+    #[test]
+    fn test_open_with_wrong_key_is_err() {
+        let container = VaultContainer::seal(&entries(), &key(b"testing987654321")).unwrap();
+        assert!(container.open(&key(b"123456789gnitset")).is_err());
+    }
+
+    #[test]
+    fn test_tampered_header_is_err() {
+        let cipher = key(b"testing987654321");
+        let mut container = VaultContainer::seal(&entries(), &cipher).unwrap();
+        container.format = String::from("something-else");
+        assert!(container.open(&cipher).is_err());
+    }
+
+    #[test]
+    fn test_newer_version_is_err() {
+        let cipher = key(b"testing987654321");
+        let mut container = VaultContainer::seal(&entries(), &cipher).unwrap();
+        container.version = VAULT_VERSION + 1;
+        assert!(container.open(&cipher).is_err());
+    }
+
+    #[test]
+    fn test_is_container() {
+        let cipher = key(b"testing987654321");
+        let sealed = serde_json::to_value(VaultContainer::seal(&entries(), &cipher).unwrap());
+        assert!(VaultContainer::is_container(&sealed.unwrap()));
+        assert!(!VaultContainer::is_container(&json!({})));
+        assert!(!VaultContainer::is_container(
+            &json!({"format": {"password": "c2VjcmV0", "nonce": "asdfjklqasdf"}})
+        ));
+    }
+}